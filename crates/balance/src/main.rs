@@ -0,0 +1,372 @@
+//! Simulates many duels between reference bots to see how a `MatchRules` variant plays,
+//! or how two variants differ.
+
+use std::str::FromStr;
+use std::sync::Arc;
+
+use logic::ai::{builtin, Perfect, BUILTIN_BOTS};
+use logic::bot::{play_duel, Bot, DEFAULT_MAX_ROUNDS};
+use logic::solver::{solve, Solution, SolverConfig};
+use logic::{Action, MatchRules};
+
+const USAGE: &str =
+    "usage: balance [--games N] [--max-rounds N] [--seed N] [--bots a,b,...] RULES [RULES]
+RULES is `default` or a JSON file of match rules, missing fields keeping their default";
+
+struct Options {
+    variants: Vec<String>,
+    /// Duels per ordered pair of bots.
+    games: u32,
+    max_rounds: u32,
+    seed: u64,
+    bots: Vec<String>,
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum Unit {
+    Count,
+    Average,
+    Rate,
+}
+
+/// Totals over every duel played with one variant.
+#[derive(Default)]
+struct Tally {
+    games: u32,
+    /// Wins of the first and second seat.
+    seat_wins: [u32; 2],
+    rounds: u64,
+    actions: [u64; 3],
+    /// Score of each bot, a draw counting half.
+    bot_scores: Vec<f64>,
+    bot_games: Vec<u32>,
+}
+
+impl Tally {
+    fn draws(&self) -> u32 {
+        self.games - self.seat_wins[0] - self.seat_wins[1]
+    }
+
+    fn rate(&self, count: impl Into<f64>) -> f64 {
+        count.into() / self.games.max(1) as f64
+    }
+
+    fn lines(&self, bots: &[String]) -> Vec<(String, f64, Unit)> {
+        let total_actions = self.actions.iter().sum::<u64>().max(1) as f64;
+        let mut lines = vec![
+            ("duels".to_string(), self.games as f64, Unit::Count),
+            (
+                "first seat wins".to_string(),
+                self.rate(self.seat_wins[0]),
+                Unit::Rate,
+            ),
+            (
+                "second seat wins".to_string(),
+                self.rate(self.seat_wins[1]),
+                Unit::Rate,
+            ),
+            ("draws".to_string(), self.rate(self.draws()), Unit::Rate),
+            (
+                "first seat advantage".to_string(),
+                self.rate(self.seat_wins[0]) - self.rate(self.seat_wins[1]),
+                Unit::Rate,
+            ),
+            (
+                "average rounds".to_string(),
+                self.rounds as f64 / self.games.max(1) as f64,
+                Unit::Average,
+            ),
+        ];
+        for (action, count) in Action::ALL.iter().zip(self.actions) {
+            lines.push((
+                format!("{} played", action.name()),
+                count as f64 / total_actions,
+                Unit::Rate,
+            ));
+        }
+        for (i, name) in bots.iter().enumerate() {
+            lines.push((
+                format!("{name} score"),
+                self.bot_scores[i] / self.bot_games[i].max(1) as f64,
+                Unit::Rate,
+            ));
+        }
+        lines
+    }
+}
+
+/// Reference bot `name`, `perfect` playing the equilibrium of `rules`. The equilibrium is
+/// solved the first time, then shared through `solution` by every copy.
+fn reference_bot(
+    name: &str,
+    rules: &MatchRules,
+    solution: &mut Option<Arc<Solution>>,
+    seed: u64,
+) -> Result<Box<dyn Bot + Send>, String> {
+    if name == "perfect" {
+        let solution =
+            solution.get_or_insert_with(|| Arc::new(solve(rules, &SolverConfig::default())));
+        return Ok(Box::new(Perfect::new(solution.clone(), seed)));
+    }
+    builtin(name, seed).ok_or(format!(
+        "unknown bot {name}, built-in bots: {}",
+        BUILTIN_BOTS.join(", ")
+    ))
+}
+
+/// Plays `games` duels between every ordered pair of bots, mirrors included.
+fn simulate(rules: &MatchRules, options: &Options) -> Result<Tally, String> {
+    let mut bots = vec![];
+    let mut solution = None;
+    // each bot plays against a copy of itself too
+    for copy in 0..2 {
+        let mut seats = vec![];
+        for (i, name) in options.bots.iter().enumerate() {
+            let seed = options
+                .seed
+                .wrapping_add((copy * options.bots.len() + i) as u64);
+            seats.push(reference_bot(name, rules, &mut solution, seed)?);
+        }
+        bots.push(seats);
+    }
+    let [first_seats, second_seats] = &mut bots[..] else {
+        unreachable!("two copies")
+    };
+
+    let mut tally = Tally {
+        bot_scores: vec![0.0; options.bots.len()],
+        bot_games: vec![0; options.bots.len()],
+        ..Default::default()
+    };
+    for (a, first) in first_seats.iter_mut().enumerate() {
+        for (b, second) in second_seats.iter_mut().enumerate() {
+            for _ in 0..options.games {
+                let duel = play_duel(rules, [first.as_mut(), second.as_mut()], options.max_rounds);
+                if let Some((loser, reason)) = &duel.forfeit {
+                    return Err(format!(
+                        "{} forfeited: {reason}",
+                        options.bots[[a, b][*loser]]
+                    ));
+                }
+                tally.games += 1;
+                tally.rounds += duel.rounds.len() as u64;
+                for actions in &duel.rounds {
+                    for action in actions {
+                        tally.actions[Action::ALL.iter().position(|a| a == action).unwrap()] += 1;
+                    }
+                }
+                let scores = match duel.winner {
+                    Some(winner) => {
+                        tally.seat_wins[winner] += 1;
+                        if winner == 0 {
+                            [1.0, 0.0]
+                        } else {
+                            [0.0, 1.0]
+                        }
+                    }
+                    None => [0.5, 0.5],
+                };
+                for (bot, score) in [a, b].into_iter().zip(scores) {
+                    tally.bot_scores[bot] += score;
+                    tally.bot_games[bot] += 1;
+                }
+            }
+        }
+    }
+    Ok(tally)
+}
+
+fn load_rules(variant: &str) -> Result<MatchRules, String> {
+    if variant == "default" {
+        return Ok(MatchRules::default());
+    }
+    let json = std::fs::read_to_string(variant).map_err(|e| format!("{variant}: {e}"))?;
+    let rules: MatchRules = serde_json::from_str(&json).map_err(|e| format!("{variant}: {e}"))?;
+    if rules.starting_health < 1 || rules.starting_ammo < 0 {
+        return Err(format!("{variant}: invalid starting stats"));
+    }
+    Ok(rules)
+}
+
+fn format_value(value: f64, unit: Unit) -> String {
+    match unit {
+        Unit::Count => format!("{value}"),
+        Unit::Average => format!("{value:.2}"),
+        Unit::Rate => format!("{:.1}%", value * 100.0),
+    }
+}
+
+fn main() {
+    let options = match parse_args(std::env::args().skip(1)) {
+        Ok(options) => options,
+        Err(e) => {
+            eprintln!("{e}\n{USAGE}");
+            std::process::exit(2);
+        }
+    };
+
+    let mut reports = vec![];
+    for variant in &options.variants {
+        let report = load_rules(variant).and_then(|rules| simulate(&rules, &options));
+        match report {
+            Ok(tally) => reports.push(tally.lines(&options.bots)),
+            Err(e) => {
+                eprintln!("{e}");
+                std::process::exit(1);
+            }
+        }
+    }
+
+    for line in table(&options.variants, &reports) {
+        println!("{line}");
+    }
+}
+
+/// Lines of the report, one column per variant and the change from the first to the second.
+fn table(variants: &[String], reports: &[Vec<(String, f64, Unit)>]) -> Vec<String> {
+    let mut header = format!("{:<24}", "");
+    for variant in variants {
+        header += &format!(" {variant:>16}");
+    }
+    if reports.len() == 2 {
+        header += &format!(" {:>16}", "change");
+    }
+    let mut lines = vec![header];
+    for (line, (label, value, unit)) in reports[0].iter().enumerate() {
+        let mut row = format!("{label:<24} {:>16}", format_value(*value, *unit));
+        if let Some(other) = reports.get(1) {
+            let (_, other_value, _) = other[line];
+            let change = other_value - value;
+            let change = match unit {
+                Unit::Count => format!("{change:+}"),
+                Unit::Average => format!("{change:+.2}"),
+                Unit::Rate => format!("{:+.1} pts", change * 100.0),
+            };
+            row += &format!(" {:>16} {change:>16}", format_value(other_value, *unit));
+        }
+        lines.push(row);
+    }
+    lines
+}
+
+fn parse_args(mut args: impl Iterator<Item = String>) -> Result<Options, String> {
+    let mut options = Options {
+        variants: vec![],
+        games: 200,
+        max_rounds: DEFAULT_MAX_ROUNDS,
+        seed: 0,
+        bots: BUILTIN_BOTS.iter().map(|name| name.to_string()).collect(),
+    };
+    while let Some(arg) = args.next() {
+        if !arg.starts_with("--") {
+            options.variants.push(arg);
+            continue;
+        }
+        let value = args.next().ok_or(format!("missing value for {arg}"))?;
+        match arg.as_str() {
+            "--games" => options.games = parse_number(&value)?,
+            "--max-rounds" => options.max_rounds = parse_number(&value)?,
+            "--seed" => options.seed = parse_number(&value)?,
+            "--bots" => options.bots = value.split(',').map(str::to_string).collect(),
+            _ => return Err(format!("unknown argument {arg}")),
+        }
+    }
+    if !(1..=2).contains(&options.variants.len()) {
+        return Err("expected one or two rule variants".to_string());
+    }
+    Ok(options)
+}
+
+fn parse_number<T: FromStr>(value: &str) -> Result<T, String> {
+    value.parse().map_err(|_| format!("invalid number {value}"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn args(args: &[&str]) -> Result<Options, String> {
+        parse_args(args.iter().map(|arg| arg.to_string()))
+    }
+
+    #[test]
+    fn parses_counts_and_variants() {
+        let options = args(&["--games", "5", "--max-rounds", "40", "default", "b.json"]).unwrap();
+        assert_eq!(options.games, 5);
+        assert_eq!(options.max_rounds, 40);
+        assert_eq!(options.variants, ["default", "b.json"]);
+        assert_eq!(options.bots.len(), BUILTIN_BOTS.len());
+        let options = args(&["--bots", "random,perfect", "default"]).unwrap();
+        assert_eq!(options.bots, ["random", "perfect"]);
+    }
+
+    #[test]
+    fn rejects_bad_arguments() {
+        assert!(args(&["--games", "-1", "default"]).is_err());
+        assert!(args(&["--games", "many", "default"]).is_err());
+        assert!(args(&["--max-rounds", "4294967296", "default"]).is_err());
+        assert!(args(&["default", "--max-rounds"]).is_err());
+        assert!(args(&["--rounds", "3", "default"]).is_err());
+        assert!(args(&[]).is_err());
+        assert!(args(&["a", "b", "c"]).is_err());
+    }
+
+    #[test]
+    fn variants_start_with_health_and_no_debt() {
+        let path = std::env::temp_dir().join(format!("balance-{}.json", std::process::id()));
+        let path = path.to_str().unwrap();
+        let load = |json: &str| {
+            std::fs::write(path, json).unwrap();
+            load_rules(path)
+        };
+        assert_eq!(load(r#"{"starting_ammo": 1}"#).unwrap().starting_ammo, 1);
+        assert!(load(r#"{"starting_health": 0}"#).is_err());
+        assert!(load(r#"{"starting_ammo": -1}"#).is_err());
+        std::fs::remove_file(path).unwrap();
+    }
+
+    fn tally() -> Tally {
+        Tally {
+            games: 4,
+            seat_wins: [2, 1],
+            rounds: 10,
+            actions: [10, 6, 4],
+            bot_scores: vec![2.5],
+            bot_games: vec![4],
+        }
+    }
+
+    #[test]
+    fn report_lines_are_rates_of_the_duels() {
+        let lines = tally().lines(&["random".to_string()]);
+        let value = |label: &str| lines.iter().find(|line| line.0 == label).unwrap().1;
+        assert_eq!(value("duels"), 4.0);
+        assert_eq!(value("first seat wins"), 0.5);
+        assert_eq!(value("draws"), 0.25);
+        assert_eq!(value("first seat advantage"), 0.25);
+        assert_eq!(value("average rounds"), 2.5);
+        assert_eq!(value("Reload played"), 0.5);
+        assert_eq!(value("random score"), 0.625);
+    }
+
+    #[test]
+    fn second_variant_is_compared_to_the_first() {
+        let bots = ["random".to_string()];
+        let mut other = tally();
+        other.games = 8;
+        other.rounds = 24;
+        let variants = ["default".to_string(), "b.json".to_string()];
+        let table = table(&variants, &[tally().lines(&bots), other.lines(&bots)]);
+        assert!(table[0].ends_with("change"));
+        let row = |label: &str| {
+            let row = table.iter().find(|row| row.starts_with(label)).unwrap();
+            row.split_whitespace().rev().take(2).collect::<Vec<_>>()
+        };
+        assert_eq!(row("duels"), ["+4", "8"]);
+        assert_eq!(row("average rounds"), ["+0.50", "3.00"]);
+        assert_eq!(row("first seat wins"), ["pts", "-25.0"]);
+
+        let table = super::table(&variants[..1], &[tally().lines(&bots)]);
+        assert!(!table[0].contains("change"));
+    }
+}
@@ -0,0 +1,173 @@
+//! Plays duels between bots speaking the line protocol of `logic::bot`, or people at the
+//! terminal.
+
+use std::io::{BufRead, Write};
+
+use logic::bot::{
+    action_word, parse_action, play_duel, split_command, Bot, Outcome, ProcessBot, RoundView,
+    DEFAULT_MAX_ROUNDS,
+};
+use logic::notation::MatchRecord;
+use logic::{Action, MatchRules};
+
+const USAGE: &str = "usage: duel [--matches N] [--max-rounds N] [--notation] PLAYER PLAYER
+PLAYER is `human` to play at the terminal, or the command running a bot, e.g. \"python3 bot.py\"";
+
+struct Options {
+    players: Vec<String>,
+    matches: u32,
+    max_rounds: u32,
+    /// Print every match in notation, instead of the rounds of a single match.
+    notation: bool,
+}
+
+/// Someone at the terminal.
+struct Human {
+    name: String,
+}
+
+impl Bot for Human {
+    fn name(&self) -> String {
+        self.name.clone()
+    }
+
+    fn choose(&mut self, view: &RoundView) -> Result<Action, String> {
+        if let Some((mine, theirs)) = view.last {
+            println!(
+                "{} played {}, opponent played {}",
+                self.name,
+                action_word(mine),
+                action_word(theirs)
+            );
+        }
+        println!(
+            "Round {} - {}: {} HP {} ammo, opponent: {} HP {} ammo",
+            view.round,
+            self.name,
+            view.me.health,
+            view.me.ammo,
+            view.opponent.health,
+            view.opponent.ammo
+        );
+        loop {
+            print!("reload, shield or fire? ");
+            std::io::stdout().flush().map_err(|e| e.to_string())?;
+            let mut line = String::new();
+            if std::io::stdin()
+                .lock()
+                .read_line(&mut line)
+                .map_err(|e| e.to_string())?
+                == 0
+            {
+                return Err(format!("{} left", self.name));
+            }
+            match parse_action(&line) {
+                Ok(action) => return Ok(action),
+                Err(e) => println!("{e}"),
+            }
+        }
+    }
+
+    fn game_over(&mut self, outcome: Outcome) {
+        println!("{}: {outcome}", self.name);
+    }
+}
+
+fn main() {
+    let options = match parse_args(std::env::args().skip(1)) {
+        Ok(options) => options,
+        Err(e) => {
+            eprintln!("{e}\n{USAGE}");
+            std::process::exit(2);
+        }
+    };
+
+    let mut bots: Vec<Box<dyn Bot>> = vec![];
+    for (i, player) in options.players.iter().enumerate() {
+        if player == "human" {
+            bots.push(Box::new(Human {
+                name: format!("Player {}", i + 1),
+            }));
+            continue;
+        }
+        match split_command(player).and_then(|(program, args)| ProcessBot::spawn(&program, &args)) {
+            Ok(bot) => bots.push(Box::new(bot)),
+            Err(e) => {
+                eprintln!("{e}");
+                std::process::exit(1);
+            }
+        }
+    }
+    let names: Vec<String> = bots.iter().map(|bot| bot.name()).collect();
+
+    let rules = MatchRules::default();
+    let mut wins = [0; 2];
+    let mut draws = 0;
+    for _ in 0..options.matches {
+        let [first, second] = &mut bots[..] else {
+            unreachable!("two players")
+        };
+        let duel = play_duel(
+            &rules,
+            [first.as_mut(), second.as_mut()],
+            options.max_rounds,
+        );
+        if options.notation {
+            let names = [names[0].clone(), names[1].clone()];
+            println!("{}", MatchRecord::from_duel(&rules, names, &duel));
+        } else if options.matches == 1 {
+            for (round, actions) in duel.rounds.iter().enumerate() {
+                println!(
+                    "round {}: {} {}, {} {}",
+                    round + 1,
+                    names[0],
+                    action_word(actions[0]),
+                    names[1],
+                    action_word(actions[1])
+                );
+            }
+        }
+        if let Some((loser, reason)) = &duel.forfeit {
+            println!("{} forfeits: {reason}", names[*loser]);
+        }
+        match duel.winner {
+            Some(winner) => wins[winner] += 1,
+            None => draws += 1,
+        }
+    }
+    println!(
+        "{}: {} wins, {}: {} wins, {} draws",
+        names[0], wins[0], names[1], wins[1], draws
+    );
+}
+
+fn parse_args(mut args: impl Iterator<Item = String>) -> Result<Options, String> {
+    let mut options = Options {
+        players: vec![],
+        matches: 1,
+        max_rounds: DEFAULT_MAX_ROUNDS,
+        notation: false,
+    };
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--matches" | "--max-rounds" => {
+                let value = args.next().ok_or(format!("missing value for {arg}"))?;
+                let number = value
+                    .parse()
+                    .map_err(|_| format!("invalid number {value}"))?;
+                if arg == "--matches" {
+                    options.matches = number;
+                } else {
+                    options.max_rounds = number;
+                }
+            }
+            "--notation" => options.notation = true,
+            _ if arg.starts_with("--") => return Err(format!("unknown argument {arg}")),
+            _ => options.players.push(arg),
+        }
+    }
+    if options.players.len() != 2 {
+        return Err("expected two players".to_string());
+    }
+    Ok(options)
+}
@@ -0,0 +1,167 @@
+//! Built-in bots, for tournaments and balance reports.
+
+use std::sync::{Arc, OnceLock};
+
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+
+use crate::bot::{Bot, RoundView};
+use crate::logic::MatchRules;
+use crate::rules::Action;
+use crate::solver::{solve, Solution, SolverConfig};
+
+/// Names `builtin` knows.
+pub const BUILTIN_BOTS: [&str; 6] = [
+    "random",
+    "aggressive",
+    "cautious",
+    "turtle",
+    "copycat",
+    "perfect",
+];
+
+/// Creates the built-in bot called `name`, its randomness drawn from `seed`.
+pub fn builtin(name: &str, seed: u64) -> Option<Box<dyn Bot + Send>> {
+    let rng = StdRng::seed_from_u64(seed);
+    Some(match name {
+        "random" => Box::new(RandomBot { rng }),
+        "aggressive" => Box::new(Aggressive),
+        "cautious" => Box::new(Cautious { rng }),
+        "turtle" => Box::new(Turtle),
+        "copycat" => Box::new(Copycat),
+        "perfect" => Box::new(Perfect::new(default_solution(), seed)),
+        _ => return None,
+    })
+}
+
+/// Any action, firing only with ammo.
+pub struct RandomBot {
+    rng: StdRng,
+}
+
+impl Bot for RandomBot {
+    fn name(&self) -> String {
+        "random".to_string()
+    }
+
+    fn choose(&mut self, view: &RoundView) -> Result<Action, String> {
+        let actions: Vec<Action> = Action::ALL
+            .into_iter()
+            .filter(|action| *action != Action::Fire || view.me.ammo > 0)
+            .collect();
+        Ok(actions[self.rng.gen_range(0..actions.len())])
+    }
+}
+
+/// Fires as soon as it has ammo, reloads otherwise.
+pub struct Aggressive;
+
+impl Bot for Aggressive {
+    fn name(&self) -> String {
+        "aggressive".to_string()
+    }
+
+    fn choose(&mut self, view: &RoundView) -> Result<Action, String> {
+        Ok(if view.me.ammo > 0 {
+            Action::Fire
+        } else {
+            Action::Reload
+        })
+    }
+}
+
+/// Shields when the opponent can fire, but not always, or it would never reload.
+pub struct Cautious {
+    rng: StdRng,
+}
+
+impl Bot for Cautious {
+    fn name(&self) -> String {
+        "cautious".to_string()
+    }
+
+    fn choose(&mut self, view: &RoundView) -> Result<Action, String> {
+        let threatened = view.opponent.ammo > 0;
+        Ok(if threatened && self.rng.gen_bool(0.6) {
+            Action::Shield
+        } else if view.me.ammo > 0 && !threatened {
+            Action::Fire
+        } else {
+            Action::Reload
+        })
+    }
+}
+
+/// Reloads once, then shields until the opponent runs dry, then fires.
+pub struct Turtle;
+
+impl Bot for Turtle {
+    fn name(&self) -> String {
+        "turtle".to_string()
+    }
+
+    fn choose(&mut self, view: &RoundView) -> Result<Action, String> {
+        Ok(if view.me.ammo == 0 {
+            Action::Reload
+        } else if view.opponent.ammo > 0 {
+            Action::Shield
+        } else {
+            Action::Fire
+        })
+    }
+}
+
+/// Plays what the opponent played last round, as long as it can.
+pub struct Copycat;
+
+impl Bot for Copycat {
+    fn name(&self) -> String {
+        "copycat".to_string()
+    }
+
+    fn choose(&mut self, view: &RoundView) -> Result<Action, String> {
+        Ok(match view.last {
+            Some((_, Action::Fire)) if view.me.ammo > 0 => Action::Fire,
+            Some((_, Action::Fire)) => Action::Shield,
+            Some((_, theirs)) => theirs,
+            None => Action::Reload,
+        })
+    }
+}
+
+/// Solution of the default rules, computed once for every `perfect` bot.
+fn default_solution() -> Arc<Solution> {
+    static SOLUTION: OnceLock<Arc<Solution>> = OnceLock::new();
+    SOLUTION
+        .get_or_init(|| Arc::new(solve(&MatchRules::default(), &SolverConfig::default())))
+        .clone()
+}
+
+/// Plays the equilibrium strategy of `solver`, nobody can expect to beat it.
+pub struct Perfect {
+    solution: Arc<Solution>,
+    rng: StdRng,
+}
+
+impl Perfect {
+    pub fn new(solution: Arc<Solution>, seed: u64) -> Self {
+        Self {
+            solution,
+            rng: StdRng::seed_from_u64(seed),
+        }
+    }
+}
+
+impl Bot for Perfect {
+    fn name(&self) -> String {
+        "perfect".to_string()
+    }
+
+    fn choose(&mut self, view: &RoundView) -> Result<Action, String> {
+        let state = self
+            .solution
+            .get([view.me, view.opponent])
+            .ok_or(format!("unsolved state {}", view.to_line()))?;
+        Ok(state.pick(0, self.rng.gen()))
+    }
+}
@@ -0,0 +1,649 @@
+//! Bots playing duels, and the line protocol external bot processes speak.
+//!
+//! The engine writes one command per line on the bot's stdin, the bot answers on stdout:
+//!
+//! ```text
+//! cowboys 1                                      -> ok <bot name>
+//! newgame
+//! round 2 hp 3 3 ammo 1 1 last reload reload     -> reload | shield | fire
+//! gameover win | loss | draw
+//! quit
+//! ```
+//!
+//! `hp` and `ammo` give the bot's value first, then its opponent's, `last` the actions of
+//! the previous round in the same order, or `none none` on the first round.
+
+use std::fmt;
+
+use ggrs::Frame;
+
+use crate::logic::MatchRules;
+use crate::rules::{resolve_round, Action, PlayerStats, NUM_PLAYERS};
+use crate::sim::Phase;
+
+/// Version of the line protocol, sent in the `cowboys` greeting.
+pub const BOT_PROTOCOL_VERSION: u32 = 1;
+
+/// Rounds after which a duel is a draw, so two bots shielding forever still finish.
+pub const DEFAULT_MAX_ROUNDS: u32 = 100;
+
+/// What a player knows when picking their action.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct RoundView {
+    /// Starts at 1.
+    pub round: u32,
+    pub me: PlayerStats,
+    pub opponent: PlayerStats,
+    /// Actions of the previous round, mine then the opponent's.
+    pub last: Option<(Action, Action)>,
+}
+
+impl RoundView {
+    /// The `round` command telling a bot about this round.
+    pub fn to_line(&self) -> String {
+        let (mine, theirs) = match self.last {
+            Some((mine, theirs)) => (action_word(mine), action_word(theirs)),
+            None => ("none", "none"),
+        };
+        format!(
+            "round {} hp {} {} ammo {} {} last {} {}",
+            self.round,
+            self.me.health,
+            self.opponent.health,
+            self.me.ammo,
+            self.opponent.ammo,
+            mine,
+            theirs
+        )
+    }
+
+    /// Reads a `round` command, for bots written in Rust.
+    pub fn parse(line: &str) -> Result<Self, String> {
+        let words: Vec<&str> = line.split_whitespace().collect();
+        let [command, round, hp, my_hp, their_hp, ammo, my_ammo, their_ammo, last, my_last, their_last] =
+            words[..]
+        else {
+            return Err(format!("malformed round: {line}"));
+        };
+        if command != "round" || hp != "hp" || ammo != "ammo" || last != "last" {
+            return Err(format!("malformed round: {line}"));
+        }
+        let number = |word: &str| {
+            word.parse::<i32>()
+                .map_err(|_| format!("invalid number {word}"))
+        };
+        let last = match (my_last, their_last) {
+            ("none", "none") => None,
+            (mine, theirs) => Some((parse_action(mine)?, parse_action(theirs)?)),
+        };
+        Ok(Self {
+            round: round
+                .parse()
+                .map_err(|_| format!("invalid round {round}"))?,
+            me: PlayerStats {
+                health: number(my_hp)?,
+                ammo: number(my_ammo)?,
+            },
+            opponent: PlayerStats {
+                health: number(their_hp)?,
+                ammo: number(their_ammo)?,
+            },
+            last,
+        })
+    }
+}
+
+/// Word for `action` in the protocol.
+pub fn action_word(action: Action) -> &'static str {
+    match action {
+        Action::Reload => "reload",
+        Action::Shield => "shield",
+        Action::Fire => "fire",
+    }
+}
+
+pub fn parse_action(word: &str) -> Result<Action, String> {
+    Action::ALL
+        .into_iter()
+        .find(|action| action_word(*action) == word.trim())
+        .ok_or(format!("unknown action {word}"))
+}
+
+/// Splits a bot command typed on one line, e.g. `python3 "my bots/bot.py"`, into its
+/// program and arguments. Quotes keep spaces in a word, nothing else is interpreted.
+pub fn split_command(command: &str) -> Result<(String, Vec<String>), String> {
+    let mut words = vec![];
+    let mut word: Option<String> = None;
+    let mut quote = None;
+    for c in command.chars() {
+        match (quote, c) {
+            (Some(open), c) if c == open => quote = None,
+            (Some(_), c) => word.get_or_insert_with(String::new).push(c),
+            (None, '"' | '\'') => {
+                quote = Some(c);
+                word.get_or_insert_with(String::new);
+            }
+            (None, c) if c.is_whitespace() => words.extend(word.take()),
+            (None, c) => word.get_or_insert_with(String::new).push(c),
+        }
+    }
+    if quote.is_some() {
+        return Err(format!("unclosed quote in {command}"));
+    }
+    words.extend(word);
+    let mut words = words.into_iter();
+    let program = words.next().ok_or("empty bot command")?;
+    Ok((program, words.collect()))
+}
+
+/// How a duel ended for one player.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Outcome {
+    Win,
+    Loss,
+    Draw,
+}
+
+impl fmt::Display for Outcome {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match self {
+            Outcome::Win => "win",
+            Outcome::Loss => "loss",
+            Outcome::Draw => "draw",
+        })
+    }
+}
+
+/// Anything picking actions in a duel: built-in AIs, external processes, people.
+pub trait Bot {
+    fn name(&self) -> String;
+
+    /// Called before every duel.
+    fn new_game(&mut self) -> Result<(), String> {
+        Ok(())
+    }
+
+    fn choose(&mut self, view: &RoundView) -> Result<Action, String>;
+
+    fn game_over(&mut self, _outcome: Outcome) {}
+}
+
+/// A finished duel.
+#[derive(Clone, Debug)]
+pub struct Duel {
+    /// `None` for a draw.
+    pub winner: Option<usize>,
+    /// Actions of both players, round after round.
+    pub rounds: Vec<[Action; NUM_PLAYERS]>,
+    pub stats: [PlayerStats; NUM_PLAYERS],
+    /// Player who lost by failing to answer, and why.
+    pub forfeit: Option<(usize, String)>,
+}
+
+impl Duel {
+    pub fn outcome(&self, player: usize) -> Outcome {
+        match self.winner {
+            None => Outcome::Draw,
+            Some(winner) if winner == player => Outcome::Win,
+            Some(_) => Outcome::Loss,
+        }
+    }
+}
+
+/// Plays a duel between `bots`, with the rules the Bevy client uses. A bot that errors out
+/// loses on the spot.
+pub fn play_duel(
+    rules: &MatchRules,
+    mut bots: [&mut dyn Bot; NUM_PLAYERS],
+    max_rounds: u32,
+) -> Duel {
+    let mut duel = Duel {
+        winner: None,
+        rounds: vec![],
+        stats: [PlayerStats {
+            health: rules.starting_health,
+            ammo: rules.starting_ammo,
+        }; NUM_PLAYERS],
+        forfeit: None,
+    };
+    for (i, bot) in bots.iter_mut().enumerate() {
+        if let Err(e) = bot.new_game() {
+            duel.forfeit = Some((i, e));
+            break;
+        }
+    }
+
+    while duel.forfeit.is_none() && duel.rounds.len() < max_rounds as usize {
+        let mut actions = [Action::Shield; NUM_PLAYERS];
+        for (i, bot) in bots.iter_mut().enumerate() {
+            let other = (i + 1) % NUM_PLAYERS;
+            let view = RoundView {
+                round: duel.rounds.len() as u32 + 1,
+                me: duel.stats[i],
+                opponent: duel.stats[other],
+                last: duel.rounds.last().map(|last| (last[i], last[other])),
+            };
+            match bot.choose(&view) {
+                Ok(action) => actions[i] = action,
+                Err(e) => {
+                    duel.forfeit = Some((i, e));
+                    break;
+                }
+            }
+        }
+        if duel.forfeit.is_some() {
+            break;
+        }
+        duel.stats = resolve_round(duel.stats, actions);
+        duel.rounds.push(actions);
+        if duel.stats.iter().any(|stats| stats.health <= 0) {
+            break;
+        }
+    }
+
+    duel.winner = match &duel.forfeit {
+        Some((loser, _)) => Some((loser + 1) % NUM_PLAYERS),
+        None => {
+            let alive: Vec<usize> = (0..NUM_PLAYERS)
+                .filter(|i| duel.stats[*i].health > 0)
+                .collect();
+            match alive[..] {
+                [winner] => Some(winner),
+                _ => None,
+            }
+        }
+    };
+    for (i, bot) in bots.iter_mut().enumerate() {
+        bot.game_over(duel.outcome(i));
+    }
+    duel
+}
+
+/// A bot seated in a match that runs frame after frame, see `BotTurns`.
+pub enum SeatedBot {
+    /// Picks its action right away, in `Bot::choose`.
+    Builtin(Box<dyn Bot + Send>),
+    /// Answers through `ProcessBot::poll_action`, while the frames go on.
+    #[cfg(not(target_arch = "wasm32"))]
+    Process(ProcessBot),
+}
+
+impl SeatedBot {
+    pub fn bot(&mut self) -> &mut dyn Bot {
+        match self {
+            SeatedBot::Builtin(bot) => bot.as_mut(),
+            #[cfg(not(target_arch = "wasm32"))]
+            SeatedBot::Process(bot) => bot,
+        }
+    }
+}
+
+/// Asks a seated bot for an action once per round: the round is sent on the frame it
+/// starts, then a process bot is polled until it answers. Drives the bots of the game's
+/// bot seat and puzzles and of the terminal client.
+#[derive(Default)]
+pub struct BotTurns {
+    /// Start frame of the round the bot was last told about.
+    asked: Option<Frame>,
+    /// Rounds played before the one asked.
+    played: u32,
+    /// Actions of the round before the one asked.
+    last: Option<[Action; NUM_PLAYERS]>,
+    /// Action picked for the round asked.
+    action: Option<Action>,
+}
+
+impl BotTurns {
+    /// Turns of a bot joining a match `played` rounds in, e.g. in a puzzle, `last` being the
+    /// actions of the last of these rounds.
+    pub fn resuming(played: u32, last: Option<[Action; NUM_PLAYERS]>) -> Self {
+        Self {
+            played,
+            last,
+            ..Default::default()
+        }
+    }
+
+    /// Rounds played before the one the bot was last asked about.
+    pub fn played(&self) -> u32 {
+        self.played
+    }
+
+    /// Runs the bot playing `seat` for a frame, `stats` and `actions` being the ones of the
+    /// match, which on the first frame of a round still holds the actions just resolved.
+    /// Returns the action the bot picked for the round, once it did.
+    pub fn update(
+        &mut self,
+        bot: &mut SeatedBot,
+        seat: usize,
+        phase: Phase,
+        stats: [PlayerStats; NUM_PLAYERS],
+        actions: [Action; NUM_PLAYERS],
+    ) -> Result<Option<Action>, String> {
+        // the first round starts on the first frame
+        let from = match phase {
+            Phase::Deciding { from, .. } => from,
+            Phase::Starting => 0,
+            Phase::Showing { .. } | Phase::Over => {
+                self.action = None;
+                return Ok(None);
+            }
+        };
+        if self.asked == Some(from) {
+            #[cfg(not(target_arch = "wasm32"))]
+            if let (SeatedBot::Process(bot), None) = (bot, self.action) {
+                self.action = bot.poll_action()?;
+            }
+            return Ok(self.action);
+        }
+        if self.asked.is_some() {
+            self.played += 1;
+            self.last = Some(actions);
+        }
+        self.asked = Some(from);
+        self.action = None;
+        let opponent = (seat + 1) % NUM_PLAYERS;
+        let view = RoundView {
+            round: self.played + 1,
+            me: stats[seat],
+            opponent: stats[opponent],
+            last: self.last.map(|last| (last[seat], last[opponent])),
+        };
+        match bot {
+            SeatedBot::Builtin(bot) => self.action = Some(bot.choose(&view)?),
+            #[cfg(not(target_arch = "wasm32"))]
+            SeatedBot::Process(bot) => bot.send_round(&view)?,
+        }
+        Ok(self.action)
+    }
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+pub use process::ProcessBot;
+
+#[cfg(not(target_arch = "wasm32"))]
+mod process {
+    use std::io::{BufRead, BufReader, Write};
+    use std::process::{Child, ChildStdin, Command, Stdio};
+    use std::sync::mpsc::{self, Receiver, RecvTimeoutError, TryRecvError};
+    use std::time::Duration;
+
+    use super::*;
+
+    /// Time a bot has to greet us once started.
+    const GREETING_TIMEOUT: Duration = Duration::from_secs(5);
+    /// Time a bot has to pick an action in `Bot::choose`.
+    const ANSWER_TIMEOUT: Duration = Duration::from_secs(10);
+    /// Time a bot has to exit by itself once told to quit.
+    const QUIT_TIMEOUT: Duration = Duration::from_millis(50);
+
+    /// External program speaking the line protocol on its stdin and stdout.
+    pub struct ProcessBot {
+        name: String,
+        /// Taken when dropped, to be stopped on another thread.
+        child: Option<Child>,
+        stdin: ChildStdin,
+        /// Lines the bot wrote, read on another thread so we never block on the bot.
+        lines: Receiver<String>,
+    }
+
+    impl ProcessBot {
+        /// Runs `program` with `args` and waits for it to greet us.
+        pub fn spawn(program: &str, args: &[String]) -> Result<Self, String> {
+            let mut child = Command::new(program)
+                .args(args)
+                .stdin(Stdio::piped())
+                .stdout(Stdio::piped())
+                .spawn()
+                .map_err(|e| format!("could not start {program}: {e}"))?;
+            let stdin = child.stdin.take().unwrap();
+            let stdout = child.stdout.take().unwrap();
+            let (sender, lines) = mpsc::channel();
+            std::thread::spawn(move || {
+                for line in BufReader::new(stdout).lines() {
+                    let Ok(line) = line else { break };
+                    if sender.send(line).is_err() {
+                        break;
+                    }
+                }
+            });
+
+            let mut bot = Self {
+                name: program.to_string(),
+                child: Some(child),
+                stdin,
+                lines,
+            };
+            bot.send(&format!("cowboys {BOT_PROTOCOL_VERSION}"))?;
+            let greeting = bot.receive(GREETING_TIMEOUT)?;
+            match greeting.split_once(' ') {
+                Some(("ok", name)) if !name.trim().is_empty() => bot.name = name.trim().into(),
+                _ if greeting.trim() == "ok" => {}
+                _ => return Err(format!("unexpected greeting: {greeting}")),
+            }
+            Ok(bot)
+        }
+
+        /// Like `spawn`, but on another thread so the caller can keep drawing frames while
+        /// the bot starts. The bot, or why it could not start, comes through the receiver.
+        pub fn spawn_in_background(
+            program: String,
+            args: Vec<String>,
+        ) -> Receiver<Result<Self, String>> {
+            let (sender, bot) = mpsc::channel();
+            std::thread::spawn(move || {
+                // nobody may be waiting anymore, the bot then quits when dropped
+                let _ = sender.send(Self::spawn(&program, &args));
+            });
+            bot
+        }
+
+        fn send(&mut self, line: &str) -> Result<(), String> {
+            writeln!(self.stdin, "{line}")
+                .and_then(|_| self.stdin.flush())
+                .map_err(|e| format!("{} stopped listening: {e}", self.name))
+        }
+
+        fn receive(&mut self, timeout: Duration) -> Result<String, String> {
+            self.lines.recv_timeout(timeout).map_err(|e| match e {
+                RecvTimeoutError::Timeout => format!("{} took too long to answer", self.name),
+                RecvTimeoutError::Disconnected => format!("{} exited", self.name),
+            })
+        }
+
+        /// Tells the bot about a new round, its answer comes through `poll_action`.
+        pub fn send_round(&mut self, view: &RoundView) -> Result<(), String> {
+            // an answer to a previous round that came too late is not for this one
+            while self.lines.try_recv().is_ok() {}
+            self.send(&view.to_line())
+        }
+
+        /// The action the bot picked, if it answered yet.
+        pub fn poll_action(&mut self) -> Result<Option<Action>, String> {
+            match self.lines.try_recv() {
+                Ok(line) => parse_action(&line).map(Some),
+                Err(TryRecvError::Empty) => Ok(None),
+                Err(TryRecvError::Disconnected) => Err(format!("{} exited", self.name)),
+            }
+        }
+    }
+
+    impl Bot for ProcessBot {
+        fn name(&self) -> String {
+            self.name.clone()
+        }
+
+        fn new_game(&mut self) -> Result<(), String> {
+            self.send("newgame")
+        }
+
+        fn choose(&mut self, view: &RoundView) -> Result<Action, String> {
+            self.send_round(view)?;
+            let answer = self.receive(ANSWER_TIMEOUT)?;
+            parse_action(&answer)
+        }
+
+        fn game_over(&mut self, outcome: Outcome) {
+            // the bot may be gone already, nothing left to tell it then
+            let _ = self.send(&format!("gameover {outcome}"));
+        }
+    }
+
+    impl Drop for ProcessBot {
+        fn drop(&mut self) {
+            let _ = self.send("quit");
+            // give the bot a moment to exit by itself before stopping it, away from the
+            // caller, which may be drawing frames
+            let mut child = self.child.take().unwrap();
+            std::thread::spawn(move || {
+                std::thread::sleep(QUIT_TIMEOUT);
+                if let Ok(None) = child.try_wait() {
+                    let _ = child.kill();
+                }
+                let _ = child.wait();
+            });
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, Mutex};
+
+    use super::*;
+
+    /// Plays its actions in turn, forever.
+    struct Scripted(Vec<Action>);
+
+    impl Bot for Scripted {
+        fn name(&self) -> String {
+            "scripted".to_string()
+        }
+
+        fn choose(&mut self, view: &RoundView) -> Result<Action, String> {
+            Ok(self.0[(view.round as usize - 1) % self.0.len()])
+        }
+    }
+
+    #[test]
+    fn round_lines_round_trip() {
+        let view = RoundView {
+            round: 4,
+            me: PlayerStats { health: 2, ammo: 1 },
+            opponent: PlayerStats { health: 3, ammo: 0 },
+            last: Some((Action::Fire, Action::Shield)),
+        };
+        assert_eq!(view.to_line(), "round 4 hp 2 3 ammo 1 0 last fire shield");
+        assert_eq!(RoundView::parse(&view.to_line()), Ok(view));
+        let first = RoundView { last: None, ..view };
+        assert_eq!(RoundView::parse(&first.to_line()), Ok(first));
+        assert!(RoundView::parse("round 4 hp 2").is_err());
+    }
+
+    #[test]
+    fn commands_split_on_spaces_outside_quotes() {
+        let (program, args) =
+            split_command(r#"python3  "my bots/bot.py" --level 'very hard' "" "#).unwrap();
+        assert_eq!(program, "python3");
+        assert_eq!(args, ["my bots/bot.py", "--level", "very hard", ""]);
+        assert!(split_command("  ").is_err());
+        assert!(split_command(r#"python3 "bot.py"#).is_err());
+    }
+
+    #[test]
+    fn duel_ends_when_a_player_dies() {
+        let mut shooter = Scripted(vec![Action::Reload, Action::Fire]);
+        let mut farmer = Scripted(vec![Action::Reload]);
+        let duel = play_duel(&MatchRules::default(), [&mut shooter, &mut farmer], 100);
+        assert_eq!(duel.winner, Some(0));
+        assert_eq!(duel.rounds.len(), 6);
+        assert_eq!(duel.stats[1].health, 0);
+    }
+
+    #[test]
+    fn endless_duel_is_a_draw() {
+        let mut first = Scripted(vec![Action::Shield]);
+        let mut second = Scripted(vec![Action::Shield]);
+        let duel = play_duel(&MatchRules::default(), [&mut first, &mut second], 10);
+        assert_eq!(duel.winner, None);
+        assert_eq!(duel.rounds.len(), 10);
+    }
+
+    /// Shields, keeping every view it is shown.
+    struct Recorder(Arc<Mutex<Vec<RoundView>>>);
+
+    impl Bot for Recorder {
+        fn name(&self) -> String {
+            "recorder".to_string()
+        }
+
+        fn choose(&mut self, view: &RoundView) -> Result<Action, String> {
+            self.0.lock().unwrap().push(*view);
+            Ok(Action::Shield)
+        }
+    }
+
+    #[test]
+    fn bots_are_asked_once_per_round() {
+        let views = Arc::new(Mutex::new(vec![]));
+        let mut bot = SeatedBot::Builtin(Box::new(Recorder(views.clone())));
+        let mut turns = BotTurns::default();
+        let stats = [
+            PlayerStats { health: 3, ammo: 0 },
+            PlayerStats { health: 2, ammo: 1 },
+        ];
+        let first = Phase::Deciding {
+            from: 0,
+            until: 120,
+        };
+        let shown = Phase::Showing {
+            from: 120,
+            until: 180,
+        };
+        let second = Phase::Deciding {
+            from: 181,
+            until: 301,
+        };
+        let mut update =
+            |phase| turns.update(&mut bot, 1, phase, stats, [Action::Fire, Action::Reload]);
+        assert_eq!(update(Phase::Starting), Ok(Some(Action::Shield)));
+        assert_eq!(update(first), Ok(Some(Action::Shield)));
+        assert_eq!(update(shown), Ok(None));
+        assert_eq!(update(second), Ok(Some(Action::Shield)));
+        assert_eq!(update(second), Ok(Some(Action::Shield)));
+        let views = views.lock().unwrap();
+        assert_eq!(
+            *views,
+            [
+                RoundView {
+                    round: 1,
+                    me: stats[1],
+                    opponent: stats[0],
+                    last: None,
+                },
+                RoundView {
+                    round: 2,
+                    me: stats[1],
+                    opponent: stats[0],
+                    last: Some((Action::Reload, Action::Fire)),
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn resumed_turns_continue_the_record() {
+        let views = Arc::new(Mutex::new(vec![]));
+        let mut bot = SeatedBot::Builtin(Box::new(Recorder(views.clone())));
+        let mut turns = BotTurns::resuming(3, Some([Action::Shield, Action::Fire]));
+        let stats = [PlayerStats { health: 1, ammo: 1 }; NUM_PLAYERS];
+        let actions = [Action::Reload; NUM_PLAYERS];
+        turns
+            .update(&mut bot, 0, Phase::Starting, stats, actions)
+            .unwrap();
+        assert_eq!(turns.played(), 3);
+        let view = views.lock().unwrap()[0];
+        assert_eq!(view.round, 4);
+        assert_eq!(view.last, Some((Action::Shield, Action::Fire)));
+    }
+}
@@ -0,0 +1,120 @@
+use std::sync::Mutex;
+
+use bevy::prelude::*;
+use bevy_egui::{egui, EguiContext};
+
+use crate::bot::{Bot, BotTurns, Outcome, ProcessBot, SeatedBot};
+use crate::input::InputDevices;
+use crate::logic::{
+    current_action, ActionFire, ActionReload, Ammunition, Health, MatchRules, Player, RoundState,
+};
+use crate::network::start_local_session;
+use crate::rules::{Action, PlayerStats, NUM_PLAYERS};
+use crate::sim::Phase;
+use crate::states::GameState;
+
+/// Seat of the bot, the human plays first.
+const BOT_SEAT: usize = 1;
+
+pub(crate) struct BotSeatPlugin;
+
+impl Plugin for BotSeatPlugin {
+    fn build(&self, app: &mut App) {
+        app.add_system_set(SystemSet::on_update(GameState::InGame).with_system(drive_bot_seat));
+        app.add_system_set(SystemSet::on_exit(GameState::InGame).with_system(end_bot_seat));
+    }
+}
+
+/// External bot playing a hot-seat match in place of the second player.
+pub(crate) struct BotSeat {
+    /// Only the bot seat systems use it, the mutex makes it a resource.
+    bot: Mutex<SeatedBot>,
+    turns: BotTurns,
+    over: bool,
+    error: Option<String>,
+}
+
+/// Starts a local match against `bot`, which greeted us already.
+pub(crate) fn start_bot_match(
+    commands: &mut Commands,
+    devices: &mut InputDevices,
+    mut bot: ProcessBot,
+) -> Result<(), String> {
+    bot.new_game()?;
+    info!("playing against bot {}", bot.name());
+    commands.insert_resource(BotSeat {
+        bot: Mutex::new(SeatedBot::Process(bot)),
+        turns: BotTurns::default(),
+        over: false,
+        error: None,
+    });
+    devices.seat_bot(BOT_SEAT);
+    start_local_session(commands, MatchRules::default(), rand::random());
+    Ok(())
+}
+
+/// Tells the bot about every new round and hands its answers to the input system.
+fn drive_bot_seat(
+    seat: Option<ResMut<BotSeat>>,
+    egui_context: Res<EguiContext>,
+    round_state: Res<RoundState>,
+    mut devices: ResMut<InputDevices>,
+    query: Query<(&Player, &Health, &Ammunition, &ActionReload, &ActionFire)>,
+) {
+    let mut seat = match seat {
+        Some(seat) => seat,
+        None => return,
+    };
+    if let Some(e) = &seat.error {
+        egui::Window::new("Bot")
+            .anchor(egui::Align2::CENTER_TOP, [0.0, 10.0])
+            .collapsible(false)
+            .resizable(false)
+            .show(egui_context.ctx(), |ui| {
+                ui.colored_label(egui::Color32::RED, format!("The bot stopped playing: {e}"));
+            });
+        return;
+    }
+
+    let mut stats = [PlayerStats { health: 0, ammo: 0 }; NUM_PLAYERS];
+    let mut actions = [Action::Shield; NUM_PLAYERS];
+    for (player, health, ammo, reload, fire) in query.iter() {
+        stats[player.handle] = PlayerStats {
+            health: health.amount,
+            ammo: ammo.amount,
+        };
+        // the flags still hold the actions of the round just resolved
+        actions[player.handle] = current_action(reload, fire);
+    }
+    let opponent = (BOT_SEAT + 1) % NUM_PLAYERS;
+
+    let seat = &mut *seat;
+    let bot = seat.bot.get_mut().unwrap();
+    let result = match *round_state {
+        RoundState::GameOver if !seat.over => {
+            seat.over = true;
+            let outcome = match (stats[BOT_SEAT].health > 0, stats[opponent].health > 0) {
+                (true, false) => Outcome::Win,
+                (false, true) => Outcome::Loss,
+                _ => Outcome::Draw,
+            };
+            bot.bot().game_over(outcome);
+            Ok(())
+        }
+        // the players may not be spawned yet
+        RoundState::NotReady => Ok(()),
+        round_state => seat
+            .turns
+            .update(bot, BOT_SEAT, Phase::of(round_state), stats, actions)
+            .map(|action| devices.bot_action = action),
+    };
+    if let Err(e) = result {
+        warn!("bot error: {}", e);
+        seat.error = Some(e);
+    }
+}
+
+fn end_bot_seat(mut commands: Commands, mut devices: ResMut<InputDevices>) {
+    commands.remove_resource::<BotSeat>();
+    devices.unseat_bot();
+}
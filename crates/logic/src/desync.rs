@@ -0,0 +1,366 @@
+#[cfg(feature = "app")]
+use std::collections::BTreeMap;
+
+#[cfg(feature = "app")]
+use bevy::prelude::*;
+#[cfg(feature = "app")]
+use bevy::utils::{Duration, Instant};
+#[cfg(feature = "app")]
+use bevy_egui::{egui, EguiContext};
+#[cfg(feature = "app")]
+use bevy_ggrs::CommandsExt;
+use ggrs::Frame;
+#[cfg(feature = "app")]
+use ggrs::P2PSession;
+use serde::{Deserialize, Serialize};
+
+use crate::logic::RoundState;
+#[cfg(feature = "app")]
+use crate::logic::{
+    ActionFire, ActionReload, ActionShield, Ammunition, FrameCount, Health, MatchRules, MatchSeed,
+    Player,
+};
+#[cfg(feature = "app")]
+use crate::replay::ReplayRecorder;
+use crate::rules::{Action, PlayerStats};
+#[cfg(feature = "app")]
+use crate::socket::ChecksumChannel;
+#[cfg(feature = "app")]
+use crate::states::GameState;
+#[cfg(feature = "app")]
+use crate::storage;
+
+/// Peers compare the state of every frame that is a multiple of this.
+pub(crate) const DESYNC_CHECK_INTERVAL: Frame = 60;
+/// Frames of inputs written in desync reports.
+#[cfg(feature = "app")]
+const REPORT_INPUT_FRAMES: usize = 120;
+/// How long to wait for the peer's state once the checksums disagree, the report is
+/// written without it after that.
+#[cfg(feature = "app")]
+const REMOTE_STATE_WAIT: Duration = Duration::from_secs(2);
+
+#[derive(Clone, Serialize, Deserialize)]
+pub(crate) struct PlayerState {
+    handle: usize,
+    health: i32,
+    ammo: i32,
+    reload: bool,
+    shield: bool,
+    fire: bool,
+}
+
+impl PlayerState {
+    pub(crate) fn new(handle: usize, stats: PlayerStats, action: Action) -> Self {
+        Self {
+            handle,
+            health: stats.health,
+            ammo: stats.ammo,
+            reload: action == Action::Reload,
+            shield: action == Action::Shield,
+            fire: action == Action::Fire,
+        }
+    }
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+pub(crate) struct FrameState {
+    pub(crate) checksum: u64,
+    round_state: String,
+    players: Vec<PlayerState>,
+}
+
+impl FrameState {
+    pub(crate) fn new(round_state: &RoundState, mut players: Vec<PlayerState>) -> Self {
+        players.sort_by_key(|p| p.handle);
+        // only fixed size little-endian integers, so native and web peers agree
+        let mut hasher = Fnv1a::new();
+        hasher.write(&[round_state_tag(round_state)]);
+        for p in &players {
+            hasher.write(&(p.handle as u32).to_le_bytes());
+            hasher.write(&p.health.to_le_bytes());
+            hasher.write(&p.ammo.to_le_bytes());
+            hasher.write(&[p.reload as u8 | (p.shield as u8) << 1 | (p.fire as u8) << 2]);
+        }
+        Self {
+            checksum: hasher.finish(),
+            round_state: format!("{round_state:?}"),
+            players,
+        }
+    }
+}
+
+/// 64-bit FNV-1a. Unlike `DefaultHasher`, its output is fixed by its definition, so peers
+/// built by different compilers still agree on checksums.
+pub(crate) struct Fnv1a(u64);
+
+impl Fnv1a {
+    pub(crate) fn new() -> Self {
+        Self(0xcbf2_9ce4_8422_2325)
+    }
+
+    pub(crate) fn write(&mut self, bytes: &[u8]) {
+        for byte in bytes {
+            self.0 ^= *byte as u64;
+            self.0 = self.0.wrapping_mul(0x0100_0000_01b3);
+        }
+    }
+
+    pub(crate) fn finish(&self) -> u64 {
+        self.0
+    }
+}
+
+fn round_state_tag(round_state: &RoundState) -> u8 {
+    match round_state {
+        RoundState::NotReady => 0,
+        RoundState::WaitUntil(_) => 1,
+        RoundState::DisplayUntil(_) => 2,
+        RoundState::Compute => 3,
+        RoundState::NextRound => 4,
+        RoundState::GameOver => 5,
+    }
+}
+
+#[cfg(feature = "app")]
+#[derive(Serialize)]
+struct DesyncReport {
+    frame: Frame,
+    local_checksum: u64,
+    remote_checksum: u64,
+    local_handle: Option<usize>,
+    state: FrameState,
+    /// State the peer reached, `None` if it did not arrive in time.
+    remote_state: Option<FrameState>,
+    /// Inputs of the last frames, the most recent ones may still be predictions.
+    recent_inputs: Vec<Vec<Vec<u8>>>,
+    /// Rounds played so far, see `notation`.
+    rounds: Option<String>,
+}
+
+/// A desync report waiting for the peer's state.
+#[cfg(feature = "app")]
+struct PendingReport {
+    report: DesyncReport,
+    since: Instant,
+}
+
+/// Compares the game state with the remote peer during a P2P match.
+#[cfg(feature = "app")]
+pub(crate) struct DesyncDetector {
+    channel: ChecksumChannel,
+    /// State of the frames not compared yet, overwritten when rollbacks resimulate them.
+    local: BTreeMap<Frame, FrameState>,
+    remote: BTreeMap<Frame, u64>,
+    /// Frames up to this one were sent to the peer.
+    sent_until: Frame,
+    /// Set once the checksums disagree.
+    pending: Option<PendingReport>,
+}
+
+#[cfg(feature = "app")]
+impl DesyncDetector {
+    pub(crate) fn new(channel: ChecksumChannel) -> Self {
+        Self {
+            channel,
+            local: BTreeMap::new(),
+            remote: BTreeMap::new(),
+            sent_until: -1,
+            pending: None,
+        }
+    }
+}
+
+/// Set once the peers disagree, the match is over.
+#[cfg(feature = "app")]
+pub(crate) struct Desync {
+    frame: Frame,
+    report: Result<String, String>,
+}
+
+/// Records the state reached by the frame being simulated, run by GGRS in the rollback schedule.
+#[cfg(feature = "app")]
+pub(crate) fn record_state(
+    frame_count: Res<FrameCount>,
+    round_state: Res<RoundState>,
+    detector: Option<ResMut<DesyncDetector>>,
+    query: Query<(
+        &Player,
+        &Health,
+        &Ammunition,
+        &ActionReload,
+        &ActionShield,
+        &ActionFire,
+    )>,
+) {
+    let mut detector = match detector {
+        Some(detector) => detector,
+        None => return,
+    };
+    if frame_count.frame % DESYNC_CHECK_INTERVAL != 0 {
+        return;
+    }
+    let players = query
+        .iter()
+        .map(|(player, health, ammo, reload, shield, fire)| PlayerState {
+            handle: player.handle,
+            health: health.amount,
+            ammo: ammo.amount,
+            reload: reload.is_active,
+            shield: shield.is_active,
+            fire: fire.is_active,
+        })
+        .collect();
+    detector
+        .local
+        .insert(frame_count.frame, FrameState::new(&round_state, players));
+}
+
+/// Sends the checksums of frames no rollback can change anymore, and compares them with
+/// the ones of the peer. On a mismatch, both peers send their state to each other and
+/// write it in the desync report, the match goes on until the peer's state arrives.
+#[cfg(feature = "app")]
+pub(crate) fn check_desync(
+    mut commands: Commands,
+    session: Option<Res<P2PSession>>,
+    detector: Option<ResMut<DesyncDetector>>,
+    recorder: Res<ReplayRecorder>,
+    desync: Option<Res<Desync>>,
+    rules: Res<MatchRules>,
+    seed: Res<MatchSeed>,
+) {
+    let (session, mut detector) = match (session, detector) {
+        (Some(session), Some(detector)) if desync.is_none() => (session, detector),
+        _ => return,
+    };
+    let detector = &mut *detector;
+    if let Some(pending) = &mut detector.pending {
+        for (frame, state) in detector.channel.receive_states() {
+            if frame == pending.report.frame {
+                pending.report.remote_state = serde_json::from_slice(&state).ok();
+            }
+        }
+        if pending.report.remote_state.is_none() && pending.since.elapsed() < REMOTE_STATE_WAIT {
+            // packets may be lost, so ours is sent again until the peer's comes
+            let state = serde_json::to_vec(&pending.report.state).unwrap();
+            detector.channel.send_state(pending.report.frame, state);
+            return;
+        }
+        let report = detector.pending.take().unwrap().report;
+        let frame = report.frame;
+        let json = serde_json::to_string_pretty(&report).unwrap();
+        info!("desync report:\n{}", json);
+        let key = format!("desync-{frame}.json");
+        let report = storage::save(&key, &json).map(|_| key);
+
+        commands.insert_resource(Desync { frame, report });
+        commands.stop_session();
+        return;
+    }
+
+    // GGRS never predicts further than `max_prediction` frames, older ones are final
+    let settled = session.current_frame() - session.max_prediction() as Frame - 1;
+    if settled > detector.sent_until {
+        for (frame, state) in detector.local.range(detector.sent_until + 1..=settled) {
+            detector.channel.send(*frame, state.checksum);
+        }
+        detector.sent_until = settled;
+    }
+    for (frame, checksum) in detector.channel.receive() {
+        detector.remote.insert(frame, checksum);
+    }
+
+    let compared: Vec<Frame> = detector
+        .remote
+        .keys()
+        .copied()
+        .filter(|frame| *frame <= detector.sent_until && detector.local.contains_key(frame))
+        .collect();
+    for frame in compared {
+        let remote_checksum = detector.remote.remove(&frame).unwrap();
+        let state = detector.local.remove(&frame).unwrap();
+        if state.checksum == remote_checksum {
+            continue;
+        }
+
+        error!("desync detected at frame {}", frame);
+        detector
+            .channel
+            .send_state(frame, serde_json::to_vec(&state).unwrap());
+        detector.pending = Some(PendingReport {
+            report: DesyncReport {
+                frame,
+                local_checksum: state.checksum,
+                remote_checksum,
+                local_handle: session.local_player_handle(),
+                state,
+                remote_state: None,
+                recent_inputs: recorder.recent_inputs(REPORT_INPUT_FRAMES).to_vec(),
+                rounds: recorder.notation(&rules, seed.0).ok(),
+            },
+            since: Instant::now(),
+        });
+        return;
+    }
+    // a lost packet means a frame is never compared, keep the history bounded
+    let oldest = detector.sent_until - 10 * DESYNC_CHECK_INTERVAL;
+    detector.local.retain(|frame, _| *frame > oldest);
+    detector.remote.retain(|frame, _| *frame > oldest);
+}
+
+#[cfg(feature = "app")]
+pub(crate) fn desync_display(
+    egui_context: Res<EguiContext>,
+    desync: Option<Res<Desync>>,
+    mut state: ResMut<State<GameState>>,
+) {
+    let desync = match desync {
+        Some(desync) => desync,
+        None => return,
+    };
+    egui::Window::new("Desync")
+        .anchor(egui::Align2::CENTER_CENTER, [0.0, 0.0])
+        .collapsible(false)
+        .resizable(false)
+        .show(egui_context.ctx(), |ui| {
+            ui.colored_label(
+                egui::Color32::RED,
+                format!(
+                    "The game went out of sync with your opponent at frame {}.",
+                    desync.frame
+                ),
+            );
+            match &desync.report {
+                Ok(key) => ui.label(format!(
+                    "A report was saved as {key}, please send it to us."
+                )),
+                Err(e) => ui.label(format!("Could not save a report: {e}")),
+            };
+            if ui.button("Back to lobby").clicked() {
+                state.set(GameState::Lobby).unwrap();
+            }
+        });
+}
+
+#[cfg(feature = "app")]
+pub(crate) fn end_desync_check(mut commands: Commands) {
+    commands.remove_resource::<DesyncDetector>();
+    commands.remove_resource::<Desync>();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn fnv1a_matches_reference_values() {
+        let hash = |bytes: &[u8]| {
+            let mut hasher = Fnv1a::new();
+            hasher.write(bytes);
+            hasher.finish()
+        };
+        assert_eq!(hash(b""), 0xcbf2_9ce4_8422_2325);
+        assert_eq!(hash(b"a"), 0xaf63_dc4c_8601_ec8c);
+        assert_eq!(hash(b"foobar"), 0x8594_4171_f739_67e8);
+    }
+}
@@ -1,19 +1,26 @@
 use bevy::prelude::*;
 use bevy_asset_loader::{AssetCollection, AssetLoader};
 use bevy_egui::{egui, EguiContext, EguiPlugin};
-use ggrs::P2PSession;
 
 use crate::{
-    logic::{ActionFire, ActionReload, ActionShield, Ammunition, Health, Player, RoundState},
+    input::InputDevices,
+    logic::{
+        ActionFire, ActionReload, ActionShield, Ammunition, FrameCount, Health, Player, RoundState,
+    },
+    replay::{ReplayPlayback, ReplayRecorder},
+    rules::Action,
     states::GameState,
 };
 
+/// Big enough to be tapped on a phone.
+const ACTION_BUTTON_SIZE: f32 = 96.0;
+
 pub struct DisplayPlugin;
 
 impl Plugin for DisplayPlugin {
     fn build(&self, app: &mut App) {
         AssetLoader::new(GameState::AssetLoading)
-            .continue_to_state(GameState::Matchmaking)
+            .continue_to_state(GameState::Lobby)
             .with_collection::<ImageAssets>()
             .build(app);
         app.insert_resource(TexturesEgui::default());
@@ -24,8 +31,11 @@ impl Plugin for DisplayPlugin {
         app.add_system_set(
             SystemSet::on_update(GameState::InGame)
                 .with_system(round_time_progress)
-                .with_system(actions_display),
+                .with_system(actions_display)
+                .with_system(action_buttons)
+                .with_system(game_over_display),
         );
+        app.add_system_set(SystemSet::on_exit(GameState::InGame).with_system(despawn_display));
     }
 }
 
@@ -123,15 +133,24 @@ pub(crate) fn spawn_display_static(
     });
 }
 
+/// Entities drawing the match, despawned with it.
+type DisplayEntities = Or<(With<DisplayPlayer>, With<DisplayRoundProgress>)>;
+
+fn despawn_display(mut commands: Commands, query: Query<Entity, DisplayEntities>) {
+    for e in query.iter() {
+        commands.entity(e).despawn();
+    }
+}
+
 fn round_time_progress(
-    session: Res<P2PSession>,
+    frame_count: Res<FrameCount>,
     round_state: Res<RoundState>,
     mut hp_query: Query<(&mut Transform, &DisplayRoundProgress)>,
 ) {
     match &*round_state {
         RoundState::WaitUntil(wait) => {
             let duration = wait.until - wait.from;
-            let ratio = (session.current_frame() - wait.from) as f32 / duration as f32;
+            let ratio = (frame_count.frame - wait.from) as f32 / duration as f32;
             for (mut t, def) in hp_query.iter_mut() {
                 t.translation = def.from.lerp(def.to, ratio).extend(0.0);
             }
@@ -231,6 +250,78 @@ fn actions_display(
     }
 }
 
+/// On-screen action buttons, the only way to play on touch screens.
+fn action_buttons(
+    egui_context: Res<EguiContext>,
+    egui_textures: Res<TexturesEgui>,
+    round_state: Res<RoundState>,
+    playback: Option<Res<ReplayPlayback>>,
+    mut devices: ResMut<InputDevices>,
+) {
+    if !matches!(*round_state, RoundState::WaitUntil(_)) || playback.is_some() {
+        devices.pointer_action = None;
+        return;
+    }
+    egui::Window::new("Actions")
+        .anchor(egui::Align2::CENTER_BOTTOM, [0.0, -10.0])
+        .title_bar(false)
+        .resizable(false)
+        .show(egui_context.ctx(), |ui| {
+            ui.horizontal(|ui| {
+                for action in Action::ALL {
+                    let texture = match action {
+                        Action::Reload => egui_textures.reload,
+                        Action::Shield => egui_textures.shield,
+                        Action::Fire => egui_textures.gunshot,
+                    };
+                    ui.vertical_centered(|ui| {
+                        let button = egui::ImageButton::new(
+                            egui::TextureId::User(texture),
+                            [ACTION_BUTTON_SIZE, ACTION_BUTTON_SIZE],
+                        );
+                        if ui.add(button).clicked() {
+                            devices.pointer_action = Some(action);
+                        }
+                        ui.label(action.name());
+                    });
+                }
+            });
+        });
+}
+
+fn game_over_display(
+    egui_context: Res<EguiContext>,
+    round_state: Res<RoundState>,
+    recorder: Res<ReplayRecorder>,
+    hp_query: Query<(&Player, &Health)>,
+    mut state: ResMut<State<GameState>>,
+) {
+    if !matches!(*round_state, RoundState::GameOver) {
+        return;
+    }
+    let winners: Vec<usize> = hp_query
+        .iter()
+        .filter(|(_, hp)| hp.amount > 0)
+        .map(|(player, _)| player.handle)
+        .collect();
+    egui::Window::new("Game over")
+        .anchor(egui::Align2::CENTER_CENTER, [0.0, 0.0])
+        .collapsible(false)
+        .resizable(false)
+        .show(egui_context.ctx(), |ui| {
+            match winners.as_slice() {
+                [winner] if recorder.forfeited().is_some() => {
+                    ui.heading(format!("Player {} wins by forfeit!", winner))
+                }
+                [winner] => ui.heading(format!("Player {} wins!", winner)),
+                _ => ui.heading("Draw"),
+            };
+            if ui.button("Back to lobby").clicked() {
+                state.set(GameState::Lobby).unwrap();
+            }
+        });
+}
+
 fn raw_display_action(
     title: &str,
     image: egui::TextureId,
@@ -0,0 +1,321 @@
+//! Duels as a reinforcement learning environment, stepped without the Bevy app.
+//!
+//! Both players act every step, each getting its own observation and reward, so the same
+//! environment trains against a fixed opponent or in self-play.
+//!
+//! The environment is stricter than the game: firing without ammo is rejected rather than
+//! played as the no-op `resolve_round` makes it, so agents learn from `legal_mask` instead
+//! of wasting rounds on an action that does nothing.
+
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+
+use crate::bot::DEFAULT_MAX_ROUNDS;
+use crate::logic::MatchRules;
+use crate::rules::{resolve_round, Action, PlayerStats, NUM_PLAYERS};
+
+/// Length of an `Observation`.
+pub const OBSERVATION_SIZE: usize = 11;
+
+/// From a player's point of view: its HP and ammo, the opponent's, the round starting at
+/// 0, then one-hot encodings of its last action and the opponent's in `Action::ALL`
+/// order, all zeros on the first round.
+pub type Observation = [f32; OBSERVATION_SIZE];
+
+#[derive(Clone)]
+pub struct EnvConfig {
+    pub rules: MatchRules,
+    /// Rounds after which the episode ends in a draw.
+    pub max_rounds: u32,
+    /// Start episodes from random HP and ammo drawn from the reset seed, for exploration,
+    /// instead of the rules' starting values.
+    pub random_start: bool,
+}
+
+impl Default for EnvConfig {
+    fn default() -> Self {
+        Self {
+            rules: MatchRules::default(),
+            max_rounds: DEFAULT_MAX_ROUNDS,
+            random_start: false,
+        }
+    }
+}
+
+/// What a step led to, by player.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Step {
+    pub observations: [Observation; NUM_PLAYERS],
+    /// 1 to the winner and -1 to the loser on the last step, 0 otherwise.
+    pub rewards: [f32; NUM_PLAYERS],
+    pub done: bool,
+}
+
+pub struct Env {
+    config: EnvConfig,
+    stats: [PlayerStats; NUM_PLAYERS],
+    round: u32,
+    last: Option<[Action; NUM_PLAYERS]>,
+    done: bool,
+}
+
+impl Env {
+    /// A finished environment, `reset` starts the first episode.
+    pub fn new(config: EnvConfig) -> Self {
+        Self {
+            stats: [PlayerStats { health: 0, ammo: 0 }; NUM_PLAYERS],
+            config,
+            round: 0,
+            last: None,
+            done: true,
+        }
+    }
+
+    pub fn reset(&mut self, seed: u64) -> [Observation; NUM_PLAYERS] {
+        let rules = &self.config.rules;
+        let mut rng = StdRng::seed_from_u64(seed);
+        for stats in self.stats.iter_mut() {
+            *stats = if self.config.random_start {
+                PlayerStats {
+                    health: rng.gen_range(1..=rules.starting_health.max(1)),
+                    ammo: rng.gen_range(0..=rules.starting_ammo.max(3)),
+                }
+            } else {
+                PlayerStats {
+                    health: rules.starting_health,
+                    ammo: rules.starting_ammo,
+                }
+            };
+        }
+        self.round = 0;
+        self.last = None;
+        self.done = false;
+        self.observations()
+    }
+
+    /// Plays a round. Fails once the episode is done or if an action is not legal.
+    pub fn step(&mut self, actions: [Action; NUM_PLAYERS]) -> Result<Step, String> {
+        self.check(actions)?;
+        self.stats = resolve_round(self.stats, actions);
+        self.round += 1;
+        self.last = Some(actions);
+
+        let alive = self.stats.map(|stats| stats.health > 0);
+        let mut rewards = [0.0; NUM_PLAYERS];
+        if alive.iter().filter(|alive| **alive).count() == 1 {
+            rewards = alive.map(|alive| if alive { 1.0 } else { -1.0 });
+        }
+        self.done = alive.contains(&false) || self.round >= self.config.max_rounds;
+        Ok(Step {
+            observations: self.observations(),
+            rewards,
+            done: self.done,
+        })
+    }
+
+    fn check(&self, actions: [Action; NUM_PLAYERS]) -> Result<(), String> {
+        if self.done {
+            return Err("the episode is done, reset first".to_string());
+        }
+        for (player, action) in actions.iter().enumerate() {
+            if !self.legal_actions(player).contains(action) {
+                return Err(format!("player {player} cannot {}", action.name()));
+            }
+        }
+        Ok(())
+    }
+
+    /// Firing takes ammo, everything else is always allowed. The game lets players fire
+    /// without ammo, to no effect, the environment does not.
+    pub fn legal_actions(&self, player: usize) -> Vec<Action> {
+        Action::ALL
+            .into_iter()
+            .filter(|action| *action != Action::Fire || self.stats[player].ammo > 0)
+            .collect()
+    }
+
+    /// `legal_actions` as a mask over `Action::ALL`.
+    pub fn legal_mask(&self, player: usize) -> [bool; 3] {
+        Action::ALL.map(|action| self.legal_actions(player).contains(&action))
+    }
+
+    pub fn stats(&self) -> [PlayerStats; NUM_PLAYERS] {
+        self.stats
+    }
+
+    pub fn is_done(&self) -> bool {
+        self.done
+    }
+
+    fn observations(&self) -> [Observation; NUM_PLAYERS] {
+        let mut observations = [[0.0; OBSERVATION_SIZE]; NUM_PLAYERS];
+        for (player, observation) in observations.iter_mut().enumerate() {
+            let opponent = (player + 1) % NUM_PLAYERS;
+            observation[0] = self.stats[player].health as f32;
+            observation[1] = self.stats[player].ammo as f32;
+            observation[2] = self.stats[opponent].health as f32;
+            observation[3] = self.stats[opponent].ammo as f32;
+            observation[4] = self.round as f32;
+            if let Some(last) = self.last {
+                for (offset, action) in [(5, last[player]), (8, last[opponent])] {
+                    let index = Action::ALL.iter().position(|a| *a == action).unwrap();
+                    observation[offset + index] = 1.0;
+                }
+            }
+        }
+        observations
+    }
+}
+
+/// Results of a batch step, flattened environment by environment then player by player.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct BatchStep {
+    /// `OBSERVATION_SIZE` values per player, of the new episode where one just ended.
+    pub observations: Vec<f32>,
+    pub rewards: Vec<f32>,
+    /// One per environment.
+    pub dones: Vec<bool>,
+    /// One per environment: the observations that ended its episode, flattened, where
+    /// `observations` already show the next one. Like Gym's `final_observation`.
+    pub final_observations: Vec<Option<Vec<f32>>>,
+}
+
+/// Environments stepped together, each starting a new episode as soon as one ends.
+pub struct BatchEnv {
+    envs: Vec<Env>,
+    /// Seed of the next episode to start.
+    next_seed: u64,
+}
+
+impl BatchEnv {
+    pub fn new(count: usize, config: EnvConfig) -> Self {
+        Self {
+            envs: (0..count).map(|_| Env::new(config.clone())).collect(),
+            next_seed: 0,
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.envs.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.envs.is_empty()
+    }
+
+    pub fn envs(&self) -> &[Env] {
+        &self.envs
+    }
+
+    /// Resets every environment, the episodes seeded from `seed` on. Returns the flattened
+    /// observations.
+    pub fn reset(&mut self, seed: u64) -> Vec<f32> {
+        self.next_seed = seed;
+        let mut observations = Vec::with_capacity(self.envs.len() * NUM_PLAYERS * OBSERVATION_SIZE);
+        for env in self.envs.iter_mut() {
+            observations.extend(env.reset(self.next_seed).concat());
+            self.next_seed = self.next_seed.wrapping_add(1);
+        }
+        observations
+    }
+
+    /// Steps environment `i` with `actions[i]`. Nothing is stepped if any action fails.
+    pub fn step(&mut self, actions: &[[Action; NUM_PLAYERS]]) -> Result<BatchStep, String> {
+        if actions.len() != self.envs.len() {
+            return Err(format!(
+                "{} actions for {} environments",
+                actions.len(),
+                self.envs.len()
+            ));
+        }
+        for (i, (env, actions)) in self.envs.iter().zip(actions).enumerate() {
+            env.check(*actions)
+                .map_err(|e| format!("environment {i}: {e}"))?;
+        }
+        let mut batch = BatchStep::default();
+        for (env, actions) in self.envs.iter_mut().zip(actions) {
+            let step = env.step(*actions)?;
+            let mut observations = step.observations;
+            let mut last = None;
+            if step.done {
+                last = Some(observations.concat());
+                observations = env.reset(self.next_seed);
+                self.next_seed = self.next_seed.wrapping_add(1);
+            }
+            batch.observations.extend(observations.concat());
+            batch.rewards.extend(step.rewards);
+            batch.dones.push(step.done);
+            batch.final_observations.push(last);
+        }
+        Ok(batch)
+    }
+
+    /// Flattened `Env::legal_mask` of every player.
+    pub fn legal_masks(&self) -> Vec<bool> {
+        self.envs
+            .iter()
+            .flat_map(|env| (0..NUM_PLAYERS).flat_map(|player| env.legal_mask(player)))
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn episode_ends_with_rewards() {
+        let mut env = Env::new(EnvConfig::default());
+        let start = env.reset(0);
+        assert_eq!(
+            start[0],
+            [3.0, 0.0, 3.0, 0.0, 0.0, 0.0, 0.0, 0.0, 0.0, 0.0, 0.0]
+        );
+        assert_eq!(env.legal_actions(0), vec![Action::Reload, Action::Shield]);
+        assert!(env.step([Action::Fire, Action::Reload]).is_err());
+
+        env.step([Action::Reload, Action::Reload]).unwrap();
+        let mut step = env.step([Action::Fire, Action::Reload]).unwrap();
+        assert_eq!(step.observations[1][..5], [2.0, 2.0, 3.0, 0.0, 2.0]);
+        // last actions: reload then fire from the second player's point of view
+        assert_eq!(step.observations[1][5..], [1.0, 0.0, 0.0, 0.0, 0.0, 1.0]);
+        for _ in 0..2 {
+            env.step([Action::Reload, Action::Shield]).unwrap();
+            step = env.step([Action::Fire, Action::Reload]).unwrap();
+        }
+        assert!(step.done);
+        assert_eq!(step.rewards, [1.0, -1.0]);
+        assert!(env.step([Action::Shield, Action::Shield]).is_err());
+    }
+
+    #[test]
+    fn batch_starts_new_episodes() {
+        let config = EnvConfig {
+            max_rounds: 2,
+            ..Default::default()
+        };
+        let mut batch = BatchEnv::new(3, config);
+        assert_eq!(batch.reset(7).len(), 3 * NUM_PLAYERS * OBSERVATION_SIZE);
+        let shields = vec![[Action::Shield; NUM_PLAYERS]; 3];
+        let step = batch.step(&shields).unwrap();
+        assert_eq!(step.dones, vec![false; 3]);
+        assert_eq!(step.final_observations, vec![None; 3]);
+        let step = batch.step(&shields).unwrap();
+        assert_eq!(step.dones, vec![true; 3]);
+        assert_eq!(step.rewards, vec![0.0; 3 * NUM_PLAYERS]);
+        // back to round 0
+        assert!(step
+            .observations
+            .chunks(OBSERVATION_SIZE)
+            .all(|observation| observation[4] == 0.0));
+        // while the episodes that ended stopped at round 2
+        for last in &step.final_observations {
+            let last = last.as_ref().unwrap();
+            assert_eq!(last.len(), NUM_PLAYERS * OBSERVATION_SIZE);
+            assert!(last
+                .chunks(OBSERVATION_SIZE)
+                .all(|observation| observation[4] == 2.0));
+        }
+        assert!(batch.envs().iter().all(|env| !env.is_done()));
+    }
+}
@@ -0,0 +1,432 @@
+//! Two headless clients playing a P2P match in the same process, connected through memory.
+//! Both advance one frame at a time and the simulated network runs on a clock moved by
+//! whole frames, so a test plays out the same however fast the machine runs it.
+
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+use bevy::prelude::*;
+use bevy::reflect::GetTypeRegistration;
+use bevy_ggrs::{Rollback, RollbackIdProvider};
+use ggrs::{Frame, GGRSError, GGRSRequest, GameInput, P2PSession, PlayerType, SessionState};
+
+use crate::logic::{
+    spawn_players, Ammunition, FrameCount, Health, MatchRules, MatchSeed, Player, RoundState,
+};
+use crate::netsim::{NetClock, NetworkConditions, SimulatedTransport};
+use crate::netstats::RollbackCounter;
+use crate::network::{connect_peers, new_p2p_session, HandshakeTimers, FRAME_DURATION};
+use crate::protocol::PlayerInput;
+use crate::replay::ReplayRecorder;
+use crate::rules::{Action, NUM_PLAYERS};
+use crate::socket::{GameSocket, MemoryTransport, Transport};
+use crate::{register_rollback_types, rollback_schedule, RollbackTypes};
+
+/// A match which has not reached the expected state after this many frames is stuck.
+const FRAME_BUDGET: usize = 1200;
+const INPUT_DELAY: u32 = 2;
+const FIXED_STEP: &str = "fixed_step";
+
+/// Sends through the simulated network, except while GGRS synchronizes the sessions: it
+/// retries lost sync requests on the wall clock, which the manual clock doesn't move.
+struct SyncBypass {
+    /// The transport under `simulated`.
+    direct: MemoryTransport,
+    simulated: SimulatedTransport,
+    synchronizing: Arc<AtomicBool>,
+}
+
+impl SyncBypass {
+    fn transport(&mut self) -> &mut dyn Transport {
+        if self.synchronizing.load(Ordering::Relaxed) {
+            &mut self.direct
+        } else {
+            &mut self.simulated
+        }
+    }
+}
+
+impl Transport for SyncBypass {
+    fn connected_peers(&self) -> Vec<SocketAddr> {
+        self.direct.connected_peers()
+    }
+
+    fn players(&self) -> Vec<PlayerType> {
+        self.direct.players()
+    }
+
+    fn send(&mut self, packet: Box<[u8]>, addr: SocketAddr) {
+        self.transport().send(packet, addr);
+    }
+
+    fn receive(&mut self) -> Vec<(SocketAddr, Box<[u8]>)> {
+        self.transport().receive()
+    }
+}
+
+/// Action of each player, round after round. Players pick nothing once their script is over.
+struct Script(Vec<Vec<Action>>);
+
+/// Replaces `input::local_input`, playing the script of the local player.
+fn scripted_input(world: &World, handle: usize) -> Vec<u8> {
+    let rules = world.get_resource::<MatchRules>().unwrap();
+    let script = world.get_resource::<Script>().unwrap();
+    let action = match *world.get_resource::<RoundState>().unwrap() {
+        RoundState::WaitUntil(wait) => {
+            let round = wait.from / (rules.decision_frames + rules.display_frames);
+            script.0[handle].get(round as usize).copied()
+        }
+        _ => None,
+    };
+    let input = PlayerInput {
+        action,
+        target: ((handle + 1) % NUM_PLAYERS) as u8,
+        ..Default::default()
+    };
+    input.encode().to_vec()
+}
+
+type Restore = Box<dyn Fn(&mut World) + Send + Sync>;
+
+/// What `add_rollback` registers with bevy_ggrs, saved and loaded like it does: from the
+/// rollback entities and from the resources.
+struct Snapshot(Vec<Restore>);
+
+impl Snapshot {
+    fn save(world: &mut World) -> Self {
+        let mut saver = Saver {
+            world,
+            restores: vec![],
+        };
+        register_rollback_types(&mut saver);
+        Self(saver.restores)
+    }
+
+    fn load(&self, world: &mut World) {
+        for restore in &self.0 {
+            restore(world);
+        }
+    }
+}
+
+/// Copies each rolled back type out of the world, with how to put it back.
+struct Saver<'w> {
+    world: &'w mut World,
+    restores: Vec<Restore>,
+}
+
+impl RollbackTypes for Saver<'_> {
+    fn register<T: GetTypeRegistration + Reflect + Default + Component>(&mut self) {
+        let mut query = self.world.query_filtered::<(Entity, &T), With<Rollback>>();
+        for (entity, component) in query.iter(self.world) {
+            let saved = component.clone_value();
+            self.restores.push(Box::new(move |world: &mut World| {
+                world
+                    .entity_mut(entity)
+                    .get_mut::<T>()
+                    .unwrap()
+                    .apply(&*saved);
+            }));
+        }
+        if let Some(resource) = self.world.get_resource::<T>() {
+            let saved = resource.clone_value();
+            self.restores.push(Box::new(move |world: &mut World| {
+                world.get_resource_mut::<T>().unwrap().apply(&*saved);
+            }));
+        }
+    }
+}
+
+/// Advances the P2P session by exactly one frame per update. bevy_ggrs's stage runs as
+/// many frames as the wall clock says, which would make tests depend on the machine.
+struct FixedStepStage {
+    schedule: Schedule,
+    /// Snapshots GGRS asked to save, indexed by frame modulo their count.
+    snapshots: Vec<Option<Snapshot>>,
+}
+
+impl Stage for FixedStepStage {
+    fn run(&mut self, world: &mut World) {
+        let mut session = world.get_resource_mut::<P2PSession>().unwrap();
+        session.poll_remote_clients();
+        session.events().for_each(drop);
+        if session.current_state() != SessionState::Running {
+            return;
+        }
+        if self.snapshots.is_empty() {
+            self.snapshots = (0..session.max_prediction() + 2).map(|_| None).collect();
+        }
+        let handle = session.local_player_handle().unwrap();
+        let input = scripted_input(world, handle);
+        let mut session = world.get_resource_mut::<P2PSession>().unwrap();
+        let requests = match session.advance_frame(handle, &input) {
+            Ok(requests) => requests,
+            Err(GGRSError::PredictionThreshold) => return,
+            Err(e) => panic!("could not advance the frame: {e}"),
+        };
+        let len = self.snapshots.len();
+        for request in requests {
+            match request {
+                GGRSRequest::SaveGameState { cell, frame } => {
+                    cell.save(ggrs::GameState::new(frame, None));
+                    self.snapshots[frame as usize % len] = Some(Snapshot::save(world));
+                }
+                GGRSRequest::LoadGameState { cell, .. } => {
+                    let frame = cell.load().frame;
+                    self.snapshots[frame as usize % len]
+                        .as_ref()
+                        .expect("no snapshot of the frame to load")
+                        .load(world);
+                }
+                GGRSRequest::AdvanceFrame { inputs } => {
+                    world.insert_resource(inputs);
+                    self.schedule.run_once(world);
+                    world.remove_resource::<Vec<GameInput>>();
+                }
+            }
+        }
+    }
+}
+
+/// Rules short enough for a match to last a few seconds.
+fn test_rules() -> MatchRules {
+    MatchRules {
+        decision_frames: 10,
+        display_frames: 2,
+        ..Default::default()
+    }
+}
+
+/// Frame at which `round` has been resolved.
+fn end_of_round(round: usize) -> Frame {
+    let rules = test_rules();
+    (round as Frame + 1) * (rules.decision_frames + rules.display_frames)
+}
+
+/// Both clients of a match, player `i` playing on `apps[i]`.
+struct Match {
+    apps: [App; NUM_PLAYERS],
+    clock: NetClock,
+    /// Whether GGRS is still synchronizing the sessions, see `SyncBypass`.
+    synchronizing: Arc<AtomicBool>,
+}
+
+impl Match {
+    fn new(script: Vec<Vec<Action>>) -> Self {
+        Self::with_conditions(script, None)
+    }
+
+    /// Every packet between the clients goes through the network `conditions`, if any.
+    fn with_conditions(script: Vec<Vec<Action>>, conditions: Option<NetworkConditions>) -> Self {
+        let addrs: [SocketAddr; 2] = [
+            "127.0.0.1:7000".parse().unwrap(),
+            "127.0.0.1:7001".parse().unwrap(),
+        ];
+        let clock = NetClock::manual();
+        let synchronizing = Arc::new(AtomicBool::new(false));
+        let [first, second] = MemoryTransport::pair(addrs);
+        let sockets =
+            [(0, first), (1, second)].map(|(handle, transport)| match conditions.clone() {
+                Some(conditions) => {
+                    // each end loses and delays its own packets
+                    let seed = conditions.seed.map(|s| s + handle);
+                    let simulated = SimulatedTransport::new(
+                        Box::new(transport.clone()),
+                        NetworkConditions { seed, ..conditions },
+                        clock.clone(),
+                    );
+                    GameSocket::new(SyncBypass {
+                        direct: transport,
+                        simulated,
+                        synchronizing: synchronizing.clone(),
+                    })
+                }
+                None => GameSocket::new(transport),
+            });
+        let sessions = connect(sockets, &clock);
+        synchronizing.store(true, Ordering::Relaxed);
+        let apps = sessions.map(|mut session| {
+            session.start_session().unwrap();
+            client(session, Script(script.clone()))
+        });
+        Self {
+            apps,
+            clock,
+            synchronizing,
+        }
+    }
+
+    /// Updates both clients a frame at a time until `done` holds for each of them.
+    fn run_until(&mut self, mut done: impl FnMut(&mut World) -> bool) {
+        for _ in 0..FRAME_BUDGET {
+            if self.apps.iter_mut().all(|app| done(&mut app.world)) {
+                return;
+            }
+            let synchronizing = self.apps.iter().any(|app| {
+                let session = app.world.get_resource::<P2PSession>().unwrap();
+                session.current_state() != SessionState::Running
+            });
+            self.synchronizing.store(synchronizing, Ordering::Relaxed);
+            self.clock.advance(FRAME_DURATION);
+            for app in &mut self.apps {
+                app.update();
+            }
+        }
+        panic!("match stuck after {FRAME_BUDGET} frames");
+    }
+
+    /// Updates both clients until they simulated `frame` and it can't be rolled back anymore.
+    /// Inputs may be confirmed before their frame is simulated, the input delay sends them early.
+    fn run_until_confirmed(&mut self, frame: Frame) {
+        self.run_until(|world| {
+            let confirmed = world
+                .get_resource::<P2PSession>()
+                .unwrap()
+                .confirmed_frame();
+            confirmed >= frame && world.get_resource::<FrameCount>().unwrap().frame > frame
+        });
+    }
+
+    /// Asserts both clients agree on a value, and returns it.
+    fn agreed<T: PartialEq + std::fmt::Debug>(
+        &mut self,
+        mut read: impl FnMut(&mut World) -> T,
+    ) -> T {
+        let [first, second] = &mut self.apps;
+        let value = read(&mut first.world);
+        assert_eq!(value, read(&mut second.world), "clients disagree");
+        value
+    }
+
+    fn health(&mut self, handle: usize) -> i32 {
+        self.agreed(|world| {
+            let mut query = world.query::<(&Player, &Health)>();
+            query
+                .iter(world)
+                .find(|(p, _)| p.handle == handle)
+                .unwrap()
+                .1
+                .amount
+        })
+    }
+
+    fn ammo(&mut self, handle: usize) -> i32 {
+        self.agreed(|world| {
+            let mut query = world.query::<(&Player, &Ammunition)>();
+            query
+                .iter(world)
+                .find(|(p, _)| p.handle == handle)
+                .unwrap()
+                .1
+                .amount
+        })
+    }
+}
+
+/// Runs the handshake on both sockets like `wait_for_players`, a frame at a time, then
+/// makes their sessions. The input delay is fixed, the round trips being wall clock ones.
+fn connect(mut sockets: [GameSocket; NUM_PLAYERS], clock: &NetClock) -> [P2PSession; NUM_PLAYERS] {
+    let rules = test_rules();
+    let mut timers = [(); NUM_PLAYERS].map(|_| HandshakeTimers::default());
+    let mut done = [false; NUM_PLAYERS];
+    for _ in 0..FRAME_BUDGET {
+        if done.iter().all(|done| *done) {
+            return sockets.map(|socket| new_p2p_session(socket, INPUT_DELAY));
+        }
+        clock.advance(FRAME_DURATION);
+        for (handle, socket) in sockets.iter_mut().enumerate() {
+            if done[handle] {
+                // keeps answering the pings of the peer
+                socket.receive_hellos();
+                continue;
+            }
+            let delta = FRAME_DURATION.as_secs_f32();
+            done[handle] = connect_peers(socket, &mut timers[handle], delta, &rules)
+                .expect("handshake failed")
+                .is_some();
+        }
+    }
+    panic!("handshake stuck after {FRAME_BUDGET} frames");
+}
+
+/// Headless app running the match simulation, without window, rendering nor UI.
+fn client(session: P2PSession, script: Script) -> App {
+    let mut app = App::new();
+    app.add_plugins(MinimalPlugins)
+        .insert_resource(test_rules())
+        .insert_resource(MatchSeed::default())
+        .insert_resource(FrameCount::default())
+        .insert_resource(RoundState::NotReady)
+        .insert_resource(RollbackIdProvider::default())
+        .insert_resource(ReplayRecorder::default())
+        .insert_resource(RollbackCounter::default())
+        .insert_resource(script)
+        .insert_resource(session)
+        .add_startup_system(spawn_players)
+        .add_stage_before(
+            CoreStage::Update,
+            FIXED_STEP,
+            FixedStepStage {
+                schedule: rollback_schedule(),
+                snapshots: vec![],
+            },
+        );
+    app
+}
+
+/// Reload, fire at a reloading opponent, then shield against their shot.
+fn duel_script() -> Vec<Vec<Action>> {
+    use Action::*;
+    vec![
+        vec![Reload, Fire, Shield, Shield],
+        vec![Reload, Reload, Fire, Shield],
+    ]
+}
+
+fn assert_duel_outcome(game: &mut Match) {
+    game.run_until_confirmed(end_of_round(2));
+    assert_eq!(game.health(0), 3);
+    assert_eq!(game.health(1), 2);
+    assert_eq!(game.ammo(0), 0);
+    assert_eq!(game.ammo(1), 1);
+}
+
+#[test]
+fn rounds_resolve_the_same_on_both_clients() {
+    let mut game = Match::new(duel_script());
+    game.run_until_confirmed(end_of_round(0));
+    assert_eq!(game.ammo(0), 1);
+    assert_eq!(game.ammo(1), 1);
+    assert_duel_outcome(&mut game);
+}
+
+#[test]
+fn rollbacks_on_a_bad_network_reach_the_same_outcome() {
+    let conditions = NetworkConditions {
+        latency: Duration::from_millis(30),
+        jitter: Duration::from_millis(10),
+        loss: 0.05,
+        reorder: 0.05,
+        seed: Some(1),
+    };
+    let mut game = Match::with_conditions(duel_script(), Some(conditions));
+    assert_duel_outcome(&mut game);
+}
+
+#[test]
+fn match_ends_when_a_player_runs_out_of_health() {
+    use Action::*;
+    let mut game = Match::new(vec![
+        vec![Reload, Fire, Reload, Fire, Reload, Fire],
+        vec![Reload; 6],
+    ]);
+    game.run_until_confirmed(end_of_round(5));
+    assert_eq!(game.health(0), 3);
+    assert_eq!(game.health(1), 0);
+    assert_eq!(
+        game.agreed(|world| *world.get_resource::<RoundState>().unwrap()),
+        RoundState::GameOver
+    );
+}
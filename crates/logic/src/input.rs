@@ -1,16 +1,114 @@
 use crate::logic::RoundState;
+use crate::protocol::PlayerInput;
+use crate::rules::{Action, NUM_PLAYERS};
+use crate::settings::KeyBindings;
 
 use super::logic::ActionFire;
 use super::logic::ActionReload;
 use super::logic::ActionShield;
 use super::logic::Player;
 use bevy::prelude::*;
+use bevy::utils::HashMap;
+use ggrs::{PlayerHandle, SyncTestSession};
 
-pub const INPUT_SIZE: usize = std::mem::size_of::<u8>();
+/// Device a local player plays with.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum InputDevice {
+    Keyboard,
+    Gamepad(Gamepad),
+    /// External bot process, see `bot_seat`.
+    Bot,
+}
+
+impl InputDevice {
+    pub(crate) fn name(&self) -> String {
+        match self {
+            InputDevice::Keyboard => "Keyboard/touch".to_string(),
+            InputDevice::Gamepad(gamepad) => format!("Gamepad {}", gamepad.0 + 1),
+            InputDevice::Bot => "Bot".to_string(),
+        }
+    }
+}
 
-const INPUT_RELOAD: u8 = 1 << 0;
-const INPUT_SHIELD: u8 = 1 << 1;
-const INPUT_FIRE: u8 = 1 << 2;
+/// Connected gamepads, and the device of each player when they share this machine.
+pub(crate) struct InputDevices {
+    /// In connection order.
+    pub(crate) gamepads: Vec<Gamepad>,
+    /// Device of each hot-seat player, by handle. Online, the local player uses every device.
+    pub(crate) seats: [Option<InputDevice>; NUM_PLAYERS],
+    /// Action picked with the on-screen buttons, waiting for the next input frame.
+    /// Pointers belong to whoever plays with the keyboard.
+    pub(crate) pointer_action: Option<Action>,
+    /// Action the bot picked this round, for the seat holding `InputDevice::Bot`.
+    pub(crate) bot_action: Option<Action>,
+}
+
+impl Default for InputDevices {
+    fn default() -> Self {
+        let mut seats = [None; NUM_PLAYERS];
+        seats[0] = Some(InputDevice::Keyboard);
+        Self {
+            gamepads: vec![],
+            seats,
+            pointer_action: None,
+            bot_action: None,
+        }
+    }
+}
+
+impl InputDevices {
+    /// Keyboard then the connected gamepads, the devices a seat can be given.
+    pub(crate) fn available(&self) -> Vec<InputDevice> {
+        std::iter::once(InputDevice::Keyboard)
+            .chain(self.gamepads.iter().map(|g| InputDevice::Gamepad(*g)))
+            .collect()
+    }
+
+    /// Puts `device` in `seat`, the seat previously holding it gets the old device of `seat`.
+    pub(crate) fn assign(&mut self, seat: usize, device: InputDevice) {
+        let previous = self.seats[seat];
+        if let Some(other) = self.seats.iter().position(|d| *d == Some(device)) {
+            self.seats[other] = previous;
+        }
+        self.seats[seat] = Some(device);
+    }
+
+    /// Puts the bot in `seat`, facing a player using the first seated device.
+    pub(crate) fn seat_bot(&mut self, seat: usize) {
+        let human = self
+            .seats
+            .iter()
+            .flatten()
+            .copied()
+            .find(|device| *device != InputDevice::Bot)
+            .unwrap_or(InputDevice::Keyboard);
+        self.seats = [None; NUM_PLAYERS];
+        self.seats[seat] = Some(InputDevice::Bot);
+        self.seats[(seat + 1) % NUM_PLAYERS] = Some(human);
+        self.bot_action = None;
+    }
+
+    pub(crate) fn unseat_bot(&mut self) {
+        for seat in self.seats.iter_mut() {
+            if *seat == Some(InputDevice::Bot) {
+                *seat = None;
+            }
+        }
+        self.bot_action = None;
+    }
+}
+
+const GAMEPAD_RELOAD: GamepadButtonType = GamepadButtonType::West;
+const GAMEPAD_SHIELD: GamepadButtonType = GamepadButtonType::East;
+const GAMEPAD_FIRE: GamepadButtonType = GamepadButtonType::South;
+
+fn gamepad_button(action: Action) -> GamepadButtonType {
+    match action {
+        Action::Reload => GAMEPAD_RELOAD,
+        Action::Shield => GAMEPAD_SHIELD,
+        Action::Fire => GAMEPAD_FIRE,
+    }
+}
 
 pub(crate) fn handle_inputs(
     mut round_state: ResMut<RoundState>,
@@ -26,45 +124,113 @@ pub(crate) fn handle_inputs(
         return;
     }
     for (mut reload, mut shield, mut fire, player) in player_query.iter_mut() {
-        let input = inputs[player.handle].buffer[0];
-
-        if input & INPUT_RELOAD != 0 {
-            reload.is_active = false;
-            shield.is_active = false;
-            fire.is_active = false;
+        let input = match PlayerInput::decode(&inputs[player.handle].buffer) {
+            Ok(input) => input,
+            Err(e) => {
+                warn!("invalid input from {}: {}", player.handle, e);
+                continue;
+            }
+        };
 
-            reload.is_active = true;
-            continue;
+        if let Some(action) = input.action {
+            reload.is_active = action == Action::Reload;
+            shield.is_active = action == Action::Shield;
+            fire.is_active = action == Action::Fire;
         }
-        if input & INPUT_SHIELD != 0 {
-            reload.is_active = false;
-            shield.is_active = false;
-            fire.is_active = false;
+    }
+}
 
-            shield.is_active = true;
-            continue;
+/// Keeps track of gamepads being plugged in and out. A new gamepad takes the first
+/// hot-seat without a device, an unplugged one leaves its seat empty until replaced.
+pub(crate) fn gamepad_connections(
+    mut events: EventReader<GamepadEvent>,
+    mut devices: ResMut<InputDevices>,
+) {
+    for GamepadEvent(gamepad, event) in events.iter() {
+        match event {
+            GamepadEventType::Connected => {
+                info!("gamepad {} connected", gamepad.0);
+                devices.gamepads.push(*gamepad);
+                if let Some(seat) = devices.seats.iter_mut().find(|seat| seat.is_none()) {
+                    *seat = Some(InputDevice::Gamepad(*gamepad));
+                }
+            }
+            GamepadEventType::Disconnected => {
+                info!("gamepad {} disconnected", gamepad.0);
+                devices.gamepads.retain(|g| g != gamepad);
+                for seat in devices.seats.iter_mut() {
+                    if *seat == Some(InputDevice::Gamepad(*gamepad)) {
+                        *seat = None;
+                    }
+                }
+            }
+            _ => {}
         }
-        if input & INPUT_FIRE != 0 {
-            reload.is_active = false;
-            shield.is_active = false;
-            fire.is_active = false;
+    }
+}
 
-            fire.is_active = true;
+pub(crate) fn local_input(
+    In(handle): In<PlayerHandle>,
+    keys: Res<Input<KeyCode>>,
+    buttons: Res<Input<GamepadButton>>,
+    bindings: Res<KeyBindings>,
+    mut devices: ResMut<InputDevices>,
+    hot_seat: Option<Res<SyncTestSession>>,
+    mut targets: Local<HashMap<PlayerHandle, usize>>,
+) -> Vec<u8> {
+    let controls = |device| hot_seat.is_none() || devices.seats.get(handle) == Some(&Some(device));
+    let gamepads: Vec<Gamepad> = devices
+        .gamepads
+        .iter()
+        .copied()
+        .filter(|g| controls(InputDevice::Gamepad(*g)))
+        .collect();
+    let pressed = |action: Action| {
+        (controls(InputDevice::Keyboard) && keys.just_pressed(bindings.key(action)))
+            || gamepads
+                .iter()
+                .any(|g| buttons.just_pressed(GamepadButton(*g, gamepad_button(action))))
+    };
+
+    // the d-pad cycles through the opponents
+    let mut target = *targets.entry(handle).or_insert((handle + 1) % NUM_PLAYERS);
+    for g in &gamepads {
+        let step = if buttons.just_pressed(GamepadButton(*g, GamepadButtonType::DPadRight)) {
+            1
+        } else if buttons.just_pressed(GamepadButton(*g, GamepadButtonType::DPadLeft)) {
+            NUM_PLAYERS - 1
+        } else {
             continue;
+        };
+        target = (target + step) % NUM_PLAYERS;
+        if target == handle {
+            target = (target + step) % NUM_PLAYERS;
         }
     }
-}
+    targets.insert(handle, target);
 
-pub(crate) fn local_input(_: In<ggrs::PlayerHandle>, keys: Res<Input<KeyCode>>) -> Vec<u8> {
-    let mut input = 0u8;
-    if keys.any_just_pressed([KeyCode::R]) {
-        input |= INPUT_RELOAD;
-    }
-    if keys.any_just_pressed([KeyCode::S]) {
-        input |= INPUT_SHIELD;
-    }
-    if keys.any_just_pressed([KeyCode::F]) {
-        input |= INPUT_FIRE
+    let pointer_action = if controls(InputDevice::Keyboard) {
+        devices.pointer_action
+    } else {
+        None
+    };
+    // online, every device drives the local player, but a bot never does
+    let bot_action = if hot_seat.is_some() && controls(InputDevice::Bot) {
+        devices.bot_action
+    } else {
+        None
+    };
+    let input = PlayerInput {
+        action: Action::ALL
+            .into_iter()
+            .find(|action| pressed(*action))
+            .or(pointer_action)
+            .or(bot_action),
+        target: target as u8,
+        ..Default::default()
+    };
+    if pointer_action.is_some() {
+        devices.pointer_action = None;
     }
-    vec![input]
+    input.encode().to_vec()
 }
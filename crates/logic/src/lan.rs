@@ -0,0 +1,150 @@
+use std::collections::HashMap;
+use std::net::{Ipv4Addr, SocketAddr, UdpSocket};
+
+use bevy::prelude::*;
+use bevy::utils::{Duration, Instant};
+use socket2::{Domain, Protocol, Socket, Type};
+
+use crate::lobby::MatchmakingRoom;
+use crate::network::DirectConnect;
+use crate::protocol::PROTOCOL_VERSION;
+use crate::states::GameState;
+
+/// Hosts announce their games to this port of every machine on the network.
+const DISCOVERY_PORT: u16 = 7001;
+const ANNOUNCE_MAGIC: &[u8; 7] = b"CBOYLAN";
+const ANNOUNCE_INTERVAL_SECS: f32 = 1.0;
+/// Games not announced for this long are gone.
+const GAME_EXPIRY: Duration = Duration::from_secs(3);
+
+pub struct LanPlugin;
+
+impl Plugin for LanPlugin {
+    fn build(&self, app: &mut App) {
+        app.insert_resource(LanGames::default());
+        app.add_system_set(SystemSet::on_enter(GameState::Lobby).with_system(start_listening));
+        app.add_system_set(SystemSet::on_update(GameState::Lobby).with_system(listen_lan_games));
+        app.add_system_set(SystemSet::on_exit(GameState::Lobby).with_system(stop_listening));
+        app.add_system_set(
+            SystemSet::on_update(GameState::Matchmaking).with_system(announce_lan_game),
+        );
+    }
+}
+
+pub(crate) struct LanGame {
+    pub(crate) addr: SocketAddr,
+    /// Whether the host plays our version of the game.
+    pub(crate) compatible: bool,
+    last_seen: Instant,
+}
+
+/// Games hosted on the local network, heard of while in the lobby.
+#[derive(Default)]
+pub(crate) struct LanGames {
+    socket: Option<UdpSocket>,
+    games: HashMap<SocketAddr, LanGame>,
+}
+
+impl LanGames {
+    pub(crate) fn iter(&self) -> impl Iterator<Item = &LanGame> {
+        self.games.values()
+    }
+}
+
+/// Several clients may run on the same machine, so they all share the discovery port.
+fn bind_discovery_socket() -> std::io::Result<UdpSocket> {
+    let socket = Socket::new(Domain::IPV4, Type::DGRAM, Some(Protocol::UDP))?;
+    socket.set_reuse_address(true)?;
+    #[cfg(unix)]
+    socket.set_reuse_port(true)?;
+    socket.set_nonblocking(true)?;
+    let addr = SocketAddr::from((Ipv4Addr::UNSPECIFIED, DISCOVERY_PORT));
+    socket.bind(&addr.into())?;
+    Ok(socket.into())
+}
+
+fn start_listening(mut lan: ResMut<LanGames>) {
+    match bind_discovery_socket() {
+        Ok(socket) => lan.socket = Some(socket),
+        Err(e) => warn!("LAN discovery unavailable: {}", e),
+    }
+}
+
+fn stop_listening(mut lan: ResMut<LanGames>) {
+    lan.socket = None;
+    lan.games.clear();
+}
+
+fn listen_lan_games(mut lan: ResMut<LanGames>) {
+    let mut buffer = [0u8; 64];
+    let mut heard = vec![];
+    if let Some(socket) = &lan.socket {
+        while let Ok((len, from)) = socket.recv_from(&mut buffer) {
+            if let Some((version, port)) = parse_announce(&buffer[..len]) {
+                heard.push((SocketAddr::new(from.ip(), port), version));
+            }
+        }
+    }
+    let now = Instant::now();
+    for (addr, version) in heard {
+        lan.games.insert(
+            addr,
+            LanGame {
+                addr,
+                compatible: version == PROTOCOL_VERSION,
+                last_seen: now,
+            },
+        );
+    }
+    lan.games
+        .retain(|_, game| now.duration_since(game.last_seen) < GAME_EXPIRY);
+}
+
+fn parse_announce(packet: &[u8]) -> Option<(u8, u16)> {
+    match packet {
+        [magic @ .., version, port_lo, port_hi] if magic == ANNOUNCE_MAGIC => {
+            Some((*version, u16::from_le_bytes([*port_lo, *port_hi])))
+        }
+        _ => None,
+    }
+}
+
+/// Broadcasts the game we host, so it shows up in the lobby of the other clients.
+fn announce_lan_game(
+    time: Res<Time>,
+    room: Res<MatchmakingRoom>,
+    mut announcer: Local<Option<UdpSocket>>,
+    mut since_announce: Local<Option<f32>>,
+) {
+    let port = match room.direct {
+        Some(DirectConnect::Host(port)) => port,
+        _ => return,
+    };
+    let elapsed = since_announce.map_or(ANNOUNCE_INTERVAL_SECS, |t| t + time.delta_seconds());
+    if elapsed < ANNOUNCE_INTERVAL_SECS {
+        *since_announce = Some(elapsed);
+        return;
+    }
+    *since_announce = Some(0.0);
+
+    if announcer.is_none() {
+        let socket = UdpSocket::bind((Ipv4Addr::UNSPECIFIED, 0)).and_then(|socket| {
+            socket.set_broadcast(true)?;
+            Ok(socket)
+        });
+        match socket {
+            Ok(socket) => *announcer = Some(socket),
+            Err(e) => {
+                warn!("could not announce the game on the LAN: {}", e);
+                return;
+            }
+        }
+    }
+    let mut packet = ANNOUNCE_MAGIC.to_vec();
+    packet.push(PROTOCOL_VERSION);
+    packet.extend_from_slice(&port.to_le_bytes());
+    let broadcast = SocketAddr::from((Ipv4Addr::BROADCAST, DISCOVERY_PORT));
+    if let Err(e) = announcer.as_ref().unwrap().send_to(&packet, broadcast) {
+        warn!("could not announce the game on the LAN: {}", e);
+    }
+}
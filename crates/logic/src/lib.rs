@@ -1,42 +1,207 @@
+pub mod ai;
+pub mod bot;
+#[cfg(all(feature = "app", not(target_arch = "wasm32")))]
+mod bot_seat;
+mod desync;
+#[cfg(feature = "app")]
 mod display;
+pub mod env;
+#[cfg(all(test, feature = "app"))]
+mod harness;
+#[cfg(feature = "app")]
 mod input;
+#[cfg(all(feature = "app", not(target_arch = "wasm32")))]
+mod lan;
+#[cfg(feature = "app")]
+mod lobby;
 mod logic;
+#[cfg(not(target_arch = "wasm32"))]
+pub mod netplay;
+#[cfg(feature = "app")]
+mod netsim;
+#[cfg(feature = "app")]
+mod netstats;
 mod network;
+pub mod notation;
+mod protocol;
+pub mod puzzle;
+#[cfg(feature = "app")]
+mod puzzle_mode;
+mod replay;
+mod rules;
+#[cfg(feature = "app")]
+mod settings;
+pub mod sim;
+mod socket;
+pub mod solver;
+#[cfg(feature = "app")]
 mod states;
+#[cfg(feature = "app")]
+mod storage;
 
+#[cfg(feature = "app")]
 use bevy::prelude::*;
+#[cfg(feature = "app")]
+use bevy::reflect::GetTypeRegistration;
+#[cfg(feature = "app")]
 use bevy_ggrs::*;
+#[cfg(feature = "app")]
 use display::*;
+#[cfg(feature = "app")]
+use lobby::*;
+#[cfg(feature = "app")]
 use logic::*;
+#[cfg(feature = "app")]
+use netstats::*;
+#[cfg(feature = "app")]
 use network::*;
+#[cfg(feature = "app")]
+use replay::*;
+#[cfg(feature = "app")]
+use settings::*;
+#[cfg(feature = "app")]
 use states::*;
+#[cfg(feature = "app")]
 use wasm_bindgen::prelude::wasm_bindgen;
 
+/// Systems advancing the match by one frame, run by GGRS (with rollbacks) or by the replay stage.
+#[cfg(feature = "app")]
+fn rollback_schedule() -> Schedule {
+    Schedule::default().with_stage(
+        "ROLLBACK_STAGE",
+        SystemStage::single_threaded()
+            .with_system(replay::record_inputs.label("record_inputs"))
+            .with_system(netstats::count_rollbacks.label("count_rollbacks"))
+            .with_system(logic::update_round.label("update_round"))
+            .with_system(
+                input::handle_inputs
+                    .label("handle_inputs")
+                    .after("update_round"),
+            )
+            .with_system(
+                logic::compute_end_round
+                    .label("compute_end_round")
+                    .after("handle_inputs"),
+            )
+            .with_system(
+                logic::react_end_round
+                    .label("react_end_round")
+                    .after("compute_end_round"),
+            )
+            .with_system(
+                desync::record_state
+                    .label("record_state")
+                    .after("react_end_round"),
+            )
+            .with_system(
+                logic::increase_frame_count
+                    .after("record_inputs")
+                    .after("count_rollbacks")
+                    .after("record_state"),
+            ),
+    )
+}
+
+#[cfg(feature = "app")]
+pub use lobby::LaunchOptions;
+pub use logic::MatchRules;
+#[cfg(feature = "app")]
+pub use netsim::NetworkConditions;
+pub use network::DirectConnect;
+pub use protocol::PlayerInput;
+pub use replay::{Replay, ReplayPlayer, ReplayRound, REPLAY_VERSION};
+pub use rules::{resolve_round, Action, PlayerStats, NUM_PLAYERS};
+
+#[cfg(feature = "app")]
 #[wasm_bindgen]
 pub fn run() {
-    let mut app = App::new()
-        .insert_resource(ClearColor(Color::rgb(0.53, 0.53, 0.53)))
-        .insert_resource(logic::RoundState::NotReady)
+    run_with(LaunchOptions::default());
+}
+
+/// Something the types GGRS saves and loads are registered with.
+#[cfg(feature = "app")]
+trait RollbackTypes {
+    /// Registers `T`, saved both as a component of the rollback entities and as a resource.
+    fn register<T: GetTypeRegistration + Reflect + Default + Component>(&mut self);
+}
+
+#[cfg(feature = "app")]
+impl RollbackTypes for App {
+    fn register<T: GetTypeRegistration + Reflect + Default + Component>(&mut self) {
+        self.register_rollback_type::<T>();
+    }
+}
+
+/// The state rolled back, shared by `add_rollback` and the snapshots of the test harness.
+#[cfg(feature = "app")]
+fn register_rollback_types(types: &mut impl RollbackTypes) {
+    types.register::<logic::ActionShield>();
+    types.register::<logic::ActionReload>();
+    types.register::<logic::ActionFire>();
+    types.register::<logic::Health>();
+    types.register::<logic::Ammunition>();
+    types.register::<logic::FrameCount>();
+    types.register::<logic::RoundState>();
+}
+
+/// Registers the match simulation with GGRS: the rollback schedule and the state it rolls back.
+#[cfg(feature = "app")]
+fn add_rollback(app: &mut App) -> &mut App {
+    app.with_rollback_schedule(rollback_schedule())
+        .insert_resource(logic::RoundState::NotReady);
+    register_rollback_types(app);
+    app
+}
+
+#[cfg(feature = "app")]
+pub fn run_with(options: LaunchOptions) {
+    let mut app = App::new();
+    app.insert_resource(ClearColor(Color::rgb(0.53, 0.53, 0.53)))
+        .insert_resource(options)
+        .insert_resource(logic::MatchRules::default())
+        .insert_resource(logic::MatchSeed::default())
+        .insert_resource(ReplayRecorder::default())
+        .insert_resource(input::InputDevices::default())
         .insert_resource(bevy::ecs::schedule::ReportExecutionOrderAmbiguities)
         .add_plugins(DefaultPlugins)
         .add_plugin(GGRSPlugin)
         .add_plugin(DisplayPlugin)
+        .add_plugin(LobbyPlugin)
+        .add_plugin(SettingsPlugin)
+        .add_plugin(NetStatsPlugin)
+        .add_plugin(puzzle_mode::PuzzleModePlugin)
+        .add_stage_before(
+            CoreStage::Update,
+            REPLAY_UPDATE,
+            ReplayStage::new(rollback_schedule()),
+        )
+        .add_startup_system(setup)
+        .add_system(input::gamepad_connections)
         .add_system_set(
-            SystemSet::on_enter(GameState::Matchmaking)
-                .with_system(start_matchbox_socket)
-                .with_system(setup),
+            SystemSet::on_enter(GameState::Matchmaking).with_system(start_matchbox_socket),
         )
         .add_system_set(SystemSet::on_update(GameState::Matchmaking).with_system(wait_for_players))
         .add_system_set(
             SystemSet::on_enter(GameState::InGame)
                 .with_system(spawn_players)
-                .with_system(spawn_display_static),
+                .with_system(spawn_display_static)
+                .with_system(start_recording),
         )
         .add_system_set(
             SystemSet::on_update(GameState::InGame)
-                .with_system(logic::update_round.exclusive_system())
-                .with_system(logic::react_end_round.exclusive_system())
-                .with_system(logic::compute_end_round),
+                .with_system(save_replay)
+                .with_system(replay_controls)
+                .with_system(desync::check_desync)
+                .with_system(desync::desync_display)
+                .with_system(watch_connection),
+        )
+        .add_system_set(
+            SystemSet::on_exit(GameState::InGame)
+                .with_system(despawn_match)
+                .with_system(end_session)
+                .with_system(stop_playback)
+                .with_system(desync::end_desync_check)
+                .with_system(end_connection_watch),
         )
         /*    .add_startup_system(network::start_matchbox_socket)
             .add_startup_system(logic::setup)
@@ -46,16 +211,8 @@ pub fn run() {
             .add_system(logic::react_end_round.exclusive_system())
             .add_system(logic::compute_end_round)
         */
-        .with_input_system(input::local_input)
-        .with_rollback_schedule(Schedule::default().with_stage(
-            "ROLLBACK_STAGE",
-            SystemStage::single_threaded().with_system(input::handle_inputs),
-        ))
-        .register_rollback_type::<logic::ActionShield>()
-        .register_rollback_type::<logic::ActionReload>()
-        .register_rollback_type::<logic::ActionFire>()
-        .register_rollback_type::<logic::Health>()
-        .register_rollback_type::<logic::Ammunition>()
-        // TODO: add RoundState to rollback types, but it's a State so be careful with that
-        .run();
+        .with_input_system(input::local_input);
+    #[cfg(not(target_arch = "wasm32"))]
+    app.add_plugin(bot_seat::BotSeatPlugin);
+    add_rollback(&mut app).run();
 }
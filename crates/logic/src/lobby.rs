@@ -0,0 +1,578 @@
+use std::sync::{Arc, Mutex};
+
+use bevy::prelude::*;
+use bevy_egui::{egui, EguiContext};
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+
+use crate::input::InputDevices;
+use crate::logic::MatchRules;
+use crate::netsim::NetworkConditions;
+use crate::network::{start_local_session, DirectConnect, DIRECT_ROOM};
+use crate::puzzle::Puzzle;
+use crate::puzzle_mode::start_puzzle;
+use crate::replay::{load_last_replay, start_playback};
+use crate::settings::SettingsUi;
+use crate::states::GameState;
+
+/// Rooms endpoint of the `web` crate.
+#[cfg(target_arch = "wasm32")]
+const LOBBY_URL: &str = "rooms";
+#[cfg(not(target_arch = "wasm32"))]
+const LOBBY_URL: &str = "http://localhost:8000/rooms";
+
+/// Matchbox room pairing whoever comes next, used by "Quick match".
+const QUICK_MATCH_ROOM: &str = "next_2";
+
+const ROOM_CODE_LEN: usize = 5;
+/// No 0/O or 1/I, so codes can be read out loud.
+const ROOM_CODE_CHARS: &[u8] = b"ABCDEFGHJKLMNPQRSTUVWXYZ23456789";
+
+#[cfg(not(target_arch = "wasm32"))]
+const DEFAULT_DIRECT_PORT: u16 = 7000;
+
+/// Published rooms expire server side, so the host refreshes them while waiting.
+const PUBLISH_INTERVAL_SECS: f32 = 10.0;
+
+pub struct LobbyPlugin;
+
+impl Plugin for LobbyPlugin {
+    fn build(&self, app: &mut App) {
+        app.insert_resource(LobbyUi::default());
+        #[cfg(not(target_arch = "wasm32"))]
+        app.add_plugin(crate::lan::LanPlugin);
+        app.add_system_set(
+            SystemSet::on_enter(GameState::Lobby)
+                .with_system(fetch_rooms)
+                .with_system(join_from_launch_options),
+        );
+        app.add_system_set(SystemSet::on_update(GameState::Lobby).with_system(lobby_ui));
+        app.add_system_set(
+            SystemSet::on_update(GameState::Matchmaking)
+                .with_system(matchmaking_ui)
+                .with_system(publish_room),
+        );
+        app.add_system_set(SystemSet::on_exit(GameState::Matchmaking).with_system(unpublish_room));
+    }
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+pub struct RoomInfo {
+    pub code: String,
+}
+
+/// The matchbox room to connect to, chosen in the lobby.
+pub(crate) struct MatchmakingRoom {
+    pub(crate) code: String,
+    /// Whether this client created the room and lists it on the lobby server.
+    pub(crate) published: bool,
+    /// Set to skip matchbox and connect over UDP.
+    pub(crate) direct: Option<DirectConnect>,
+    /// Seconds since the room was last published, `None` until it first is.
+    since_publish: Option<f32>,
+}
+
+impl MatchmakingRoom {
+    fn matchbox(code: String, published: bool) -> Self {
+        Self {
+            code,
+            published,
+            direct: None,
+            since_publish: None,
+        }
+    }
+
+    fn direct(direct: DirectConnect) -> Self {
+        Self {
+            code: DIRECT_ROOM.to_string(),
+            published: false,
+            direct: Some(direct),
+            since_publish: None,
+        }
+    }
+}
+
+/// Options given on launch, e.g. from the command line of the native client.
+#[derive(Default)]
+pub struct LaunchOptions {
+    /// Connects right away instead of showing the lobby.
+    pub direct: Option<DirectConnect>,
+    /// Simulates a bad network in online matches, for testing.
+    pub network_conditions: Option<NetworkConditions>,
+}
+
+enum RoomList {
+    Fetching,
+    Fetched(Vec<RoomInfo>),
+    Failed(String),
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+type BotStart = std::sync::mpsc::Receiver<Result<crate::bot::ProcessBot, String>>;
+
+pub(crate) struct LobbyUi {
+    join_code: String,
+    #[cfg(not(target_arch = "wasm32"))]
+    host_port: String,
+    #[cfg(not(target_arch = "wasm32"))]
+    connect_addr: String,
+    /// Command running the bot to play against.
+    #[cfg(not(target_arch = "wasm32"))]
+    bot_command: String,
+    #[cfg(not(target_arch = "wasm32"))]
+    bot_error: Option<String>,
+    /// The bot being started, the match begins once it greets us.
+    #[cfg(not(target_arch = "wasm32"))]
+    bot_starting: Option<Mutex<BotStart>>,
+    /// Why the last matchmaking attempt was aborted.
+    pub(crate) connection_error: Option<String>,
+    replay_error: Option<String>,
+    puzzles: Vec<Puzzle>,
+    /// Puzzle pasted in notation.
+    puzzle_text: String,
+    puzzle_error: Option<String>,
+    /// Filled in by the HTTP callback, which may run on another thread.
+    rooms: Arc<Mutex<RoomList>>,
+}
+
+impl Default for LobbyUi {
+    fn default() -> Self {
+        Self {
+            join_code: String::new(),
+            #[cfg(not(target_arch = "wasm32"))]
+            host_port: DEFAULT_DIRECT_PORT.to_string(),
+            #[cfg(not(target_arch = "wasm32"))]
+            connect_addr: format!("127.0.0.1:{DEFAULT_DIRECT_PORT}"),
+            #[cfg(not(target_arch = "wasm32"))]
+            bot_command: String::new(),
+            #[cfg(not(target_arch = "wasm32"))]
+            bot_error: None,
+            #[cfg(not(target_arch = "wasm32"))]
+            bot_starting: None,
+            connection_error: None,
+            replay_error: None,
+            puzzles: Puzzle::builtin(),
+            puzzle_text: String::new(),
+            puzzle_error: None,
+            rooms: Arc::new(Mutex::new(RoomList::Fetching)),
+        }
+    }
+}
+
+fn generate_room_code() -> String {
+    let mut rng = rand::thread_rng();
+    (0..ROOM_CODE_LEN)
+        .map(|_| ROOM_CODE_CHARS[rng.gen_range(0..ROOM_CODE_CHARS.len())] as char)
+        .collect()
+}
+
+/// Whether `code` could have come from `generate_room_code`, anything else is not sent
+/// to the matchbox server.
+fn is_room_code(code: &str) -> bool {
+    code.len() == ROOM_CODE_LEN && code.bytes().all(|c| ROOM_CODE_CHARS.contains(&c))
+}
+
+fn join_from_launch_options(
+    mut commands: Commands,
+    mut options: ResMut<LaunchOptions>,
+    mut state: ResMut<State<GameState>>,
+) {
+    if let Some(direct) = options.direct.take() {
+        commands.insert_resource(MatchmakingRoom::direct(direct));
+        state.set(GameState::Matchmaking).unwrap();
+    }
+}
+
+fn fetch_rooms(lobby: Res<LobbyUi>) {
+    request_rooms(&lobby.rooms);
+}
+
+fn request_rooms(rooms: &Arc<Mutex<RoomList>>) {
+    *rooms.lock().unwrap() = RoomList::Fetching;
+    let rooms = rooms.clone();
+    ehttp::fetch(ehttp::Request::get(LOBBY_URL), move |result| {
+        let list = match result {
+            Ok(response) if response.ok => match serde_json::from_slice(&response.bytes) {
+                Ok(list) => RoomList::Fetched(list),
+                Err(e) => RoomList::Failed(e.to_string()),
+            },
+            Ok(response) => RoomList::Failed(response.status_text),
+            Err(e) => RoomList::Failed(e),
+        };
+        *rooms.lock().unwrap() = list;
+    });
+}
+
+fn lobby_ui(
+    mut commands: Commands,
+    egui_context: Res<EguiContext>,
+    mut lobby: ResMut<LobbyUi>,
+    mut settings_ui: ResMut<SettingsUi>,
+    mut devices: ResMut<InputDevices>,
+    #[cfg(not(target_arch = "wasm32"))] lan: Res<crate::lan::LanGames>,
+    mut state: ResMut<State<GameState>>,
+) {
+    if settings_ui.open {
+        return;
+    }
+    let mut room = None;
+    let mut refresh = false;
+    let mut watch_replay = false;
+    let mut hot_seat = false;
+    let mut puzzle = None;
+    #[cfg(not(target_arch = "wasm32"))]
+    let mut bot_match = false;
+    egui::Window::new("Lobby")
+        .anchor(egui::Align2::CENTER_CENTER, [0.0, 0.0])
+        .collapsible(false)
+        .resizable(false)
+        .show(egui_context.ctx(), |ui| {
+            if let Some(e) = &lobby.connection_error {
+                ui.colored_label(egui::Color32::RED, e);
+                ui.separator();
+            }
+            if ui.button("Quick match").clicked() {
+                room = Some(MatchmakingRoom::matchbox(
+                    QUICK_MATCH_ROOM.to_string(),
+                    false,
+                ));
+            }
+            if ui.button("Create room").clicked() {
+                room = Some(MatchmakingRoom::matchbox(generate_room_code(), true));
+            }
+            ui.horizontal(|ui| {
+                ui.text_edit_singleline(&mut lobby.join_code);
+                let code = lobby.join_code.trim().to_uppercase();
+                if ui
+                    .add_enabled(is_room_code(&code), egui::Button::new("Join"))
+                    .clicked()
+                {
+                    room = Some(MatchmakingRoom::matchbox(code, false));
+                }
+            });
+
+            ui.separator();
+            ui.horizontal(|ui| {
+                ui.label("Open rooms");
+                refresh = ui.button("Refresh").clicked();
+            });
+            match &*lobby.rooms.lock().unwrap() {
+                RoomList::Fetching => {
+                    ui.label("Loading...");
+                }
+                RoomList::Failed(e) => {
+                    ui.label(format!("Room list unavailable: {e}"));
+                }
+                RoomList::Fetched(rooms) if rooms.is_empty() => {
+                    ui.label("No open rooms");
+                }
+                RoomList::Fetched(rooms) => {
+                    for info in rooms {
+                        ui.horizontal(|ui| {
+                            ui.label(&info.code);
+                            if ui.button("Join").clicked() {
+                                room = Some(MatchmakingRoom::matchbox(info.code.clone(), false));
+                            }
+                        });
+                    }
+                }
+            }
+
+            #[cfg(not(target_arch = "wasm32"))]
+            {
+                ui.separator();
+                if let Some(direct) = direct_connect_ui(ui, &mut lobby, &lan) {
+                    room = Some(MatchmakingRoom::direct(direct));
+                }
+                ui.separator();
+                bot_match = bot_ui(ui, &mut lobby);
+            }
+
+            ui.separator();
+            ui.label("Hot-seat");
+            let available = devices.available();
+            for seat in 0..devices.seats.len() {
+                let selected = devices.seats[seat].map_or("None".to_string(), |d| d.name());
+                egui::ComboBox::from_label(format!("Player {}", seat + 1))
+                    .selected_text(selected)
+                    .show_ui(ui, |ui| {
+                        for device in &available {
+                            let is_selected = devices.seats[seat] == Some(*device);
+                            if ui.selectable_label(is_selected, device.name()).clicked() {
+                                devices.assign(seat, *device);
+                            }
+                        }
+                    });
+            }
+            let seated = devices.seats.iter().all(|seat| seat.is_some());
+            hot_seat = ui
+                .add_enabled(seated, egui::Button::new("Start hot-seat"))
+                .clicked();
+            if !seated {
+                ui.label("Plug in a gamepad for each player without a device");
+            }
+
+            ui.separator();
+            puzzle = puzzle_ui(ui, &mut lobby);
+
+            ui.separator();
+            watch_replay = ui.button("Watch last replay").clicked();
+            if let Some(e) = &lobby.replay_error {
+                ui.label(e);
+            }
+
+            ui.separator();
+            if ui.button("Settings").clicked() {
+                settings_ui.open = true;
+            }
+        });
+
+    #[cfg(not(target_arch = "wasm32"))]
+    if bot_match {
+        match crate::bot::split_command(&lobby.bot_command) {
+            Ok((program, args)) => {
+                let starting = crate::bot::ProcessBot::spawn_in_background(program, args);
+                lobby.bot_starting = Some(Mutex::new(starting));
+                lobby.bot_error = None;
+            }
+            Err(e) => lobby.bot_error = Some(format!("Could not start the bot: {e}")),
+        }
+        return;
+    }
+    #[cfg(not(target_arch = "wasm32"))]
+    if let Some(starting) = &lobby.bot_starting {
+        let started = match starting.lock().unwrap().try_recv() {
+            Ok(started) => started,
+            Err(std::sync::mpsc::TryRecvError::Empty) => return,
+            Err(std::sync::mpsc::TryRecvError::Disconnected) => {
+                Err("the bot thread stopped".to_string())
+            }
+        };
+        lobby.bot_starting = None;
+        match started
+            .and_then(|bot| crate::bot_seat::start_bot_match(&mut commands, &mut devices, bot))
+        {
+            Ok(()) => state.set(GameState::InGame).unwrap(),
+            Err(e) => lobby.bot_error = Some(format!("Could not start the bot: {e}")),
+        }
+        return;
+    }
+
+    if let Some(puzzle) = puzzle {
+        match start_puzzle(&mut commands, &mut devices, puzzle) {
+            Ok(()) => {
+                lobby.puzzle_error = None;
+                state.set(GameState::InGame).unwrap();
+            }
+            Err(e) => lobby.puzzle_error = Some(format!("Could not start the puzzle: {e}")),
+        }
+        return;
+    }
+
+    if watch_replay {
+        match load_last_replay() {
+            Ok(replay) => {
+                lobby.replay_error = None;
+                start_playback(&mut commands, replay);
+                state.set(GameState::InGame).unwrap();
+            }
+            Err(e) => lobby.replay_error = Some(format!("Could not load replay: {e}")),
+        }
+    } else if hot_seat {
+        lobby.replay_error = None;
+        start_local_session(&mut commands, MatchRules::default(), rand::random());
+        state.set(GameState::InGame).unwrap();
+    } else if refresh {
+        request_rooms(&lobby.rooms);
+    } else if let Some(room) = room {
+        info!("joining room {} {:?}", room.code, room.direct);
+        lobby.connection_error = None;
+        commands.insert_resource(room);
+        state.set(GameState::Matchmaking).unwrap();
+    }
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+fn direct_connect_ui(
+    ui: &mut egui::Ui,
+    lobby: &mut LobbyUi,
+    lan: &crate::lan::LanGames,
+) -> Option<DirectConnect> {
+    let mut direct = None;
+    ui.label("LAN games");
+    let mut games: Vec<_> = lan.iter().collect();
+    if games.is_empty() {
+        ui.label("No game hosted on the local network");
+    }
+    games.sort_by_key(|game| game.addr);
+    for game in games {
+        ui.horizontal(|ui| {
+            ui.label(game.addr.to_string());
+            if !game.compatible {
+                ui.label("(other version)");
+            } else if ui.button("Join").clicked() {
+                direct = Some(DirectConnect::Connect(game.addr));
+            }
+        });
+    }
+
+    ui.label("Direct connection");
+    ui.horizontal(|ui| {
+        ui.label("Port");
+        ui.text_edit_singleline(&mut lobby.host_port);
+        let port = lobby.host_port.trim().parse().ok();
+        if ui
+            .add_enabled(port.is_some(), egui::Button::new("Host"))
+            .clicked()
+        {
+            direct = port.map(DirectConnect::Host);
+        }
+    });
+    ui.horizontal(|ui| {
+        ui.label("Address");
+        ui.text_edit_singleline(&mut lobby.connect_addr);
+        let addr = lobby.connect_addr.trim().parse().ok();
+        if ui
+            .add_enabled(addr.is_some(), egui::Button::new("Connect"))
+            .clicked()
+        {
+            direct = addr.map(DirectConnect::Connect);
+        }
+    });
+    direct
+}
+
+/// Returns whether to start a match against the bot command typed in.
+#[cfg(not(target_arch = "wasm32"))]
+fn bot_ui(ui: &mut egui::Ui, lobby: &mut LobbyUi) -> bool {
+    ui.label("Against a bot");
+    let mut play = false;
+    ui.horizontal(|ui| {
+        ui.label("Command");
+        ui.text_edit_singleline(&mut lobby.bot_command);
+        play = ui
+            .add_enabled(
+                !lobby.bot_command.trim().is_empty() && lobby.bot_starting.is_none(),
+                egui::Button::new("Play"),
+            )
+            .clicked();
+    });
+    if lobby.bot_starting.is_some() {
+        ui.label("Starting the bot...");
+    }
+    if let Some(e) = &lobby.bot_error {
+        ui.colored_label(egui::Color32::RED, e);
+    }
+    play
+}
+
+/// Returns the puzzle to play, picked from the built-in ones or pasted in.
+fn puzzle_ui(ui: &mut egui::Ui, lobby: &mut LobbyUi) -> Option<Puzzle> {
+    let mut picked = None;
+    ui.label("Puzzles");
+    for puzzle in &lobby.puzzles {
+        ui.horizontal(|ui| {
+            ui.label(format!("{} ({} rounds)", puzzle.name, puzzle.rounds));
+            if ui.button("Play").clicked() {
+                picked = Some(puzzle.clone());
+            }
+        });
+    }
+    ui.collapsing("Paste a puzzle", |ui| {
+        ui.text_edit_multiline(&mut lobby.puzzle_text);
+        if ui
+            .add_enabled(
+                !lobby.puzzle_text.trim().is_empty(),
+                egui::Button::new("Play"),
+            )
+            .clicked()
+        {
+            match Puzzle::parse(&lobby.puzzle_text) {
+                Ok(puzzle) => picked = Some(puzzle),
+                Err(e) => lobby.puzzle_error = Some(format!("Invalid puzzle: {e}")),
+            }
+        }
+    });
+    if let Some(e) = &lobby.puzzle_error {
+        ui.colored_label(egui::Color32::RED, e);
+    }
+    picked
+}
+
+fn matchmaking_ui(egui_context: Res<EguiContext>, room: Res<MatchmakingRoom>) {
+    egui::Window::new("Matchmaking")
+        .anchor(egui::Align2::CENTER_CENTER, [0.0, 0.0])
+        .collapsible(false)
+        .resizable(false)
+        .show(egui_context.ctx(), |ui| {
+            match room.direct {
+                Some(DirectConnect::Host(port)) => {
+                    ui.heading(format!("Hosting on port {port}"));
+                }
+                Some(DirectConnect::Connect(addr)) => {
+                    ui.heading(format!("Connecting to {addr}"));
+                }
+                None if room.code != QUICK_MATCH_ROOM => {
+                    ui.heading(format!("Room {}", room.code));
+                }
+                None => {}
+            }
+            ui.label("Waiting for an opponent...");
+        });
+}
+
+fn publish_room(time: Res<Time>, mut room: ResMut<MatchmakingRoom>) {
+    if !room.published {
+        return;
+    }
+    let elapsed = room
+        .since_publish
+        .map_or(PUBLISH_INTERVAL_SECS, |t| t + time.delta_seconds());
+    if elapsed < PUBLISH_INTERVAL_SECS {
+        room.since_publish = Some(elapsed);
+        return;
+    }
+    room.since_publish = Some(0.0);
+
+    let body = serde_json::to_vec(&RoomInfo {
+        code: room.code.clone(),
+    })
+    .unwrap();
+    let mut request = ehttp::Request::post(LOBBY_URL, body);
+    request
+        .headers
+        .insert("Content-Type".to_string(), "application/json".to_string());
+    ehttp::fetch(request, |result| {
+        if let Err(e) = result {
+            warn!("could not publish room: {}", e);
+        }
+    });
+}
+
+fn unpublish_room(room: Res<MatchmakingRoom>) {
+    if !room.published {
+        return;
+    }
+    let request = ehttp::Request {
+        method: "DELETE".to_string(),
+        url: format!("{}/{}", LOBBY_URL, room.code),
+        body: vec![],
+        headers: ehttp::headers(&[("Accept", "*/*")]),
+    };
+    ehttp::fetch(request, |_| {});
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn only_generated_room_codes_are_accepted() {
+        assert!(is_room_code(&generate_room_code()));
+        assert!(!is_room_code("ABCD"));
+        assert!(!is_room_code("ABCDEF"));
+        assert!(!is_room_code("ABCD0"));
+        assert!(!is_room_code("AB/CD"));
+        assert!(!is_room_code("ÉBCDE"));
+    }
+}
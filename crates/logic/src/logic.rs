@@ -1,191 +1,306 @@
+#[cfg(feature = "app")]
 use bevy::prelude::*;
-use bevy_ggrs::Rollback;
-use bevy_ggrs::RollbackIdProvider;
-use ggrs::P2PSession;
+#[cfg(feature = "app")]
+use bevy_ggrs::{Rollback, RollbackIdProvider};
+use serde::{Deserialize, Serialize};
+use tracing::info;
 
 use ggrs::Frame;
 
+#[cfg(feature = "app")]
+use crate::rules::{resolve_round, Action};
+use crate::rules::{PlayerStats, NUM_PLAYERS};
+
+#[cfg(feature = "app")]
 #[derive(Component)]
 pub(crate) struct Player {
     pub(crate) handle: usize,
 }
 
+#[cfg(feature = "app")]
 #[derive(Component, Default, Reflect)]
 pub(crate) struct ActionReload {
     pub(crate) is_active: bool,
 }
 
+#[cfg(feature = "app")]
 #[derive(Component, Default, Reflect)]
 pub(crate) struct ActionShield {
     pub(crate) is_active: bool,
 }
 
+#[cfg(feature = "app")]
 #[derive(Component, Default, Reflect)]
 pub(crate) struct ActionFire {
     pub(crate) is_active: bool,
 }
 
+#[cfg(feature = "app")]
 #[derive(Component, Default, Reflect)]
 pub(crate) struct Health {
     pub(crate) amount: i32,
 }
 
+#[cfg(feature = "app")]
 #[derive(Component, Default, Reflect)]
 pub(crate) struct Ammunition {
     pub(crate) amount: i32,
 }
 
-#[derive(Clone, Copy)]
+#[derive(Clone, Copy, Debug, Hash, PartialEq, Eq)]
 pub struct RoundWait {
     pub from: Frame,
     pub until: Frame,
 }
 
-#[derive(Clone, Copy)]
+#[derive(Clone, Copy, Debug, Default, Hash, PartialEq, Eq)]
+#[cfg_attr(
+    feature = "app",
+    derive(Component, Reflect),
+    reflect_value(Hash, PartialEq)
+)]
 pub(crate) enum RoundState {
+    #[default]
     NotReady,
     WaitUntil(RoundWait),
     DisplayUntil(RoundWait),
     Compute,
     NextRound,
+    GameOver,
+}
+
+/// Number of frames simulated since the match started, saved and loaded with rollbacks
+/// so the round timers stay in sync with the inputs.
+#[cfg(feature = "app")]
+#[derive(Component, Default, Reflect, Clone, Copy)]
+pub(crate) struct FrameCount {
+    pub(crate) frame: Frame,
+}
+
+/// Match settings, identical for both players.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct MatchRules {
+    pub starting_health: i32,
+    pub starting_ammo: i32,
+    /// Frames players have to pick an action each round.
+    pub decision_frames: Frame,
+    /// Frames the chosen actions are shown before the round is resolved.
+    pub display_frames: Frame,
+    /// Bounds of the input delay picked from the measured round-trip time, in frames.
+    pub min_input_delay: u32,
+    pub max_input_delay: u32,
+}
+
+impl Default for MatchRules {
+    fn default() -> Self {
+        Self {
+            starting_health: 3,
+            starting_ammo: 0,
+            decision_frames: 60 * 2,
+            display_frames: 60,
+            min_input_delay: 2,
+            max_input_delay: 8,
+        }
+    }
+}
+
+/// Seed both peers agree on for the current match.
+#[cfg(feature = "app")]
+#[derive(Clone, Copy, Default)]
+pub struct MatchSeed(pub u64);
+
+/// Stats the players spawn with when a match does not start from the rules' ones, e.g. in
+/// a puzzle.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) struct MatchStart(pub(crate) [PlayerStats; NUM_PLAYERS]);
+
+impl MatchStart {
+    pub(crate) fn from_rules(rules: &MatchRules) -> Self {
+        Self(
+            [PlayerStats {
+                health: rules.starting_health,
+                ammo: rules.starting_ammo,
+            }; NUM_PLAYERS],
+        )
+    }
 }
 
+#[cfg(feature = "app")]
 pub(crate) struct ComputeRoundResult;
 
+#[cfg(feature = "app")]
 pub(crate) fn setup(mut commands: Commands) {
     let mut camera_bundle = OrthographicCameraBundle::new_2d();
     camera_bundle.orthographic_projection.scale = 1. / 50.;
     commands.spawn_bundle(camera_bundle);
 }
 
-pub(crate) fn spawn_players(mut commands: Commands, mut rip: ResMut<RollbackIdProvider>) {
-    // Player 1
-    commands
-        .spawn()
-        .insert(Player { handle: 0 })
-        .insert(Rollback::new(rip.next_id()))
-        .insert(ActionFire::default())
-        .insert(ActionReload { is_active: true })
-        .insert(ActionShield::default())
-        .insert(Health { amount: 3 })
-        .insert(Ammunition::default());
-
-    // Player 2
-    commands
-        .spawn()
-        .insert(Player { handle: 1 })
-        .insert(Rollback::new(rip.next_id()))
-        .insert(ActionFire::default())
-        .insert(ActionReload { is_active: true })
-        .insert(ActionShield::default())
-        .insert(Health { amount: 3 })
-        .insert(Ammunition::default());
-}
-
-pub(crate) fn update_round(world: &mut World) {
-    if world.get_resource::<P2PSession>().is_some() {
-        let frame = world.get_resource::<P2PSession>().unwrap().current_frame();
-        dbg!(frame);
-        if let Some(mut round_state) = world.get_resource_mut::<RoundState>() {
-            if matches!(*round_state, RoundState::NotReady) {
-                *round_state = RoundState::WaitUntil(RoundWait {
+#[cfg(feature = "app")]
+pub(crate) fn spawn_players(
+    mut commands: Commands,
+    mut rip: ResMut<RollbackIdProvider>,
+    rules: Res<MatchRules>,
+    start: Option<Res<MatchStart>>,
+) {
+    let start = start.map_or_else(|| MatchStart::from_rules(&rules), |start| *start);
+    for (handle, stats) in start.0.iter().enumerate() {
+        commands
+            .spawn()
+            .insert(Player { handle })
+            .insert(Rollback::new(rip.next_id()))
+            .insert(ActionFire::default())
+            .insert(ActionReload { is_active: true })
+            .insert(ActionShield::default())
+            .insert(Health {
+                amount: stats.health,
+            })
+            .insert(Ammunition { amount: stats.ammo });
+    }
+}
+
+/// Puts every player back in the state `spawn_players` created them with.
+#[cfg(feature = "app")]
+pub(crate) fn reset_players(world: &mut World, start: &MatchStart) {
+    let mut query = world.query::<(
+        &Player,
+        &mut ActionFire,
+        &mut ActionReload,
+        &mut ActionShield,
+        &mut Health,
+        &mut Ammunition,
+    )>();
+    for (player, mut fire, mut reload, mut shield, mut health, mut ammo) in query.iter_mut(world) {
+        fire.is_active = false;
+        reload.is_active = true;
+        shield.is_active = false;
+        health.amount = start.0[player.handle].health;
+        ammo.amount = start.0[player.handle].ammo;
+    }
+}
+
+#[cfg(feature = "app")]
+pub(crate) fn despawn_match(
+    mut commands: Commands,
+    mut round_state: ResMut<RoundState>,
+    query: Query<Entity, With<Player>>,
+) {
+    for e in query.iter() {
+        commands.entity(e).despawn();
+    }
+    commands.remove_resource::<FrameCount>();
+    commands.remove_resource::<MatchStart>();
+    *round_state = RoundState::NotReady;
+}
+
+#[cfg(feature = "app")]
+pub(crate) fn increase_frame_count(mut frame_count: ResMut<FrameCount>) {
+    frame_count.frame += 1;
+}
+
+#[cfg(feature = "app")]
+pub(crate) fn update_round(
+    frame_count: Res<FrameCount>,
+    rules: Res<MatchRules>,
+    mut round_state: ResMut<RoundState>,
+) {
+    *round_state = next_round_state(*round_state, frame_count.frame, &rules);
+}
+
+/// Where the round timers lead `state` at `frame`, before inputs are handled.
+pub(crate) fn next_round_state(state: RoundState, frame: Frame, rules: &MatchRules) -> RoundState {
+    match state {
+        RoundState::NotReady => RoundState::WaitUntil(RoundWait {
+            from: frame,
+            until: frame + rules.decision_frames,
+        }),
+        RoundState::WaitUntil(wait) => {
+            if wait.until <= frame {
+                info!("displayUntil");
+                RoundState::DisplayUntil(RoundWait {
                     from: frame,
-                    until: frame + 60 * 2,
+                    until: frame + rules.display_frames,
                 })
-            }
-            *round_state = match *round_state {
-                RoundState::NotReady => RoundState::WaitUntil(RoundWait {
-                    from: frame,
-                    until: frame + 60 * 2,
-                }),
-                RoundState::WaitUntil(wait) => {
-                    if wait.until <= frame {
-                        info!("displayUntil");
-                        RoundState::DisplayUntil(RoundWait {
-                            from: frame,
-                            until: frame + 60 * 1,
-                        })
-                    } else {
-                        *round_state
-                    }
-                }
-                RoundState::DisplayUntil(wait) => {
-                    if wait.until <= frame {
-                        info!("round compute");
-                        RoundState::Compute
-                    } else {
-                        *round_state
-                    }
-                }
-                _ => *round_state,
+            } else {
+                state
             }
         }
-    } else if let Some(mut round_state) = world.get_resource_mut::<RoundState>() {
-        if !matches!(*round_state, RoundState::NotReady) {
-            *round_state = RoundState::NotReady;
+        RoundState::DisplayUntil(wait) => {
+            if wait.until <= frame {
+                info!("round compute");
+                RoundState::Compute
+            } else {
+                state
+            }
         }
+        _ => state,
     }
 }
 
+/// Action a player's flags stand for. Neither reloading nor firing is as safe as shielding.
+#[cfg(feature = "app")]
+pub(crate) fn current_action(reload: &ActionReload, fire: &ActionFire) -> Action {
+    if reload.is_active {
+        Action::Reload
+    } else if fire.is_active {
+        Action::Fire
+    } else {
+        Action::Shield
+    }
+}
+
+#[cfg(feature = "app")]
 pub(crate) fn compute_end_round(
-    mut commands: Commands,
     mut round_state: ResMut<RoundState>,
-    mut reload_query: Query<(Entity, &mut ActionReload, &Player)>,
-    mut shield_query: Query<(Entity, &ActionShield, &Player)>,
-    mut fire_query: Query<(Entity, &ActionFire, &Player)>,
-    mut ammo_query: Query<&mut Ammunition>,
-    mut hp_query: Query<(Entity, &mut Health, &Player)>,
+    mut query: Query<(
+        &Player,
+        &ActionReload,
+        &ActionFire,
+        &mut Health,
+        &mut Ammunition,
+    )>,
 ) {
     if matches!(*round_state, RoundState::Compute) {
-        let mut vulnerables = vec![0, 1];
-        for (e, reload, player) in reload_query.iter() {
-            if reload.is_active {
-                ammo_query.get_mut(e).unwrap().amount += 1;
-            } else {
-                vulnerables.retain(|h| *h != player.handle);
-            }
+        let mut players = [PlayerStats { health: 0, ammo: 0 }; NUM_PLAYERS];
+        let mut actions = [Action::Shield; NUM_PLAYERS];
+        for (player, reload, fire, health, ammo) in query.iter() {
+            players[player.handle] = PlayerStats {
+                health: health.amount,
+                ammo: ammo.amount,
+            };
+            actions[player.handle] = current_action(reload, fire);
         }
-        let mut damages = vec![];
-        for (e, fire, player) in fire_query.iter() {
-            if !fire.is_active {
-                continue;
-            }
-            let mut ammo = ammo_query.get_mut(e).unwrap();
-            if ammo.amount <= 0 {
-                continue;
-            }
-            ammo.amount -= 1;
-            info!("{} fires, now at {} ammo", player.handle, ammo.amount);
-            let attacked_player = if player.handle == 0 { 1 } else { 0 };
-            if vulnerables.contains(&attacked_player) {
-                damages.push(attacked_player);
+        let resolved = resolve_round(players, actions);
+        for (player, _, _, mut health, mut ammo) in query.iter_mut() {
+            let stats = resolved[player.handle];
+            if stats.health != health.amount {
+                info!("{} loses hp, now at {} HP", player.handle, stats.health);
             }
+            health.amount = stats.health;
+            ammo.amount = stats.ammo;
         }
-        for (e, mut health, player) in hp_query.iter_mut() {
-            if damages.contains(&player.handle) {
-                info!("{} loses hp, now at {} HP", player.handle, health.amount);
-                health.amount -= 1;
-                if health.amount <= 0 {
-                    // TODO: trigger lose for player!
-                    //commands.entity(e).despawn();
-                }
-            }
-        }
-        *round_state = RoundState::NextRound;
+        let someone_died = resolved.iter().any(|stats| stats.health <= 0);
+        *round_state = if someone_died {
+            info!("game over");
+            RoundState::GameOver
+        } else {
+            RoundState::NextRound
+        };
     }
 }
 
-pub(crate) fn react_end_round(world: &mut World) {
-    if world.get_resource::<P2PSession>().is_some() {
-        let frame = world.get_resource::<P2PSession>().unwrap().current_frame();
-        if let Some(mut round_state) = world.get_resource_mut::<RoundState>() {
-            if matches!(*round_state, RoundState::NextRound) {
-                *round_state = RoundState::WaitUntil(RoundWait {
-                    from: frame,
-                    until: frame + 60 * 2,
-                });
-                info!("round wait");
-            }
-        }
+#[cfg(feature = "app")]
+pub(crate) fn react_end_round(
+    frame_count: Res<FrameCount>,
+    rules: Res<MatchRules>,
+    mut round_state: ResMut<RoundState>,
+) {
+    let frame = frame_count.frame;
+    if matches!(*round_state, RoundState::NextRound) {
+        *round_state = RoundState::WaitUntil(RoundWait {
+            from: frame,
+            until: frame + rules.decision_frames,
+        });
+        info!("round wait");
     }
 }
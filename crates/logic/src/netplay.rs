@@ -0,0 +1,323 @@
+//! Direct UDP matches for clients stepping a `MatchSim` instead of running the Bevy app:
+//! the same handshake, GGRS session and desync checks as the game, so they can play it.
+
+use std::collections::BTreeMap;
+use std::net::SocketAddr;
+use std::time::{Duration, Instant};
+
+use ggrs::{Frame, GGRSError, GGRSRequest, P2PSession, SessionState};
+
+use crate::desync::DESYNC_CHECK_INTERVAL;
+use crate::logic::MatchRules;
+use crate::network::{
+    connect_peers, new_p2p_session, open_direct_socket, remote_players, room_seed, DirectConnect,
+    HandshakeTimers, DIRECT_ROOM, DISCONNECT_NOTIFY, RECONNECT_WINDOW,
+};
+use crate::protocol::PlayerInput;
+use crate::replay::ReplayRound;
+use crate::sim::MatchSim;
+use crate::socket::{ChecksumChannel, GameSocket, PeerActivity};
+
+/// A direct connection waiting for its peer.
+pub struct DirectConnection {
+    socket: Option<GameSocket>,
+    timers: HandshakeTimers,
+    last_update: Instant,
+    rules: MatchRules,
+}
+
+impl DirectConnection {
+    pub fn open(direct: DirectConnect) -> Result<Self, String> {
+        Ok(Self {
+            socket: Some(open_direct_socket(direct)?),
+            timers: HandshakeTimers::default(),
+            last_update: Instant::now(),
+            rules: MatchRules::default(),
+        })
+    }
+
+    /// Advances the handshake, call it every frame. Returns the match once the peers are
+    /// ready to play it.
+    pub fn update(&mut self) -> Result<Option<NetMatch>, String> {
+        let socket = self.socket.as_mut().ok_or("already connected")?;
+        let delta = self.last_update.elapsed().as_secs_f32();
+        self.last_update = Instant::now();
+        let delay = match connect_peers(socket, &mut self.timers, delta, &self.rules)? {
+            Some(delay) => delay,
+            None => return Ok(None),
+        };
+
+        let socket = self.socket.take().unwrap();
+        NetMatch::start(socket, delay, room_seed(DIRECT_ROOM), self.rules.clone()).map(Some)
+    }
+}
+
+/// A match against a remote peer, played by rolling a `MatchSim` back and forth like
+/// bevy_ggrs does with the Bevy world.
+pub struct NetMatch {
+    session: P2PSession,
+    local_handle: usize,
+    seed: u64,
+    rules: MatchRules,
+    delay: u32,
+    /// Simulations GGRS asked to save, indexed by frame modulo their count.
+    snapshots: Vec<Option<MatchSim>>,
+    /// Inputs of every frame simulated, as `ReplayRecorder` keeps them.
+    inputs: Vec<Vec<Vec<u8>>>,
+    /// Rounds resolved so far, the latest ones dropped again when a rollback resimulates
+    /// them.
+    rounds: Vec<ReplayRound>,
+    checksums: ChecksumChannel,
+    /// Checksums of the frames not compared yet, overwritten when rollbacks resimulate them.
+    local: BTreeMap<Frame, u64>,
+    remote: BTreeMap<Frame, u64>,
+    /// Frames up to this one were sent to the peer.
+    sent_until: Frame,
+    activity: PeerActivity,
+    /// Handle and address of the opponent.
+    remotes: Vec<(usize, SocketAddr)>,
+}
+
+impl NetMatch {
+    /// Starts the GGRS session between the players of `socket`, who said hello already.
+    fn start(socket: GameSocket, delay: u32, seed: u64, rules: MatchRules) -> Result<Self, String> {
+        let checksums = socket.checksums();
+        let activity = socket.activity();
+        let remotes = remote_players(&socket.players());
+        let mut session = new_p2p_session(socket, delay);
+        session.start_session().map_err(|e| e.to_string())?;
+        let local_handle = session
+            .local_player_handle()
+            .ok_or("no local player in the session")?;
+        Ok(Self {
+            session,
+            local_handle,
+            seed,
+            rules,
+            delay,
+            snapshots: vec![],
+            inputs: vec![],
+            rounds: vec![],
+            checksums,
+            local: BTreeMap::new(),
+            remote: BTreeMap::new(),
+            sent_until: -1,
+            activity,
+            remotes,
+        })
+    }
+
+    pub fn local_handle(&self) -> usize {
+        self.local_handle
+    }
+
+    /// Seed both peers agree on.
+    pub fn seed(&self) -> u64 {
+        self.seed
+    }
+
+    pub fn rules(&self) -> &MatchRules {
+        &self.rules
+    }
+
+    /// Frames of input delay picked from the measured round trip.
+    pub fn delay(&self) -> u32 {
+        self.delay
+    }
+
+    /// Inputs of every frame simulated, the latest ones may still be predictions.
+    pub fn inputs(&self) -> &[Vec<Vec<u8>>] {
+        &self.inputs
+    }
+
+    /// Rounds resolved so far, like `Replay::rounds` without replaying the match. The
+    /// latest ones may still be predictions.
+    pub fn rounds(&self) -> &[ReplayRound] {
+        &self.rounds
+    }
+
+    /// Whether we are ahead of the peer, the next frame should then come a bit later.
+    pub fn run_slow(&self) -> bool {
+        self.session.frames_ahead() > 0
+    }
+
+    /// Sends our input and runs the frames GGRS asks for on `sim`, rolling it back when a
+    /// prediction was wrong. Does nothing while waiting for the peer.
+    pub fn advance(&mut self, sim: &mut MatchSim, input: &PlayerInput) -> Result<(), String> {
+        self.session.poll_remote_clients();
+        // GGRS reports what it sees through events, we watch the peer ourselves
+        self.session.events().for_each(drop);
+        if self.session.current_state() != SessionState::Running {
+            return Ok(());
+        }
+        let requests = match self
+            .session
+            .advance_frame(self.local_handle, &input.encode())
+        {
+            Ok(requests) => requests,
+            Err(GGRSError::PredictionThreshold) => return Ok(()),
+            Err(e) => return Err(e.to_string()),
+        };
+        if self.snapshots.is_empty() {
+            self.snapshots = vec![None; self.session.max_prediction() + 2];
+        }
+        let len = self.snapshots.len();
+        for request in requests {
+            match request {
+                GGRSRequest::SaveGameState { cell, frame } => {
+                    debug_assert_eq!(frame, sim.frame());
+                    cell.save(ggrs::GameState::new(frame, None));
+                    self.snapshots[frame as usize % len] = Some(sim.clone());
+                }
+                GGRSRequest::LoadGameState { cell, .. } => {
+                    let frame = cell.load().frame;
+                    *sim = self.snapshots[frame as usize % len]
+                        .clone()
+                        .ok_or(format!("no snapshot of frame {frame}"))?;
+                    let kept = self.rounds.partition_point(|round| round.frame < frame);
+                    self.rounds.truncate(kept);
+                }
+                GGRSRequest::AdvanceFrame { inputs } => {
+                    let inputs: Vec<Vec<u8>> =
+                        inputs.into_iter().map(|input| input.buffer).collect();
+                    let frame = sim.frame();
+                    self.inputs.truncate(frame as usize);
+                    self.inputs.push(inputs.clone());
+                    let players = sim.players();
+                    if let Some(actions) = sim.advance(&self.rules, &inputs) {
+                        self.rounds.push(ReplayRound {
+                            frame,
+                            players,
+                            actions,
+                        });
+                    }
+                    if frame % DESYNC_CHECK_INTERVAL == 0 {
+                        self.local.insert(frame, sim.checksum());
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Sends the checksums of frames no rollback can change anymore and compares them with
+    /// the peer's, like `desync::check_desync`. Returns the first frame they disagree on.
+    pub fn check_desync(&mut self) -> Option<Frame> {
+        let settled = self.session.current_frame() - self.session.max_prediction() as Frame - 1;
+        if settled > self.sent_until {
+            for (frame, checksum) in self.local.range(self.sent_until + 1..=settled) {
+                self.checksums.send(*frame, *checksum);
+            }
+            self.sent_until = settled;
+        }
+        for (frame, checksum) in self.checksums.receive() {
+            self.remote.insert(frame, checksum);
+        }
+
+        let compared: Vec<Frame> = self
+            .remote
+            .keys()
+            .copied()
+            .filter(|frame| *frame <= self.sent_until && self.local.contains_key(frame))
+            .collect();
+        for frame in compared {
+            let remote = self.remote.remove(&frame).unwrap();
+            let local = self.local.remove(&frame).unwrap();
+            if local != remote {
+                return Some(frame);
+            }
+        }
+        None
+    }
+
+    /// Time left for the opponent to come back before they forfeit, once they have been
+    /// silent long enough to tell the player.
+    pub fn opponent_timeout(&self) -> Option<Duration> {
+        let silence = self
+            .remotes
+            .iter()
+            .map(|(_, addr)| self.activity.silent_for(addr))
+            .max()
+            .unwrap_or_default();
+        (silence >= DISCONNECT_NOTIFY).then(|| RECONNECT_WINDOW.saturating_sub(silence))
+    }
+
+    /// Whether the opponent was silent for too long to come back, they then forfeit.
+    pub fn opponent_gone(&self) -> bool {
+        self.opponent_timeout() == Some(Duration::ZERO)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::rules::{Action, NUM_PLAYERS};
+    use crate::socket::MemoryTransport;
+
+    use super::*;
+
+    /// Both sides of a match over memory, synchronized and ready to play.
+    fn net_pair(rules: &MatchRules) -> [NetMatch; NUM_PLAYERS] {
+        let addrs = ["127.0.0.1:7000", "127.0.0.1:7001"].map(|addr| addr.parse().unwrap());
+        let mut sockets = MemoryTransport::pair(addrs).map(GameSocket::new);
+        for socket in &mut sockets {
+            socket.say_hello();
+        }
+        for socket in &mut sockets {
+            socket.receive_hellos();
+        }
+        // no input delay, every prediction of the peer's input is made and can be wrong
+        let mut nets = sockets.map(|socket| NetMatch::start(socket, 0, 7, rules.clone()).unwrap());
+        for _ in 0..100 {
+            for net in &mut nets {
+                net.session.poll_remote_clients();
+            }
+        }
+        for net in &nets {
+            assert_eq!(net.session.current_state(), SessionState::Running);
+        }
+        nets
+    }
+
+    fn picking(action: Action) -> PlayerInput {
+        PlayerInput {
+            action: Some(action),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn both_sides_agree_after_a_rollback() {
+        let rules = MatchRules::default();
+        let [mut first, mut second] = net_pair(&rules);
+        let mut sims = [(); NUM_PLAYERS].map(|_| MatchSim::from_rules(&rules));
+        let inputs = [picking(Action::Reload), picking(Action::Shield)];
+
+        // the first side runs ahead, predicting the second sends what it sent last: nothing
+        for _ in 0..6 {
+            first.advance(&mut sims[0], &inputs[0]).unwrap();
+        }
+        let predicted = first.inputs().to_vec();
+        assert_eq!(predicted.len(), 6);
+
+        // past the first round, the second side's inputs roll the first one back
+        let frames = rules.decision_frames + rules.display_frames + 10;
+        for _ in 0..frames {
+            second.advance(&mut sims[1], &inputs[1]).unwrap();
+            first.advance(&mut sims[0], &inputs[0]).unwrap();
+        }
+        while sims[1].frame() < sims[0].frame() {
+            second.advance(&mut sims[1], &inputs[1]).unwrap();
+        }
+        first.advance(&mut sims[0], &inputs[0]).unwrap();
+        second.advance(&mut sims[1], &inputs[1]).unwrap();
+
+        assert_eq!(sims[0].frame(), sims[1].frame());
+        assert_ne!(first.inputs()[..6], predicted[..]);
+        assert_eq!(first.inputs()[0][1], inputs[1].encode().to_vec());
+        assert_eq!(first.inputs(), second.inputs());
+        assert_eq!(first.rounds(), second.rounds());
+        assert_eq!(first.rounds().len(), 1);
+        assert_eq!(first.rounds()[0].actions, [Action::Reload, Action::Shield]);
+        assert_eq!(sims[0].checksum(), sims[1].checksum());
+    }
+}
@@ -0,0 +1,264 @@
+use bevy::utils::{Duration, Instant};
+use ggrs::PlayerType;
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+use std::net::SocketAddr;
+use std::str::FromStr;
+use std::sync::{Arc, Mutex};
+
+use crate::socket::Transport;
+
+/// Extra time a reordered packet is held back, so the packets sent after it overtake it.
+const REORDER_HOLD: Duration = Duration::from_millis(50);
+
+/// Bad network to simulate, to test rollbacks without one. Latency and jitter are added in
+/// each direction, on top of the real network.
+#[derive(Clone, Debug, Default)]
+pub struct NetworkConditions {
+    pub latency: Duration,
+    /// Latency varies by up to this much either way.
+    pub jitter: Duration,
+    /// Chance for a packet to be dropped, from 0 to 1.
+    pub loss: f32,
+    /// Chance for a packet to arrive after the ones sent after it, from 0 to 1.
+    pub reorder: f32,
+    /// Seed of the losses, delays and reorders, to reproduce a run. Random if `None`.
+    pub seed: Option<u64>,
+}
+
+/// Parses `latency=80,jitter=20,loss=0.05,reorder=0.01,seed=7`, times in milliseconds.
+/// Missing settings are left at zero, and the seed random.
+impl FromStr for NetworkConditions {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut conditions = Self::default();
+        for setting in s.split(',').filter(|setting| !setting.is_empty()) {
+            let (name, value) = setting
+                .split_once('=')
+                .ok_or(format!("expected name=value, got {setting}"))?;
+            let invalid = || format!("invalid {name} {value}");
+            match name {
+                "latency" => {
+                    conditions.latency =
+                        Duration::from_millis(value.parse().map_err(|_| invalid())?)
+                }
+                "jitter" => {
+                    conditions.jitter = Duration::from_millis(value.parse().map_err(|_| invalid())?)
+                }
+                "loss" | "reorder" => {
+                    let chance: f32 = value.parse().map_err(|_| invalid())?;
+                    if !(0.0..=1.0).contains(&chance) {
+                        return Err(invalid());
+                    }
+                    if name == "loss" {
+                        conditions.loss = chance;
+                    } else {
+                        conditions.reorder = chance;
+                    }
+                }
+                "seed" => conditions.seed = Some(value.parse().map_err(|_| invalid())?),
+                _ => return Err(format!("unknown network condition {name}")),
+            }
+        }
+        Ok(conditions)
+    }
+}
+
+/// Time the simulated network delivers packets by.
+#[derive(Clone, Default)]
+pub(crate) enum NetClock {
+    #[default]
+    Real,
+    /// Only moves when advanced, so tests don't depend on how fast they run.
+    #[cfg_attr(not(test), allow(dead_code))]
+    Manual(Arc<Mutex<Instant>>),
+}
+
+impl NetClock {
+    #[cfg(test)]
+    pub(crate) fn manual() -> Self {
+        NetClock::Manual(Arc::new(Mutex::new(Instant::now())))
+    }
+
+    #[cfg(test)]
+    pub(crate) fn advance(&self, by: Duration) {
+        if let NetClock::Manual(now) = self {
+            *now.lock().unwrap() += by;
+        }
+    }
+
+    fn now(&self) -> Instant {
+        match self {
+            NetClock::Real => Instant::now(),
+            NetClock::Manual(now) => *now.lock().unwrap(),
+        }
+    }
+}
+
+struct InFlight {
+    deliver_at: Instant,
+    addr: SocketAddr,
+    packet: Box<[u8]>,
+}
+
+/// Transport delaying, dropping and reordering the packets of the transport it wraps. It
+/// sits under `GameSocket`, so the handshake and our own packets go through it too.
+pub(crate) struct SimulatedTransport {
+    inner: Box<dyn Transport>,
+    conditions: NetworkConditions,
+    clock: NetClock,
+    rng: StdRng,
+    outgoing: Vec<InFlight>,
+    incoming: Vec<InFlight>,
+}
+
+impl SimulatedTransport {
+    pub(crate) fn new(
+        inner: Box<dyn Transport>,
+        conditions: NetworkConditions,
+        clock: NetClock,
+    ) -> Self {
+        let rng = match conditions.seed {
+            Some(seed) => StdRng::seed_from_u64(seed),
+            None => StdRng::from_entropy(),
+        };
+        Self {
+            inner,
+            conditions,
+            clock,
+            rng,
+            outgoing: vec![],
+            incoming: vec![],
+        }
+    }
+
+    /// When a packet sent now arrives, `None` if it is lost.
+    fn deliver_at(&mut self) -> Option<Instant> {
+        if self.rng.gen::<f32>() < self.conditions.loss {
+            return None;
+        }
+        let jitter = self.conditions.jitter.as_secs_f32();
+        let mut delay =
+            (self.conditions.latency.as_secs_f32() + self.rng.gen_range(-jitter..=jitter)).max(0.0);
+        if self.rng.gen::<f32>() < self.conditions.reorder {
+            delay += REORDER_HOLD.as_secs_f32();
+        }
+        Some(self.clock.now() + Duration::from_secs_f32(delay))
+    }
+
+    fn flush_outgoing(&mut self) {
+        for message in take_due(&mut self.outgoing, self.clock.now()) {
+            self.inner.send(message.packet, message.addr);
+        }
+    }
+}
+
+/// Removes the packets which should have arrived by `now`, in arrival order.
+fn take_due(queue: &mut Vec<InFlight>, now: Instant) -> Vec<InFlight> {
+    let (mut due, waiting) = std::mem::take(queue)
+        .into_iter()
+        .partition(|message: &InFlight| message.deliver_at <= now);
+    *queue = waiting;
+    due.sort_by_key(|message| message.deliver_at);
+    due
+}
+
+impl Transport for SimulatedTransport {
+    fn accept_new_connections(&mut self) {
+        self.inner.accept_new_connections();
+    }
+
+    fn connected_peers(&self) -> Vec<SocketAddr> {
+        self.inner.connected_peers()
+    }
+
+    fn players(&self) -> Vec<PlayerType> {
+        self.inner.players()
+    }
+
+    fn send(&mut self, packet: Box<[u8]>, addr: SocketAddr) {
+        if let Some(deliver_at) = self.deliver_at() {
+            self.outgoing.push(InFlight {
+                deliver_at,
+                addr,
+                packet,
+            });
+        }
+        self.flush_outgoing();
+    }
+
+    fn receive(&mut self) -> Vec<(SocketAddr, Box<[u8]>)> {
+        // the socket polls often, delayed packets are sent from here when nothing new is sent
+        self.flush_outgoing();
+        for (addr, packet) in self.inner.receive() {
+            if let Some(deliver_at) = self.deliver_at() {
+                self.incoming.push(InFlight {
+                    deliver_at,
+                    addr,
+                    packet,
+                });
+            }
+        }
+        take_due(&mut self.incoming, self.clock.now())
+            .into_iter()
+            .map(|message| (message.addr, message.packet))
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_every_setting() {
+        let conditions: NetworkConditions = "latency=80,jitter=20,loss=0.05,reorder=0.01,seed=7"
+            .parse()
+            .unwrap();
+        assert_eq!(conditions.latency, Duration::from_millis(80));
+        assert_eq!(conditions.jitter, Duration::from_millis(20));
+        assert_eq!(conditions.loss, 0.05);
+        assert_eq!(conditions.reorder, 0.01);
+        assert_eq!(conditions.seed, Some(7));
+    }
+
+    #[test]
+    fn missing_settings_stay_at_zero() {
+        let conditions: NetworkConditions = "loss=1".parse().unwrap();
+        assert_eq!(conditions.latency, Duration::ZERO);
+        assert_eq!(conditions.jitter, Duration::ZERO);
+        assert_eq!(conditions.loss, 1.0);
+        assert_eq!(conditions.reorder, 0.0);
+        assert_eq!(conditions.seed, None);
+        assert_eq!("".parse::<NetworkConditions>().unwrap().seed, None);
+    }
+
+    #[test]
+    fn times_are_whole_milliseconds() {
+        assert!("latency=80ms".parse::<NetworkConditions>().is_err());
+        assert!("jitter=1.5".parse::<NetworkConditions>().is_err());
+        assert!("latency=-1".parse::<NetworkConditions>().is_err());
+    }
+
+    #[test]
+    fn chances_are_between_zero_and_one() {
+        assert!("loss=1.5".parse::<NetworkConditions>().is_err());
+        assert!("loss=-0.1".parse::<NetworkConditions>().is_err());
+        assert!("reorder=2".parse::<NetworkConditions>().is_err());
+        assert!("reorder=often".parse::<NetworkConditions>().is_err());
+    }
+
+    #[test]
+    fn rejects_unknown_or_malformed_settings() {
+        assert_eq!(
+            "latency=80,delay=5"
+                .parse::<NetworkConditions>()
+                .unwrap_err(),
+            "unknown network condition delay"
+        );
+        assert!("latency".parse::<NetworkConditions>().is_err());
+        assert!("seed=".parse::<NetworkConditions>().is_err());
+        assert!("seed=random".parse::<NetworkConditions>().is_err());
+    }
+}
@@ -0,0 +1,129 @@
+use std::collections::VecDeque;
+
+use bevy::prelude::*;
+use bevy_egui::{egui, EguiContext};
+use ggrs::{Frame, P2PSession};
+
+use crate::logic::FrameCount;
+use crate::rules::NUM_PLAYERS;
+use crate::states::GameState;
+
+const TOGGLE_KEY: KeyCode = KeyCode::F3;
+
+pub struct NetStatsPlugin;
+
+impl Plugin for NetStatsPlugin {
+    fn build(&self, app: &mut App) {
+        app.insert_resource(NetStatsOverlay::default());
+        app.insert_resource(RollbackCounter::default());
+        app.add_system_set(SystemSet::on_enter(GameState::InGame).with_system(reset_rollbacks));
+        app.add_system(toggle_net_stats);
+        app.add_system(net_stats_overlay);
+    }
+}
+
+#[derive(Default)]
+pub(crate) struct NetStatsOverlay {
+    visible: bool,
+}
+
+/// Notices rollbacks from the rollback schedule going back to frames it already simulated.
+#[derive(Default)]
+pub(crate) struct RollbackCounter {
+    last_frame: Option<Frame>,
+    /// When the rollbacks of the last second happened, in seconds since startup.
+    rollbacks: VecDeque<f64>,
+}
+
+impl RollbackCounter {
+    /// Forgets the rollbacks older than a second, so the count stays small when the overlay
+    /// is hidden for a whole match.
+    fn prune(&mut self, now: f64) {
+        while self.rollbacks.front().is_some_and(|t| *t < now - 1.0) {
+            self.rollbacks.pop_front();
+        }
+    }
+
+    fn per_second(&mut self, now: f64) -> usize {
+        self.prune(now);
+        self.rollbacks.len()
+    }
+}
+
+/// Forgets the frames of the previous match, which would otherwise count as a rollback.
+fn reset_rollbacks(mut counter: ResMut<RollbackCounter>) {
+    *counter = RollbackCounter::default();
+}
+
+/// Run by GGRS in the rollback schedule. Sync test and replay sessions resimulate frames
+/// too, only P2P rollbacks are counted.
+pub(crate) fn count_rollbacks(
+    time: Res<Time>,
+    frame_count: Res<FrameCount>,
+    session: Option<Res<P2PSession>>,
+    mut counter: ResMut<RollbackCounter>,
+) {
+    if session.is_none() {
+        return;
+    }
+    let frame = frame_count.frame;
+    let now = time.seconds_since_startup();
+    // only the first resimulated frame goes back, the following ones move forward again
+    if counter.last_frame.is_some_and(|last| frame <= last) {
+        counter.rollbacks.push_back(now);
+    }
+    counter.last_frame = Some(frame);
+    counter.prune(now);
+}
+
+fn toggle_net_stats(keys: Res<Input<KeyCode>>, mut overlay: ResMut<NetStatsOverlay>) {
+    if keys.just_pressed(TOGGLE_KEY) {
+        overlay.visible = !overlay.visible;
+    }
+}
+
+fn net_stats_overlay(
+    egui_context: Res<EguiContext>,
+    time: Res<Time>,
+    overlay: Res<NetStatsOverlay>,
+    session: Option<Res<P2PSession>>,
+    mut counter: ResMut<RollbackCounter>,
+) {
+    let session = match session {
+        Some(session) if overlay.visible => session,
+        _ => return,
+    };
+    let rollbacks = counter.per_second(time.seconds_since_startup());
+    egui::Window::new("Network (F3)")
+        .anchor(egui::Align2::RIGHT_TOP, [-10.0, 10.0])
+        .collapsible(false)
+        .resizable(false)
+        .show(egui_context.ctx(), |ui| {
+            ui.label(format!(
+                "prediction: {} frames",
+                session.current_frame() - session.confirmed_frame()
+            ));
+            ui.label(format!("rollbacks: {}/s", rollbacks));
+            for handle in 0..NUM_PLAYERS {
+                if Some(handle) == session.local_player_handle() {
+                    continue;
+                }
+                ui.separator();
+                ui.strong(format!("Player {}", handle));
+                match session.network_stats(handle) {
+                    Ok(stats) => {
+                        ui.label(format!("ping: {} ms", stats.ping));
+                        ui.label(format!(
+                            "frame advantage: local {}, remote {}",
+                            -stats.local_frames_behind, -stats.remote_frames_behind
+                        ));
+                        ui.label(format!("send queue: {}", stats.send_queue_len));
+                        ui.label(format!("bandwidth: {} kbps", stats.kbps_sent));
+                    }
+                    Err(e) => {
+                        ui.label(format!("no stats: {}", e));
+                    }
+                }
+            }
+        });
+}
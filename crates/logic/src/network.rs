@@ -1,56 +1,282 @@
-use crate::input::INPUT_SIZE;
+#[cfg(feature = "app")]
+use crate::desync::DesyncDetector;
+use crate::desync::Fnv1a;
+#[cfg(feature = "app")]
+use crate::lobby::{LaunchOptions, LobbyUi, MatchmakingRoom};
+use crate::logic::MatchRules;
+#[cfg(feature = "app")]
+use crate::logic::{FrameCount, Health, MatchSeed, Player, RoundState};
+#[cfg(feature = "app")]
+use crate::netsim::NetClock;
+use crate::protocol::{INPUT_SIZE, PROTOCOL_VERSION};
+#[cfg(feature = "app")]
+use crate::replay::ReplayRecorder;
+use crate::rules::NUM_PLAYERS;
+use crate::socket::{GameSocket, Handshake};
+#[cfg(feature = "app")]
+use crate::socket::{MatchboxTransport, PeerActivity};
+#[cfg(feature = "app")]
 use crate::states::GameState;
+#[cfg(feature = "app")]
 use bevy::prelude::*;
+#[cfg(feature = "app")]
 use bevy::tasks::IoTaskPool;
+#[cfg(feature = "app")]
+use bevy_egui::{egui, EguiContext};
+#[cfg(feature = "app")]
 use bevy_ggrs::CommandsExt;
 use ggrs::P2PSession;
 use ggrs::PlayerType;
-use matchbox_socket::WebRtcNonBlockingSocket;
+#[cfg(feature = "app")]
+use ggrs::SyncTestSession;
+use std::net::SocketAddr;
+use std::time::Duration;
+use tracing::{info, warn};
 
-pub(crate) fn start_matchbox_socket(mut commands: Commands, task_pool: Res<IoTaskPool>) {
-    let room_url = "ws://matchbox-vrixyz.herokuapp.com/next_2";
+#[cfg(feature = "app")]
+const MATCHBOX_URL: &str = "ws://matchbox-vrixyz.herokuapp.com";
+
+pub(crate) const MAX_PREDICTION: usize = 12;
+/// Room code of direct connections, only used to derive the match seed.
+pub(crate) const DIRECT_ROOM: &str = "direct";
+
+/// Silence after which we tell the player we are waiting for their opponent.
+pub(crate) const DISCONNECT_NOTIFY: Duration = Duration::from_millis(500);
+/// Silence after which the opponent is considered gone for good and forfeits.
+pub(crate) const RECONNECT_WINDOW: Duration = Duration::from_secs(15);
+/// Silence after which GGRS drops a peer. Longer than `RECONNECT_WINDOW`, so GGRS never
+/// disconnects the opponent before they forfeit.
+const GGRS_DISCONNECT_TIMEOUT: Duration = Duration::from_secs(20);
+
+/// Hellos are sent again at this interval until the peers agree on the protocol version.
+const HELLO_INTERVAL_SECS: f32 = 0.25;
+
+/// Once the handshake is done, peers ping each other at this interval until they have
+/// `ROUND_TRIP_SAMPLES` round trips, or for `MEASURE_TIMEOUT_SECS` at most.
+const PING_INTERVAL_SECS: f32 = 0.1;
+const ROUND_TRIP_SAMPLES: usize = 8;
+const MEASURE_TIMEOUT_SECS: f32 = 2.0;
+
+/// Duration of a GGRS frame, bevy_ggrs runs at 60 frames per second.
+pub(crate) const FRAME_DURATION: Duration = Duration::from_micros(16_667);
+
+/// Timers of the handshake run by `wait_for_players`.
+#[derive(Default)]
+pub(crate) struct HandshakeTimers {
+    since_hello: f32,
+    since_ping: f32,
+    measuring_for: f32,
+}
+
+/// Watches the remote players of a P2P match, so a silent opponent does not freeze the game.
+///
+/// GGRS tells when a peer goes silent with its `NetworkInterrupted`, `NetworkResumed` and
+/// `Disconnected` events, but bevy_ggrs 0.1.3 drains `P2PSession::events` itself every frame
+/// and only prints them. So the socket records when each peer was last heard from instead.
+#[cfg(feature = "app")]
+pub(crate) struct ConnectionMonitor {
+    activity: PeerActivity,
+    /// Handle and address of each remote player.
+    remotes: Vec<(usize, SocketAddr)>,
+}
+
+/// Direct UDP connection between native clients, instead of matchbox.
+#[derive(Clone, Copy, Debug)]
+pub enum DirectConnect {
+    /// Waits for a client on this port.
+    Host(u16),
+    /// Joins a host.
+    Connect(SocketAddr),
+}
+
+#[cfg(feature = "app")]
+pub(crate) fn start_matchbox_socket(
+    mut commands: Commands,
+    task_pool: Res<IoTaskPool>,
+    room: Res<MatchmakingRoom>,
+    options: Res<LaunchOptions>,
+    mut lobby: ResMut<LobbyUi>,
+    mut state: ResMut<State<GameState>>,
+) {
+    let socket = match room.direct {
+        Some(direct) => open_direct_socket(direct),
+        None => Ok(open_matchbox_socket(&task_pool, &room.code)),
+    };
+    match socket {
+        Ok(socket) => {
+            let socket = match options.network_conditions.clone() {
+                Some(conditions) => socket.simulate(conditions, NetClock::Real),
+                None => socket,
+            };
+            commands.insert_resource(Some(socket));
+        }
+        Err(e) => {
+            lobby.connection_error = Some(format!("Could not connect: {e}"));
+            state.set(GameState::Lobby).unwrap();
+        }
+    }
+}
+
+#[cfg(feature = "app")]
+fn open_matchbox_socket(task_pool: &IoTaskPool, room_code: &str) -> GameSocket {
+    let room_url = format!("{}/{}", MATCHBOX_URL, room_code);
     info!("connecting to matchbox server: {:?}", room_url);
-    let (socket, message_loop) = WebRtcNonBlockingSocket::new(room_url);
+    let (transport, message_loop) = MatchboxTransport::new(room_url);
 
     // The message loop needs to be awaited, or nothing will happen.
     // We do this here using bevy's task system.
-    task_pool.spawn(message_loop).detach();
+    task_pool.spawn_local(message_loop).detach();
 
-    commands.insert_resource(Some(socket));
+    GameSocket::new(transport)
 }
 
+#[cfg(not(target_arch = "wasm32"))]
+pub(crate) fn open_direct_socket(direct: DirectConnect) -> Result<GameSocket, String> {
+    use crate::socket::UdpTransport;
+
+    info!("direct connection: {:?}", direct);
+    let transport = match direct {
+        DirectConnect::Host(port) => UdpTransport::host(port),
+        DirectConnect::Connect(addr) => UdpTransport::connect(addr),
+    };
+    transport
+        .map(GameSocket::new)
+        .map_err(|e| format!("could not open a UDP socket: {e}"))
+}
+
+#[cfg(all(feature = "app", target_arch = "wasm32"))]
+fn open_direct_socket(_: DirectConnect) -> Result<GameSocket, String> {
+    Err("browsers can't open direct connections".to_string())
+}
+
+#[cfg(feature = "app")]
 pub(crate) fn wait_for_players(
     mut commands: Commands,
-    mut socket: ResMut<Option<WebRtcNonBlockingSocket>>,
+    mut socket: ResMut<Option<GameSocket>>,
     mut state: ResMut<State<GameState>>,
+    mut lobby: ResMut<LobbyUi>,
+    room: Res<MatchmakingRoom>,
+    time: Res<Time>,
+    mut timers: Local<HandshakeTimers>,
 ) {
-    let socket = socket.as_mut();
-
     // If there is no socket we've already started the game
-    if socket.is_none() {
-        return;
-    }
+    let game_socket = match socket.as_mut() {
+        Some(socket) => socket,
+        None => return,
+    };
 
-    // Check for new connections
-    socket.as_mut().unwrap().accept_new_connections();
-    let players = socket.as_ref().unwrap().players();
+    let rules = MatchRules::default();
+    let delay = match connect_peers(game_socket, &mut timers, time.delta_seconds(), &rules) {
+        Ok(Some(delay)) => delay,
+        Ok(None) => return,
+        Err(e) => {
+            lobby.connection_error = Some(format!("Could not connect: {e}"));
+            *socket = None;
+            *timers = HandshakeTimers::default();
+            state.set(GameState::Lobby).unwrap();
+            return;
+        }
+    };
+    *timers = HandshakeTimers::default();
+
+    // consume the socket (currently required because GGRS takes ownership of its socket)
+    let socket = socket.take().unwrap();
+    let players = socket.players();
+    commands.insert_resource(DesyncDetector::new(socket.checksums()));
+    commands.insert_resource(ConnectionMonitor {
+        activity: socket.activity(),
+        remotes: remote_players(&players),
+    });
+    let p2p_session = new_p2p_session(socket, delay);
+
+    commands.insert_resource(MatchSeed(room_seed(&room.code)));
+    commands.insert_resource(rules);
+    commands.insert_resource(FrameCount::default());
+
+    // start the GGRS session
+    commands.start_p2p_session(p2p_session);
+
+    state.set(GameState::InGame).unwrap();
+}
 
-    let num_players = 2;
-    if players.len() < num_players {
-        return; // wait for more players
+/// Advances the handshake with the peers of `socket`, `delta` seconds after the last call:
+/// waits for every player, checks they play our version, then measures the round-trip
+/// time. Returns the input delay to play with once done.
+pub(crate) fn connect_peers(
+    socket: &mut GameSocket,
+    timers: &mut HandshakeTimers,
+    delta: f32,
+    rules: &MatchRules,
+) -> Result<Option<u32>, String> {
+    // Check for new connections
+    socket.accept_new_connections();
+    if socket.players().len() < NUM_PLAYERS {
+        return Ok(None); // wait for more players
     }
 
-    info!("All peers have joined, going in-game");
-    info!("All peers have joined, going in-game");
+    // Make sure everyone speaks the same protocol before handing the socket to GGRS
+    timers.since_hello += delta;
+    if timers.since_hello >= HELLO_INTERVAL_SECS {
+        timers.since_hello = 0.0;
+        socket.say_hello();
+    }
+    socket.receive_hellos();
+    match socket.handshake() {
+        Handshake::Pending => return Ok(None),
+        Handshake::Done => {}
+        Handshake::Mismatch(version) => {
+            let theirs = version.map_or("an unknown version".to_string(), |v| format!("v{v}"));
+            warn!(
+                "protocol mismatch: we play v{}, peer plays {}",
+                PROTOCOL_VERSION, theirs
+            );
+            return Err(format!(
+                "your opponent plays {theirs} of the game, you play v{PROTOCOL_VERSION}."
+            ));
+        }
+    }
 
-    // consume the socket (currently required because GGRS takes ownership of its socket)
-    let socket = socket.take().unwrap();
+    // Measure the round-trip time to pick the input delay
+    timers.measuring_for += delta;
+    timers.since_ping += delta;
+    let measuring = socket.round_trip_samples() < ROUND_TRIP_SAMPLES;
+    if measuring && timers.measuring_for < MEASURE_TIMEOUT_SECS {
+        if timers.since_ping >= PING_INTERVAL_SECS {
+            timers.since_ping = 0.0;
+            socket.ping();
+        }
+        return Ok(None);
+    }
+    let round_trip = socket.round_trip_time();
+    let delay = input_delay(round_trip, rules);
+    info!(
+        "All peers have joined, going in-game (round trip {:?}, input delay {} frames)",
+        round_trip, delay
+    );
+    Ok(Some(delay))
+}
 
-    let max_prediction = 12;
+/// Handle and address of the remote players among `players`.
+pub(crate) fn remote_players(players: &[PlayerType]) -> Vec<(usize, SocketAddr)> {
+    players
+        .iter()
+        .enumerate()
+        .filter_map(|(handle, player)| match player {
+            PlayerType::Remote(addr) => Some((handle, *addr)),
+            _ => None,
+        })
+        .collect()
+}
 
-    // create a GGRS P2P session
+/// GGRS session between the players of `socket`, the local one's inputs delayed by
+/// `delay` frames.
+pub(crate) fn new_p2p_session(socket: GameSocket, delay: u32) -> P2PSession {
+    let players = socket.players();
     let mut p2p_session =
-        ggrs::P2PSession::new_with_socket(num_players as u32, INPUT_SIZE, max_prediction, socket);
+        P2PSession::new_with_socket(NUM_PLAYERS as u32, INPUT_SIZE, MAX_PREDICTION, socket);
+    // we decide when the opponent is gone, GGRS must not drop them before
+    p2p_session.set_disconnect_timeout(GGRS_DISCONNECT_TIMEOUT);
+    p2p_session.set_disconnect_notify_delay(DISCONNECT_NOTIFY);
 
     for (i, player) in players.into_iter().enumerate() {
         p2p_session
@@ -59,12 +285,124 @@ pub(crate) fn wait_for_players(
 
         if player == PlayerType::Local {
             // set input delay for the local player
-            p2p_session.set_frame_delay(2, i).unwrap();
+            p2p_session.set_frame_delay(delay, i).unwrap();
         }
     }
+    p2p_session
+}
 
-    // start the GGRS session
-    commands.start_p2p_session(p2p_session);
+/// Both peers know the room they met in, which makes it a seed they agree on. Hashed with
+/// FNV-1a, native and web peers may be built by different compilers.
+pub(crate) fn room_seed(code: &str) -> u64 {
+    let mut hasher = Fnv1a::new();
+    hasher.write(code.as_bytes());
+    hasher.finish()
+}
 
-    state.set(GameState::InGame).unwrap();
+/// Frames of input delay covering the one-way trip of our inputs, plus a frame of margin for
+/// jitter. A round lasts seconds, so a longer delay is better than rollbacks changing what
+/// the opponent "will fire". Without a measure, we take the most delay allowed.
+fn input_delay(round_trip: Option<Duration>, rules: &MatchRules) -> u32 {
+    let delay = match round_trip {
+        Some(round_trip) => {
+            let one_way = round_trip.as_secs_f32() / 2.0;
+            (one_way / FRAME_DURATION.as_secs_f32()).ceil() as u32 + 1
+        }
+        None => rules.max_input_delay,
+    };
+    delay.clamp(rules.min_input_delay, rules.max_input_delay)
+}
+
+/// Starts a match between players sharing this machine. Every input is local, so a sync
+/// test session without any rollback check simply runs the frames.
+#[cfg(feature = "app")]
+pub(crate) fn start_local_session(commands: &mut Commands, rules: MatchRules, seed: u64) {
+    let session = SyncTestSession::new(NUM_PLAYERS as u32, INPUT_SIZE, MAX_PREDICTION, 0)
+        .expect("failed to create local session");
+    commands.insert_resource(MatchSeed(seed));
+    commands.insert_resource(rules);
+    commands.insert_resource(FrameCount::default());
+    commands.start_synctest_session(session);
+}
+
+#[cfg(feature = "app")]
+pub(crate) fn end_session(mut commands: Commands) {
+    commands.stop_session();
+}
+
+/// Shows a countdown while the opponent is silent. If they don't come back within
+/// `RECONNECT_WINDOW`, they forfeit: they lose all their health and the match is over,
+/// which shows the result and saves the replay like any other match end.
+#[cfg(feature = "app")]
+#[allow(clippy::too_many_arguments)]
+pub(crate) fn watch_connection(
+    mut commands: Commands,
+    egui_context: Res<EguiContext>,
+    session: Option<Res<P2PSession>>,
+    monitor: Option<Res<ConnectionMonitor>>,
+    mut round_state: ResMut<RoundState>,
+    mut recorder: ResMut<ReplayRecorder>,
+    mut query: Query<(&Player, &mut Health)>,
+    mut state: ResMut<State<GameState>>,
+) {
+    let (session, monitor) = match (session, monitor) {
+        (Some(session), Some(monitor)) => (session, monitor),
+        _ => return,
+    };
+    if matches!(*round_state, RoundState::GameOver) {
+        return;
+    }
+
+    let (handle, silence) = match monitor
+        .remotes
+        .iter()
+        .map(|(handle, addr)| (*handle, monitor.activity.silent_for(addr)))
+        .max_by_key(|(_, silence)| *silence)
+    {
+        Some(silent) => silent,
+        None => return,
+    };
+    if silence < DISCONNECT_NOTIFY {
+        return;
+    }
+    if silence >= RECONNECT_WINDOW {
+        warn!("player {} silent for {:?}, they forfeit", handle, silence);
+        recorder.forfeit(handle, session.confirmed_frame());
+        for (player, mut health) in query.iter_mut() {
+            if player.handle == handle {
+                health.amount = 0;
+            }
+        }
+        *round_state = RoundState::GameOver;
+        commands.stop_session();
+        return;
+    }
+
+    let remaining = (RECONNECT_WINDOW - silence).as_secs() + 1;
+    egui::Window::new("Connection lost")
+        .anchor(egui::Align2::CENTER_CENTER, [0.0, 0.0])
+        .collapsible(false)
+        .resizable(false)
+        .show(egui_context.ctx(), |ui| {
+            ui.heading(format!("Waiting for opponent ({remaining} s)"));
+            if ui.button("Leave").clicked() {
+                state.set(GameState::Lobby).unwrap();
+            }
+        });
+}
+
+#[cfg(feature = "app")]
+pub(crate) fn end_connection_watch(mut commands: Commands) {
+    commands.remove_resource::<ConnectionMonitor>();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn room_seeds_do_not_depend_on_the_compiler() {
+        assert_eq!(room_seed("K7QX2"), 0x1058_8d8c_04ac_a734);
+        assert_eq!(room_seed(DIRECT_ROOM), 0x1ef2_d345_70fd_a3fc);
+    }
 }
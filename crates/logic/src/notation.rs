@@ -0,0 +1,388 @@
+//! Text notation of whole matches, to read, share and paste into bug reports.
+//!
+//! ```text
+//! [Player1 "alice"]
+//! [Player2 "bob"]
+//! [Seed "42"]
+//! [Health "3"]
+//! [Ammo "0"]
+//!
+//! 1. R R {3/1 3/1}
+//! 2. F S {3/0 3/1}
+//! 3. R F {2/1 3/0}
+//! 0-1
+//! ```
+//!
+//! Tags come first, then one line per round: its number, the action of each player
+//! (`R`eload, `S`hield or `F`ire) and the health/ammo of each player after it, which a
+//! reader may leave out and which must match the rules otherwise. The last line is the
+//! result, `1-0`, `0-1`, `1/2-1/2` or `*` for a match still going on. A `Start` tag,
+//! like `[Start "2/1 1/0"]`, starts from other stats than the rules' ones. Lines starting
+//! with `;` are comments.
+
+use std::fmt;
+
+use crate::bot::Duel;
+use crate::logic::MatchRules;
+use crate::rules::{resolve_round, Action, PlayerStats, NUM_PLAYERS};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum MatchResult {
+    Won(usize),
+    Draw,
+    Unfinished,
+}
+
+impl MatchResult {
+    fn token(&self) -> &'static str {
+        match self {
+            MatchResult::Won(0) => "1-0",
+            MatchResult::Won(_) => "0-1",
+            MatchResult::Draw => "1/2-1/2",
+            MatchResult::Unfinished => "*",
+        }
+    }
+
+    fn parse(token: &str) -> Option<Self> {
+        match token {
+            "1-0" => Some(MatchResult::Won(0)),
+            "0-1" => Some(MatchResult::Won(1)),
+            "1/2-1/2" => Some(MatchResult::Draw),
+            "*" => Some(MatchResult::Unfinished),
+            _ => None,
+        }
+    }
+}
+
+/// A match, as written in the notation.
+#[derive(Clone, Debug, PartialEq)]
+pub struct MatchRecord {
+    pub players: [String; NUM_PLAYERS],
+    pub seed: Option<u64>,
+    pub rules: MatchRules,
+    /// Stats before the first round.
+    pub start: [PlayerStats; NUM_PLAYERS],
+    pub rounds: Vec<[Action; NUM_PLAYERS]>,
+    pub result: MatchResult,
+    /// Any other tags, in order.
+    pub tags: Vec<(String, String)>,
+}
+
+impl MatchRecord {
+    /// A match about to start.
+    pub fn new(rules: MatchRules) -> Self {
+        Self {
+            players: ["Player 1".to_string(), "Player 2".to_string()],
+            seed: None,
+            start: [PlayerStats {
+                health: rules.starting_health,
+                ammo: rules.starting_ammo,
+            }; NUM_PLAYERS],
+            rules,
+            rounds: vec![],
+            result: MatchResult::Unfinished,
+            tags: vec![],
+        }
+    }
+
+    /// A duel played by `play_duel`, a duel lost by forfeit counting as unfinished.
+    pub fn from_duel(rules: &MatchRules, players: [String; NUM_PLAYERS], duel: &Duel) -> Self {
+        let mut record = Self::new(rules.clone());
+        record.players = players;
+        record.rounds = duel.rounds.clone();
+        record.result = match (&duel.forfeit, duel.winner) {
+            (Some(_), _) => MatchResult::Unfinished,
+            (None, Some(winner)) => MatchResult::Won(winner),
+            (None, None) => MatchResult::Draw,
+        };
+        record
+    }
+
+    /// Stats of the players before the first round, then after every round.
+    pub fn positions(&self) -> Vec<[PlayerStats; NUM_PLAYERS]> {
+        let mut positions = vec![self.start];
+        for actions in &self.rounds {
+            positions.push(resolve_round(*positions.last().unwrap(), *actions));
+        }
+        positions
+    }
+
+    /// Result the rounds lead to, `None` while both players are alive.
+    pub fn outcome(&self) -> Option<MatchResult> {
+        let last = *self.positions().last().unwrap();
+        let alive: Vec<usize> = (0..NUM_PLAYERS).filter(|i| last[*i].health > 0).collect();
+        match alive[..] {
+            [winner] => Some(MatchResult::Won(winner)),
+            [] => Some(MatchResult::Draw),
+            _ => None,
+        }
+    }
+
+    pub fn tag(&self, name: &str) -> Option<&str> {
+        self.tags
+            .iter()
+            .find(|(tag, _)| tag == name)
+            .map(|(_, value)| value.as_str())
+    }
+
+    pub fn parse(text: &str) -> Result<Self, String> {
+        let mut record = Self::new(MatchRules::default());
+        let mut start = None;
+        let mut result = None;
+        let mut expected = vec![];
+        for (number, line) in text.lines().enumerate() {
+            let line = line.trim();
+            let error = |e: String| format!("line {}: {e}", number + 1);
+            if line.is_empty() || line.starts_with(';') {
+                continue;
+            }
+            if result.is_some() {
+                return Err(error("nothing may follow the result".to_string()));
+            }
+            if let Some(tag) = line.strip_prefix('[') {
+                if !record.rounds.is_empty() {
+                    return Err(error("tags must come before the rounds".to_string()));
+                }
+                let (name, value) = parse_tag(tag).map_err(error)?;
+                match name.as_str() {
+                    "Player1" => record.players[0] = value,
+                    "Player2" => record.players[1] = value,
+                    "Seed" => record.seed = Some(parse_number(&value).map_err(error)?),
+                    "Health" => {
+                        record.rules.starting_health = parse_number(&value).map_err(error)?
+                    }
+                    "Ammo" => record.rules.starting_ammo = parse_number(&value).map_err(error)?,
+                    "Start" => start = Some(parse_stats(&value).map_err(error)?),
+                    _ => record.tags.push((name, value)),
+                }
+                continue;
+            }
+            if let Some(parsed) = MatchResult::parse(line) {
+                result = Some(parsed);
+                continue;
+            }
+            let (actions, stats) = parse_round(line, record.rounds.len() + 1).map_err(error)?;
+            record.rounds.push(actions);
+            expected.push((number + 1, stats));
+        }
+
+        record.start = start.unwrap_or(
+            [PlayerStats {
+                health: record.rules.starting_health,
+                ammo: record.rules.starting_ammo,
+            }; NUM_PLAYERS],
+        );
+        let positions = record.positions();
+        for (round, (line, stats)) in expected.into_iter().enumerate() {
+            let reached = positions[round + 1];
+            if stats.is_some_and(|stats| stats != reached) {
+                return Err(format!(
+                    "line {line}: the rules lead to {}",
+                    format_stats(reached)
+                ));
+            }
+            if round + 1 < record.rounds.len() && reached.iter().any(|s| s.health <= 0) {
+                return Err(format!("line {line}: the match is over after this round"));
+            }
+        }
+        // the result may be left out
+        record.result = match (result, record.outcome()) {
+            (Some(result), Some(over)) if result != over => {
+                return Err(format!(
+                    "the result is {}, not {}",
+                    over.token(),
+                    result.token()
+                ))
+            }
+            (Some(result), _) => result,
+            (None, over) => over.unwrap_or(MatchResult::Unfinished),
+        };
+        Ok(record)
+    }
+}
+
+impl fmt::Display for MatchRecord {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let mut tags = vec![
+            ("Player1".to_string(), self.players[0].clone()),
+            ("Player2".to_string(), self.players[1].clone()),
+        ];
+        if let Some(seed) = self.seed {
+            tags.push(("Seed".to_string(), seed.to_string()));
+        }
+        tags.push(("Health".to_string(), self.rules.starting_health.to_string()));
+        tags.push(("Ammo".to_string(), self.rules.starting_ammo.to_string()));
+        let rules_start = PlayerStats {
+            health: self.rules.starting_health,
+            ammo: self.rules.starting_ammo,
+        };
+        if self.start.iter().any(|stats| *stats != rules_start) {
+            tags.push(("Start".to_string(), format_stats(self.start)));
+        }
+        for (name, value) in tags.iter().chain(&self.tags) {
+            let value = value.replace('\\', "\\\\").replace('"', "\\\"");
+            writeln!(f, "[{name} \"{value}\"]")?;
+        }
+        writeln!(f)?;
+        for (round, (actions, stats)) in self.rounds.iter().zip(&self.positions()[1..]).enumerate()
+        {
+            writeln!(
+                f,
+                "{}. {} {} {{{}}}",
+                round + 1,
+                action_letter(actions[0]),
+                action_letter(actions[1]),
+                format_stats(*stats)
+            )?;
+        }
+        writeln!(f, "{}", self.result.token())
+    }
+}
+
+pub fn action_letter(action: Action) -> char {
+    match action {
+        Action::Reload => 'R',
+        Action::Shield => 'S',
+        Action::Fire => 'F',
+    }
+}
+
+pub(crate) fn parse_action_letter(word: &str) -> Result<Action, String> {
+    match word {
+        "R" | "r" => Ok(Action::Reload),
+        "S" | "s" => Ok(Action::Shield),
+        "F" | "f" => Ok(Action::Fire),
+        _ => Err(format!("unknown action {word}, expected R, S or F")),
+    }
+}
+
+/// `health/ammo` of every player.
+fn format_stats(stats: [PlayerStats; NUM_PLAYERS]) -> String {
+    stats
+        .iter()
+        .map(|s| format!("{}/{}", s.health, s.ammo))
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+fn parse_stats(text: &str) -> Result<[PlayerStats; NUM_PLAYERS], String> {
+    let mut stats = [PlayerStats { health: 0, ammo: 0 }; NUM_PLAYERS];
+    let words: Vec<&str> = text.split_whitespace().collect();
+    if words.len() != NUM_PLAYERS {
+        return Err(format!(
+            "expected {NUM_PLAYERS} health/ammo pairs in {text}"
+        ));
+    }
+    for (stats, word) in stats.iter_mut().zip(words) {
+        let (health, ammo) = word
+            .split_once('/')
+            .ok_or(format!("expected health/ammo, got {word}"))?;
+        stats.health = parse_number(health)?;
+        stats.ammo = parse_number(ammo)?;
+    }
+    Ok(stats)
+}
+
+fn parse_number<T: std::str::FromStr>(text: &str) -> Result<T, String> {
+    text.parse().map_err(|_| format!("invalid number {text}"))
+}
+
+/// Reads what follows the `[` of a tag line.
+fn parse_tag(tag: &str) -> Result<(String, String), String> {
+    let tag = tag.strip_suffix(']').ok_or("tags end with ]")?;
+    let (name, value) = tag.split_once(' ').ok_or("expected [Name \"value\"]")?;
+    let value = value
+        .trim()
+        .strip_prefix('"')
+        .and_then(|v| v.strip_suffix('"'))
+        .ok_or("tag values are quoted")?;
+    let mut unescaped = String::new();
+    let mut chars = value.chars();
+    while let Some(c) = chars.next() {
+        unescaped.push(if c == '\\' {
+            chars.next().ok_or("dangling \\")?
+        } else {
+            c
+        });
+    }
+    if !name.is_empty() && name.chars().all(|c| c.is_ascii_alphanumeric()) {
+        Ok((name.to_string(), unescaped))
+    } else {
+        Err(format!("invalid tag name {name}"))
+    }
+}
+
+/// Reads the line of round `round`, its stats being optional.
+#[allow(clippy::type_complexity)]
+fn parse_round(
+    line: &str,
+    round: usize,
+) -> Result<([Action; NUM_PLAYERS], Option<[PlayerStats; NUM_PLAYERS]>), String> {
+    let (moves, stats) = match line.split_once('{') {
+        Some((moves, stats)) => {
+            let stats = stats.trim_end().strip_suffix('}').ok_or("missing }")?;
+            (moves, Some(parse_stats(stats)?))
+        }
+        None => (line, None),
+    };
+    let words: Vec<&str> = moves.split_whitespace().collect();
+    let [number, first, second] = words[..] else {
+        return Err(format!("expected `{round}. R S`, got {line}"));
+    };
+    if number != format!("{round}.") {
+        return Err(format!("expected round {round}, got {number}"));
+    }
+    Ok((
+        [parse_action_letter(first)?, parse_action_letter(second)?],
+        stats,
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MATCH: &str = r#"[Player1 "alice"]
+[Player2 "bob \"the kid\""]
+[Seed "42"]
+[Health "2"]
+[Ammo "0"]
+[Event "friendly"]
+
+1. R R {2/1 2/1}
+2. F S {2/0 2/1}
+3. R F {1/1 2/0}
+4. S R {1/1 2/1}
+5. R F {0/2 2/0}
+0-1
+"#;
+
+    #[test]
+    fn round_trip() {
+        let record = MatchRecord::parse(MATCH).unwrap();
+        assert_eq!(record.players, ["alice", "bob \"the kid\""]);
+        assert_eq!(record.seed, Some(42));
+        assert_eq!(record.rules.starting_health, 2);
+        assert_eq!(record.tag("Event"), Some("friendly"));
+        assert_eq!(record.rounds[1], [Action::Fire, Action::Shield]);
+        assert_eq!(record.result, MatchResult::Won(1));
+        assert_eq!(record.to_string(), MATCH);
+    }
+
+    #[test]
+    fn stats_are_optional_but_checked() {
+        let record = MatchRecord::parse("[Start \"1/1 1/0\"]\n1. f r\n").unwrap();
+        assert_eq!(record.result, MatchResult::Won(0));
+        assert!(record.to_string().contains("[Start \"1/1 1/0\"]"));
+        assert!(record.to_string().ends_with("1. F R {1/0 0/1}\n1-0\n"));
+        let unfinished = MatchRecord::parse("1. R S\n").unwrap();
+        assert_eq!(unfinished.result, MatchResult::Unfinished);
+
+        let wrong = MatchRecord::parse("1. R R {3/1 2/1}\n");
+        assert_eq!(wrong.unwrap_err(), "line 1: the rules lead to 3/1 3/1");
+        let after_the_end = MatchRecord::parse("[Health \"1\"]\n1. R R\n2. F R\n3. R R\n");
+        assert!(after_the_end.is_err());
+        let wrong_result = MatchRecord::parse("[Health \"1\"]\n1. R R\n2. F R\n1/2-1/2\n");
+        assert_eq!(wrong_result.unwrap_err(), "the result is 1-0, not 1/2-1/2");
+    }
+}
@@ -0,0 +1,170 @@
+use crate::rules::Action;
+
+/// Bumped on every change to the input encoding or to the game rules, peers only play
+/// against the exact same version.
+pub const PROTOCOL_VERSION: u8 = 1;
+
+/// Size of an encoded `PlayerInput`, as sent through GGRS.
+///
+/// Layout: version, action, target, ability, emote, 3 reserved bytes, then the commit
+/// as a little-endian u64. Unused fields are zero, which GGRS compresses well.
+pub const INPUT_SIZE: usize = 16;
+
+const COMMIT_OFFSET: usize = 8;
+
+/// Input of one player for one frame.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct PlayerInput {
+    /// Action picked this frame, if any.
+    pub action: Option<Action>,
+    /// Handle of the targeted player.
+    pub target: u8,
+    pub ability: u8,
+    pub emote: u8,
+    /// Hash a player commits to, to be revealed later.
+    pub commit: u64,
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum DecodeError {
+    WrongSize(usize),
+    UnsupportedVersion(u8),
+    UnknownAction(u8),
+}
+
+impl std::fmt::Display for DecodeError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            DecodeError::WrongSize(size) => {
+                write!(f, "input is {} bytes, expected {}", size, INPUT_SIZE)
+            }
+            DecodeError::UnsupportedVersion(version) => write!(
+                f,
+                "input has protocol version {}, expected {}",
+                version, PROTOCOL_VERSION
+            ),
+            DecodeError::UnknownAction(action) => write!(f, "unknown action {}", action),
+        }
+    }
+}
+
+impl std::error::Error for DecodeError {}
+
+impl PlayerInput {
+    pub fn encode(&self) -> [u8; INPUT_SIZE] {
+        let mut bytes = [0u8; INPUT_SIZE];
+        bytes[0] = PROTOCOL_VERSION;
+        bytes[1] = match self.action {
+            None => 0,
+            Some(Action::Reload) => 1,
+            Some(Action::Shield) => 2,
+            Some(Action::Fire) => 3,
+        };
+        bytes[2] = self.target;
+        bytes[3] = self.ability;
+        bytes[4] = self.emote;
+        bytes[COMMIT_OFFSET..].copy_from_slice(&self.commit.to_le_bytes());
+        bytes
+    }
+
+    /// Decodes an input received from GGRS.
+    ///
+    /// GGRS hands out all-zero buffers for players it has no input for yet (or anymore),
+    /// those decode to an empty input.
+    pub fn decode(bytes: &[u8]) -> Result<Self, DecodeError> {
+        if bytes.len() != INPUT_SIZE {
+            return Err(DecodeError::WrongSize(bytes.len()));
+        }
+        if bytes.iter().all(|b| *b == 0) {
+            return Ok(Self::default());
+        }
+        if bytes[0] != PROTOCOL_VERSION {
+            return Err(DecodeError::UnsupportedVersion(bytes[0]));
+        }
+        let action = match bytes[1] {
+            0 => None,
+            1 => Some(Action::Reload),
+            2 => Some(Action::Shield),
+            3 => Some(Action::Fire),
+            other => return Err(DecodeError::UnknownAction(other)),
+        };
+        let mut commit = [0u8; 8];
+        commit.copy_from_slice(&bytes[COMMIT_OFFSET..]);
+        Ok(Self {
+            action,
+            target: bytes[2],
+            ability: bytes[3],
+            emote: bytes[4],
+            commit: u64::from_le_bytes(commit),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn round_trip() {
+        let actions = [
+            None,
+            Some(Action::Reload),
+            Some(Action::Shield),
+            Some(Action::Fire),
+        ];
+        for action in actions {
+            let input = PlayerInput {
+                action,
+                target: 1,
+                ability: 7,
+                emote: 255,
+                commit: 0x0123_4567_89ab_cdef,
+            };
+            let bytes = input.encode();
+            assert_eq!(bytes.len(), INPUT_SIZE);
+            assert_eq!(PlayerInput::decode(&bytes), Ok(input));
+        }
+    }
+
+    #[test]
+    fn default_round_trip() {
+        let input = PlayerInput::default();
+        assert_eq!(PlayerInput::decode(&input.encode()), Ok(input));
+    }
+
+    #[test]
+    fn blank_input_is_empty() {
+        assert_eq!(
+            PlayerInput::decode(&[0; INPUT_SIZE]),
+            Ok(PlayerInput::default())
+        );
+    }
+
+    #[test]
+    fn rejects_wrong_size() {
+        assert_eq!(
+            PlayerInput::decode(&[PROTOCOL_VERSION]),
+            Err(DecodeError::WrongSize(1))
+        );
+    }
+
+    #[test]
+    fn rejects_other_versions() {
+        let mut bytes = PlayerInput::default().encode();
+        bytes[0] = PROTOCOL_VERSION + 1;
+        assert_eq!(
+            PlayerInput::decode(&bytes),
+            Err(DecodeError::UnsupportedVersion(PROTOCOL_VERSION + 1))
+        );
+    }
+
+    #[test]
+    fn rejects_unknown_actions() {
+        let mut bytes = PlayerInput::default().encode();
+        bytes[1] = 42;
+        assert_eq!(
+            PlayerInput::decode(&bytes),
+            Err(DecodeError::UnknownAction(42))
+        );
+    }
+}
@@ -0,0 +1,293 @@
+//! Puzzles: a position to win from within a few rounds, against a known opponent.
+//!
+//! Puzzles are written in the match notation, the position being the one its rounds lead
+//! to, with a few more tags:
+//!
+//! ```text
+//! [Puzzle "Copycat"]
+//! [Opponent "copycat"]
+//! [Rounds "2"]
+//! [Hint "It plays what you played last"]
+//! [Health "1"]
+//!
+//! 1. R S {1/1 1/0}
+//! 2. S R {1/1 1/1}
+//! ```
+//!
+//! `Opponent` names a built-in bot, or `Script` gives the actions the opponent plays in
+//! turn, counting from the first round of the record, like `[Script "R F S"]`. The player
+//! solving the puzzle is the first one.
+
+use std::sync::Arc;
+
+use crate::ai::{builtin, Perfect, BUILTIN_BOTS};
+use crate::bot::{Bot, RoundView};
+use crate::notation::{parse_action_letter, MatchRecord};
+use crate::rules::{resolve_round, Action, PlayerStats, NUM_PLAYERS};
+use crate::solver::{solve, SolverConfig};
+
+/// Seat of the opponent, the player plays first.
+pub const OPPONENT_SEAT: usize = 1;
+
+/// Puzzles shipped with the game, easiest first.
+pub const BUILTIN_PUZZLES: [&str; 4] = [
+    r#"[Puzzle "Finish it"]
+[Opponent "aggressive"]
+[Rounds "1"]
+[Hint "An empty gun has to be reloaded"]
+[Start "1/1 1/0"]
+"#,
+    r#"[Puzzle "Copycat"]
+[Opponent "copycat"]
+[Rounds "2"]
+[Hint "It plays what you played last"]
+[Health "1"]
+
+1. R S {1/1 1/0}
+2. S R {1/1 1/1}
+"#,
+    r#"[Puzzle "Clockwork"]
+[Script "R F S"]
+[Rounds "1"]
+[Hint "The same three actions, over and over"]
+[Health "1"]
+
+1. S R {1/0 1/1}
+2. S F {1/0 1/0}
+3. R S {1/1 1/0}
+"#,
+    r#"[Puzzle "Outgunned"]
+[Opponent "perfect"]
+[Rounds "6"]
+[Hint "It plays the best odds, but it does not know what you will do"]
+[Start "1/1 2/2"]
+"#,
+];
+
+/// Who the puzzle is played against.
+#[derive(Clone, Debug, PartialEq)]
+pub enum PuzzleOpponent {
+    /// Name of a built-in bot.
+    Bot(String),
+    Script(Vec<Action>),
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum PuzzleStatus {
+    Playing,
+    Solved,
+    Failed,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Puzzle {
+    pub name: String,
+    pub hint: Option<String>,
+    /// Rounds leading to the position, the puzzle starting after the last one.
+    pub record: MatchRecord,
+    pub opponent: PuzzleOpponent,
+    /// Rounds the player has to win in.
+    pub rounds: u32,
+}
+
+impl Puzzle {
+    pub fn parse(text: &str) -> Result<Self, String> {
+        let record = MatchRecord::parse(text)?;
+        if record.outcome().is_some() {
+            return Err("the match is already over".to_string());
+        }
+        let opponent = match (record.tag("Opponent"), record.tag("Script")) {
+            (Some(name), None) if BUILTIN_BOTS.contains(&name) => {
+                PuzzleOpponent::Bot(name.to_string())
+            }
+            (Some(name), None) => {
+                return Err(format!(
+                    "unknown opponent {name}, built-in bots: {}",
+                    BUILTIN_BOTS.join(", ")
+                ))
+            }
+            (None, Some(script)) => PuzzleOpponent::Script(
+                script
+                    .split_whitespace()
+                    .map(parse_action_letter)
+                    .collect::<Result<_, _>>()?,
+            ),
+            _ => return Err("expected either an Opponent or a Script tag".to_string()),
+        };
+        if opponent == PuzzleOpponent::Script(vec![]) {
+            return Err("the script is empty".to_string());
+        }
+        let rounds = record
+            .tag("Rounds")
+            .ok_or("missing Rounds tag")?
+            .parse()
+            .map_err(|_| "Rounds is not a number")?;
+        if rounds == 0 {
+            return Err("Rounds must be at least 1".to_string());
+        }
+        Ok(Self {
+            name: record.tag("Puzzle").unwrap_or("Puzzle").to_string(),
+            hint: record.tag("Hint").map(str::to_string),
+            record,
+            opponent,
+            rounds,
+        })
+    }
+
+    /// The puzzles in `BUILTIN_PUZZLES`.
+    pub fn builtin() -> Vec<Puzzle> {
+        BUILTIN_PUZZLES
+            .iter()
+            .map(|text| Puzzle::parse(text).expect("built-in puzzles are valid"))
+            .collect()
+    }
+
+    /// Stats of the players when the puzzle starts.
+    pub fn start(&self) -> [PlayerStats; NUM_PLAYERS] {
+        *self.record.positions().last().unwrap()
+    }
+
+    /// The opponent, ready for its first round. `perfect` plays the equilibrium of the
+    /// puzzle's rules and stats.
+    pub fn opponent(&self, seed: u64) -> Box<dyn Bot + Send> {
+        match &self.opponent {
+            PuzzleOpponent::Script(actions) => Box::new(Script(actions.clone())),
+            PuzzleOpponent::Bot(name) if name == "perfect" => {
+                let start = self.start();
+                let mut rules = self.record.rules.clone();
+                rules.starting_health = start.iter().map(|s| s.health).fold(1, i32::max);
+                let most_ammo = start.iter().map(|s| s.ammo).max().unwrap_or(0);
+                let config = SolverConfig {
+                    max_ammo: (most_ammo + self.rounds as i32)
+                        .max(SolverConfig::default().max_ammo),
+                    ..Default::default()
+                };
+                Box::new(Perfect::new(Arc::new(solve(&rules, &config)), seed))
+            }
+            PuzzleOpponent::Bot(name) => builtin(name, seed).expect("checked when parsing"),
+        }
+    }
+
+    /// What the opponent knows after the puzzle's rounds `played`.
+    pub fn opponent_view(&self, played: &[[Action; NUM_PLAYERS]]) -> RoundView {
+        let stats = self.position(played);
+        let player = (OPPONENT_SEAT + 1) % NUM_PLAYERS;
+        let history = self.record.rounds.iter().chain(played);
+        RoundView {
+            round: (self.record.rounds.len() + played.len()) as u32 + 1,
+            me: stats[OPPONENT_SEAT],
+            opponent: stats[player],
+            last: history
+                .last()
+                .map(|last| (last[OPPONENT_SEAT], last[player])),
+        }
+    }
+
+    /// Stats after the puzzle's rounds `played`.
+    pub fn position(&self, played: &[[Action; NUM_PLAYERS]]) -> [PlayerStats; NUM_PLAYERS] {
+        played.iter().fold(self.start(), |stats, actions| {
+            resolve_round(stats, *actions)
+        })
+    }
+
+    /// Solved when the opponent is the only one down, failed on anything else ending the
+    /// match or once the rounds run out.
+    pub fn status(&self, played: &[[Action; NUM_PLAYERS]]) -> PuzzleStatus {
+        let stats = self.position(played);
+        let alive = stats.map(|stats| stats.health > 0);
+        if !alive[OPPONENT_SEAT] && alive.iter().filter(|alive| **alive).count() == 1 {
+            PuzzleStatus::Solved
+        } else if alive.contains(&false) || played.len() >= self.rounds as usize {
+            PuzzleStatus::Failed
+        } else {
+            PuzzleStatus::Playing
+        }
+    }
+}
+
+/// Plays its actions in turn, round after round.
+struct Script(Vec<Action>);
+
+impl Bot for Script {
+    fn name(&self) -> String {
+        "script".to_string()
+    }
+
+    fn choose(&mut self, view: &RoundView) -> Result<Action, String> {
+        Ok(self.0[(view.round as usize - 1) % self.0.len()])
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Actions of the player winning the rest of `puzzle` after `played` against the
+    /// opponent seeded with `seed`, found by trying them all.
+    fn winning_line(
+        puzzle: &Puzzle,
+        seed: u64,
+        played: &mut Vec<[Action; NUM_PLAYERS]>,
+    ) -> Option<Vec<Action>> {
+        match puzzle.status(played) {
+            PuzzleStatus::Solved => return Some(vec![]),
+            PuzzleStatus::Failed => return None,
+            PuzzleStatus::Playing => {}
+        }
+        // the opponent may remember past rounds, so it replays them all
+        let mut opponent = puzzle.opponent(seed);
+        opponent.new_game().unwrap();
+        for round in 0..played.len() {
+            opponent
+                .choose(&puzzle.opponent_view(&played[..round]))
+                .unwrap();
+        }
+        let answer = opponent.choose(&puzzle.opponent_view(played)).unwrap();
+        for action in Action::ALL {
+            played.push([action, answer]);
+            let line = winning_line(puzzle, seed, played);
+            played.pop();
+            if let Some(mut line) = line {
+                line.insert(0, action);
+                return Some(line);
+            }
+        }
+        None
+    }
+
+    #[test]
+    fn builtin_puzzles_can_be_solved() {
+        for puzzle in Puzzle::builtin() {
+            assert_eq!(puzzle.status(&[]), PuzzleStatus::Playing, "{}", puzzle.name);
+            // nothing is sure to beat the equilibrium, it can only be played well
+            if puzzle.opponent == PuzzleOpponent::Bot("perfect".to_string()) {
+                continue;
+            }
+            let line = winning_line(&puzzle, 0, &mut vec![]);
+            assert!(line.is_some(), "{} cannot be solved", puzzle.name);
+        }
+    }
+
+    #[test]
+    fn puzzles_start_after_their_rounds() {
+        let puzzle = Puzzle::parse(BUILTIN_PUZZLES[2]).unwrap();
+        assert_eq!(
+            puzzle.opponent,
+            PuzzleOpponent::Script(vec![Action::Reload, Action::Fire, Action::Shield])
+        );
+        assert_eq!(puzzle.start()[1], PlayerStats { health: 1, ammo: 0 });
+        let view = puzzle.opponent_view(&[]);
+        assert_eq!(view.round, 4);
+        assert_eq!(view.last, Some((Action::Shield, Action::Reload)));
+        // the script starts over, reloading in round 4
+        let played = [[Action::Shield, Action::Reload]];
+        assert_eq!(puzzle.status(&played), PuzzleStatus::Failed);
+        let played = [[Action::Fire, Action::Reload]];
+        assert_eq!(puzzle.status(&played), PuzzleStatus::Solved);
+
+        assert!(Puzzle::parse("[Rounds \"1\"]\n").is_err());
+        assert!(Puzzle::parse("[Opponent \"nobody\"]\n[Rounds \"1\"]\n").is_err());
+        let over = "[Opponent \"turtle\"]\n[Rounds \"1\"]\n[Start \"1/1 0/0\"]\n";
+        assert!(Puzzle::parse(over).is_err());
+    }
+}
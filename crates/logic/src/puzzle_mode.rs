@@ -0,0 +1,186 @@
+use std::sync::Mutex;
+
+use bevy::prelude::*;
+use bevy_egui::{egui, EguiContext};
+
+use crate::bot::{BotTurns, SeatedBot};
+use crate::input::InputDevices;
+use crate::logic::{
+    current_action, ActionFire, ActionReload, Ammunition, Health, MatchStart, Player, RoundState,
+};
+use crate::network::start_local_session;
+use crate::puzzle::{Puzzle, PuzzleStatus, OPPONENT_SEAT};
+use crate::rules::{Action, PlayerStats, NUM_PLAYERS};
+use crate::sim::Phase;
+use crate::states::GameState;
+
+pub(crate) struct PuzzleModePlugin;
+
+impl Plugin for PuzzleModePlugin {
+    fn build(&self, app: &mut App) {
+        app.add_system_set(
+            SystemSet::on_update(GameState::InGame)
+                .with_system(drive_puzzle)
+                .with_system(puzzle_display),
+        );
+        app.add_system_set(SystemSet::on_exit(GameState::InGame).with_system(end_puzzle));
+    }
+}
+
+/// Puzzle being played, its opponent seated like a bot.
+pub(crate) struct PuzzleRun {
+    puzzle: Puzzle,
+    /// Only the puzzle systems use it, the mutex makes it a resource.
+    opponent: Mutex<SeatedBot>,
+    /// Turns of the opponent, which continue the puzzle's record.
+    turns: BotTurns,
+    /// Rounds played since the puzzle's position.
+    played: Vec<[Action; NUM_PLAYERS]>,
+    show_hint: bool,
+    error: Option<String>,
+}
+
+/// Starts a local match from the position of `puzzle`, against its opponent.
+pub(crate) fn start_puzzle(
+    commands: &mut Commands,
+    devices: &mut InputDevices,
+    puzzle: Puzzle,
+) -> Result<(), String> {
+    let seed = rand::random();
+    let mut opponent = puzzle.opponent(seed);
+    opponent.new_game()?;
+    info!("starting puzzle {}", puzzle.name);
+    devices.seat_bot(OPPONENT_SEAT);
+    start_local_session(commands, puzzle.record.rules.clone(), seed);
+    commands.insert_resource(MatchStart(puzzle.start()));
+    let record = &puzzle.record.rounds;
+    let turns = BotTurns::resuming(record.len() as u32, record.last().copied());
+    commands.insert_resource(PuzzleRun {
+        puzzle,
+        opponent: Mutex::new(SeatedBot::Builtin(opponent)),
+        turns,
+        played: vec![],
+        show_hint: false,
+        error: None,
+    });
+    Ok(())
+}
+
+/// Records the rounds as they end and has the opponent pick its action for every new one.
+fn drive_puzzle(
+    run: Option<ResMut<PuzzleRun>>,
+    round_state: Res<RoundState>,
+    mut devices: ResMut<InputDevices>,
+    query: Query<(&Player, &Health, &Ammunition, &ActionReload, &ActionFire)>,
+) {
+    let mut run = match run {
+        Some(run) => run,
+        None => return,
+    };
+    if run.error.is_some() || run.puzzle.status(&run.played) != PuzzleStatus::Playing {
+        return;
+    }
+    // the flags still hold the actions of the round just resolved
+    let mut stats = [PlayerStats { health: 0, ammo: 0 }; NUM_PLAYERS];
+    let mut actions = [Action::Shield; NUM_PLAYERS];
+    for (player, health, ammo, reload, fire) in query.iter() {
+        stats[player.handle] = PlayerStats {
+            health: health.amount,
+            ammo: ammo.amount,
+        };
+        actions[player.handle] = current_action(reload, fire);
+    }
+
+    let run = &mut *run;
+    match *round_state {
+        RoundState::GameOver => {
+            run.played.push(actions);
+            return;
+        }
+        // the players may not be spawned yet
+        RoundState::NotReady => return,
+        _ => {}
+    }
+    let played = run.turns.played();
+    let opponent = run.opponent.get_mut().unwrap();
+    match run.turns.update(
+        opponent,
+        OPPONENT_SEAT,
+        Phase::of(*round_state),
+        stats,
+        actions,
+    ) {
+        Ok(action) => devices.bot_action = action,
+        Err(e) => {
+            warn!("puzzle opponent error: {}", e);
+            run.error = Some(e);
+        }
+    }
+    if run.turns.played() > played {
+        run.played.push(actions);
+        // the opponent is done once the puzzle is
+        if run.puzzle.status(&run.played) != PuzzleStatus::Playing {
+            devices.bot_action = None;
+        }
+    }
+}
+
+fn puzzle_display(
+    egui_context: Res<EguiContext>,
+    run: Option<ResMut<PuzzleRun>>,
+    mut state: ResMut<State<GameState>>,
+) {
+    let mut run = match run {
+        Some(run) => run,
+        None => return,
+    };
+    let status = run.puzzle.status(&run.played);
+    let mut back = false;
+    egui::Window::new("Puzzle")
+        .anchor(egui::Align2::RIGHT_TOP, [-10.0, 10.0])
+        .collapsible(false)
+        .resizable(false)
+        .show(egui_context.ctx(), |ui| {
+            ui.heading(&run.puzzle.name);
+            ui.label(format!("Win within {} rounds", run.puzzle.rounds));
+            if let Some(e) = &run.error {
+                ui.colored_label(egui::Color32::RED, format!("The opponent stopped: {e}"));
+            }
+            match status {
+                PuzzleStatus::Playing => {
+                    let left = run.puzzle.rounds as usize - run.played.len();
+                    ui.label(format!("Rounds left: {left}"));
+                }
+                PuzzleStatus::Solved => {
+                    ui.colored_label(egui::Color32::GREEN, "Solved!");
+                }
+                PuzzleStatus::Failed => {
+                    ui.colored_label(egui::Color32::RED, "Failed");
+                }
+            }
+            if let Some(hint) = run.puzzle.hint.clone() {
+                if run.show_hint {
+                    ui.label(hint);
+                } else if ui.button("Hint").clicked() {
+                    run.show_hint = true;
+                }
+            }
+            if status != PuzzleStatus::Playing {
+                back = ui.button("Back to lobby").clicked();
+            }
+        });
+    if back {
+        state.set(GameState::Lobby).unwrap();
+    }
+}
+
+fn end_puzzle(
+    mut commands: Commands,
+    run: Option<Res<PuzzleRun>>,
+    mut devices: ResMut<InputDevices>,
+) {
+    if run.is_some() {
+        commands.remove_resource::<PuzzleRun>();
+        devices.unseat_bot();
+    }
+}
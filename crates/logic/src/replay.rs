@@ -0,0 +1,470 @@
+#[cfg(feature = "app")]
+use bevy::prelude::*;
+#[cfg(feature = "app")]
+use bevy_egui::{egui, EguiContext};
+use ggrs::Frame;
+#[cfg(feature = "app")]
+use ggrs::{GameInput, P2PSession, SyncTestSession};
+use serde::{Deserialize, Serialize};
+
+#[cfg(feature = "app")]
+use crate::logic::{reset_players, FrameCount, MatchSeed, RoundState};
+use crate::logic::{MatchRules, MatchStart};
+use crate::notation::{MatchRecord, MatchResult};
+use crate::rules::{Action, PlayerStats, NUM_PLAYERS};
+use crate::sim::{MatchSim, Phase};
+#[cfg(feature = "app")]
+use crate::states::GameState;
+#[cfg(feature = "app")]
+use crate::storage;
+
+/// Bumped whenever a change makes recorded inputs play out differently.
+pub const REPLAY_VERSION: u32 = 2;
+
+/// Stage label for the stage playing replays back.
+#[cfg(feature = "app")]
+pub const REPLAY_UPDATE: &str = "replay_update";
+
+#[cfg(feature = "app")]
+const LAST_REPLAY_KEY: &str = "last_replay.json";
+#[cfg(feature = "app")]
+const LAST_MATCH_KEY: &str = "last_match.txt";
+#[cfg(feature = "app")]
+const REPLAY_FPS: f32 = 60.0;
+#[cfg(feature = "app")]
+const REPLAY_SPEEDS: [f32; 4] = [0.5, 1.0, 2.0, 4.0];
+
+#[derive(Clone, Serialize, Deserialize)]
+pub struct ReplayPlayer {
+    pub handle: usize,
+    /// Whether this player was the one playing on the machine that saved the replay.
+    pub local: bool,
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+pub struct Replay {
+    pub version: u32,
+    pub rules: MatchRules,
+    pub seed: u64,
+    /// Stats the players started with, when not the rules' ones.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub start: Option<[PlayerStats; NUM_PLAYERS]>,
+    pub players: Vec<ReplayPlayer>,
+    /// Input buffer of every player, for every frame of the match.
+    pub inputs: Vec<Vec<Vec<u8>>>,
+    /// Handle of the player who left before the match was over, losing it.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub forfeit: Option<usize>,
+}
+
+impl Replay {
+    pub fn from_json(json: &str) -> Result<Self, String> {
+        let replay: Replay = serde_json::from_str(json).map_err(|e| e.to_string())?;
+        if replay.version != REPLAY_VERSION {
+            return Err(format!(
+                "replay version {} is not supported (expected {})",
+                replay.version, REPLAY_VERSION
+            ));
+        }
+        Ok(replay)
+    }
+
+    fn match_start(&self) -> MatchStart {
+        self.start
+            .map_or_else(|| MatchStart::from_rules(&self.rules), MatchStart)
+    }
+
+    /// Rounds the recorded inputs play out, simulated without the Bevy app.
+    pub fn rounds(&self) -> Result<Vec<ReplayRound>, String> {
+        let mut rounds = vec![];
+        let mut sim = MatchSim::new(self.match_start().0);
+        for inputs in &self.inputs {
+            if inputs.len() != NUM_PLAYERS {
+                return Err(format!("frame {} has {} inputs", sim.frame(), inputs.len()));
+            }
+            let (frame, players) = (sim.frame(), sim.players());
+            if let Some(actions) = sim.advance(&self.rules, inputs) {
+                rounds.push(ReplayRound {
+                    frame,
+                    players,
+                    actions,
+                });
+            }
+            if sim.phase() == Phase::Over {
+                break;
+            }
+        }
+        Ok(rounds)
+    }
+
+    /// The match in notation, the player who saved the replay marked as "You".
+    pub fn to_record(&self) -> Result<MatchRecord, String> {
+        let mut record = MatchRecord::new(self.rules.clone());
+        record.seed = Some(self.seed);
+        record.start = self.match_start().0;
+        for player in &self.players {
+            if player.local && self.players.iter().filter(|p| p.local).count() == 1 {
+                record.players[player.handle] = "You".to_string();
+            }
+        }
+        record.rounds = self.rounds()?.iter().map(|round| round.actions).collect();
+        record.result = match (record.outcome(), self.forfeit) {
+            (Some(result), _) => result,
+            (None, Some(handle)) => MatchResult::Won((handle + 1) % NUM_PLAYERS),
+            (None, None) => MatchResult::Unfinished,
+        };
+        Ok(record)
+    }
+}
+
+/// A round of a replay.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct ReplayRound {
+    /// Frame the round was resolved on.
+    pub frame: Frame,
+    /// Stats before the round.
+    pub players: [PlayerStats; NUM_PLAYERS],
+    pub actions: [Action; NUM_PLAYERS],
+}
+
+/// Inputs of the running match, indexed by frame. Rollbacks record the frames they
+/// resimulate again, so only confirmed inputs are left once the match is over.
+#[cfg(feature = "app")]
+#[derive(Default)]
+pub(crate) struct ReplayRecorder {
+    inputs: Vec<Vec<Vec<u8>>>,
+    forfeit: Option<usize>,
+    saved: bool,
+}
+
+#[cfg(feature = "app")]
+impl ReplayRecorder {
+    /// Inputs of the last `frames` frames recorded.
+    pub(crate) fn recent_inputs(&self, frames: usize) -> &[Vec<Vec<u8>>] {
+        &self.inputs[self.inputs.len().saturating_sub(frames)..]
+    }
+
+    /// Ends the match with `handle` leaving it. Inputs after the `confirmed` frame are
+    /// only predictions of theirs, so they are dropped.
+    pub(crate) fn forfeit(&mut self, handle: usize, confirmed: Frame) {
+        self.inputs.truncate((confirmed + 1).max(0) as usize);
+        self.forfeit = Some(handle);
+    }
+
+    /// Handle of the player who left the match, if one did.
+    pub(crate) fn forfeited(&self) -> Option<usize> {
+        self.forfeit
+    }
+
+    /// The rounds recorded so far, in notation.
+    pub(crate) fn notation(&self, rules: &MatchRules, seed: u64) -> Result<String, String> {
+        let replay = Replay {
+            version: REPLAY_VERSION,
+            rules: rules.clone(),
+            seed,
+            start: None,
+            players: vec![],
+            inputs: self.inputs.clone(),
+            forfeit: self.forfeit,
+        };
+        replay.to_record().map(|record| record.to_string())
+    }
+}
+
+#[cfg(feature = "app")]
+pub(crate) struct ReplayPlayback {
+    replay: Replay,
+    pub(crate) paused: bool,
+    pub(crate) speed: f32,
+    seek_to: Option<Frame>,
+}
+
+#[cfg(feature = "app")]
+impl ReplayPlayback {
+    pub(crate) fn new(replay: Replay) -> Self {
+        Self {
+            replay,
+            paused: false,
+            speed: 1.0,
+            seek_to: None,
+        }
+    }
+
+    fn len(&self) -> Frame {
+        self.replay.inputs.len() as Frame
+    }
+}
+
+#[cfg(feature = "app")]
+pub(crate) fn load_last_replay() -> Result<Replay, String> {
+    let json = storage::load(LAST_REPLAY_KEY).ok_or("no replay saved yet")?;
+    Replay::from_json(&json)
+}
+
+/// Sets up the resources a match needs and plays `replay` back instead of a GGRS session.
+#[cfg(feature = "app")]
+pub(crate) fn start_playback(commands: &mut Commands, replay: Replay) {
+    commands.insert_resource(replay.rules.clone());
+    commands.insert_resource(MatchSeed(replay.seed));
+    commands.insert_resource(replay.match_start());
+    commands.insert_resource(FrameCount::default());
+    commands.insert_resource(ReplayPlayback::new(replay));
+}
+
+#[cfg(feature = "app")]
+pub(crate) fn start_recording(mut commands: Commands) {
+    commands.insert_resource(ReplayRecorder::default());
+}
+
+#[cfg(feature = "app")]
+pub(crate) fn stop_playback(mut commands: Commands) {
+    commands.remove_resource::<ReplayPlayback>();
+}
+
+#[cfg(feature = "app")]
+pub(crate) fn record_inputs(
+    frame_count: Res<FrameCount>,
+    inputs: Res<Vec<GameInput>>,
+    mut recorder: ResMut<ReplayRecorder>,
+) {
+    let frame = frame_count.frame as usize;
+    recorder.inputs.truncate(frame);
+    recorder
+        .inputs
+        .push(inputs.iter().map(|input| input.buffer.clone()).collect());
+}
+
+#[cfg(feature = "app")]
+#[allow(clippy::too_many_arguments)]
+pub(crate) fn save_replay(
+    round_state: Res<RoundState>,
+    rules: Res<MatchRules>,
+    seed: Res<MatchSeed>,
+    start: Option<Res<MatchStart>>,
+    session: Option<Res<P2PSession>>,
+    local_session: Option<Res<SyncTestSession>>,
+    playback: Option<Res<ReplayPlayback>>,
+    mut recorder: ResMut<ReplayRecorder>,
+) {
+    if recorder.saved || playback.is_some() || !matches!(*round_state, RoundState::GameOver) {
+        return;
+    }
+    recorder.saved = true;
+
+    let local_handle = session.and_then(|s| s.local_player_handle());
+    let replay = Replay {
+        version: REPLAY_VERSION,
+        rules: rules.clone(),
+        seed: seed.0,
+        start: start.map(|start| start.0),
+        players: (0..2)
+            .map(|handle| ReplayPlayer {
+                handle,
+                local: local_session.is_some() || Some(handle) == local_handle,
+            })
+            .collect(),
+        inputs: recorder.inputs.clone(),
+        forfeit: recorder.forfeit,
+    };
+    let json = serde_json::to_string(&replay).unwrap();
+    match storage::save(LAST_REPLAY_KEY, &json) {
+        Ok(()) => info!("replay saved ({} frames)", replay.inputs.len()),
+        Err(e) => warn!("could not save replay: {}", e),
+    }
+    let notation = replay.to_record().map(|record| record.to_string());
+    if let Err(e) = notation.and_then(|notation| storage::save(LAST_MATCH_KEY, &notation)) {
+        warn!("could not save the match notation: {}", e);
+    }
+    #[cfg(not(target_arch = "wasm32"))]
+    {
+        let secs = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .map_or(0, |d| d.as_secs());
+        if let Err(e) = storage::save(&format!("replay-{secs}.json"), &json) {
+            warn!("could not archive replay: {}", e);
+        }
+    }
+}
+
+/// Runs the rollback schedule with the recorded inputs, in place of the GGRS stage.
+#[cfg(feature = "app")]
+pub(crate) struct ReplayStage {
+    schedule: Schedule,
+    /// Frames owed to the playback, accumulated from the elapsed time and speed.
+    accumulator: f32,
+}
+
+#[cfg(feature = "app")]
+impl ReplayStage {
+    pub(crate) fn new(schedule: Schedule) -> Self {
+        Self {
+            schedule,
+            accumulator: 0.0,
+        }
+    }
+
+    fn rewind(&mut self, world: &mut World, start: &MatchStart) {
+        reset_players(world, start);
+        world.insert_resource(RoundState::NotReady);
+        world.insert_resource(FrameCount::default());
+    }
+
+    fn step(&mut self, world: &mut World, frame: Frame) {
+        let buffers = world
+            .get_resource::<ReplayPlayback>()
+            .and_then(|playback| playback.replay.inputs.get(frame as usize).cloned())
+            .unwrap_or_default();
+        let inputs: Vec<GameInput> = buffers
+            .into_iter()
+            .map(|buffer| GameInput {
+                frame,
+                size: buffer.len(),
+                buffer,
+            })
+            .collect();
+        world.insert_resource(inputs);
+        self.schedule.run_once(world);
+        world.remove_resource::<Vec<GameInput>>();
+    }
+}
+
+#[cfg(feature = "app")]
+impl Stage for ReplayStage {
+    fn run(&mut self, world: &mut World) {
+        let delta = world
+            .get_resource::<Time>()
+            .map_or(0.0, |time| time.delta_seconds());
+        let current = world
+            .get_resource::<FrameCount>()
+            .map_or(0, |frame_count| frame_count.frame);
+
+        let (target, start) = match world.get_resource_mut::<ReplayPlayback>() {
+            Some(mut playback) => {
+                let target = match playback.seek_to.take() {
+                    Some(seek) => seek,
+                    None if playback.paused => return,
+                    None => {
+                        self.accumulator += delta * REPLAY_FPS * playback.speed;
+                        let steps = self.accumulator.floor();
+                        self.accumulator -= steps;
+                        current + steps as Frame
+                    }
+                };
+                (
+                    target.clamp(0, playback.len()),
+                    playback.replay.match_start(),
+                )
+            }
+            None => {
+                self.accumulator = 0.0;
+                return;
+            }
+        };
+
+        let mut frame = current;
+        if target < current {
+            self.rewind(world, &start);
+            frame = 0;
+        }
+        while frame < target {
+            self.step(world, frame);
+            frame += 1;
+        }
+    }
+}
+
+#[cfg(feature = "app")]
+pub(crate) fn replay_controls(
+    egui_context: Res<EguiContext>,
+    frame_count: Option<Res<FrameCount>>,
+    playback: Option<ResMut<ReplayPlayback>>,
+    mut state: ResMut<State<GameState>>,
+) {
+    let mut playback = match playback {
+        Some(playback) => playback,
+        None => return,
+    };
+    let mut frame = frame_count.map_or(0, |frame_count| frame_count.frame);
+    let len = playback.len();
+
+    egui::Window::new("Replay")
+        .anchor(egui::Align2::CENTER_BOTTOM, [0.0, -10.0])
+        .collapsible(false)
+        .resizable(false)
+        .show(egui_context.ctx(), |ui| {
+            ui.horizontal(|ui| {
+                let label = if playback.paused { "Play" } else { "Pause" };
+                if ui.button(label).clicked() {
+                    playback.paused = !playback.paused;
+                }
+                for speed in REPLAY_SPEEDS {
+                    if ui
+                        .selectable_label(playback.speed == speed, format!("{speed}x"))
+                        .clicked()
+                    {
+                        playback.speed = speed;
+                    }
+                }
+                if ui.button("Back to lobby").clicked() {
+                    state.set(GameState::Lobby).unwrap();
+                }
+            });
+            if ui
+                .add(egui::Slider::new(&mut frame, 0..=len).text("frame"))
+                .changed()
+            {
+                playback.seek_to = Some(frame);
+            }
+        });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::protocol::PlayerInput;
+
+    fn input(action: Option<Action>) -> Vec<u8> {
+        PlayerInput {
+            action,
+            ..Default::default()
+        }
+        .encode()
+        .to_vec()
+    }
+
+    #[test]
+    fn rounds_follow_the_timers() {
+        let rules = MatchRules {
+            starting_health: 1,
+            decision_frames: 2,
+            display_frames: 1,
+            ..Default::default()
+        };
+        let mut inputs = vec![vec![input(None), input(None)]; 8];
+        inputs[0] = vec![input(Some(Action::Reload)), input(Some(Action::Shield))];
+        // shown while the first round is displayed, too late to count
+        inputs[2] = vec![input(Some(Action::Fire)), input(Some(Action::Fire))];
+        inputs[4] = vec![input(Some(Action::Fire)), input(Some(Action::Reload))];
+        let replay = Replay {
+            version: REPLAY_VERSION,
+            rules,
+            seed: 0,
+            start: None,
+            players: vec![],
+            inputs,
+            forfeit: None,
+        };
+        let rounds = replay.rounds().unwrap();
+        assert_eq!(
+            rounds
+                .iter()
+                .map(|r| (r.frame, r.actions))
+                .collect::<Vec<_>>(),
+            vec![
+                (3, [Action::Reload, Action::Shield]),
+                (6, [Action::Fire, Action::Reload])
+            ]
+        );
+        assert_eq!(rounds[1].players[0], PlayerStats { health: 1, ammo: 1 });
+    }
+}
@@ -0,0 +1,132 @@
+use serde::{Deserialize, Serialize};
+
+/// What a player does during a round.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub enum Action {
+    /// Gain one ammo, vulnerable to attacks.
+    Reload,
+    /// Invulnerable to attacks.
+    Shield,
+    /// Spend one ammo, the opponent loses 1 HP unless shielding or firing too.
+    Fire,
+}
+
+impl Action {
+    pub const ALL: [Action; 3] = [Action::Reload, Action::Shield, Action::Fire];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Action::Reload => "Reload",
+            Action::Shield => "Shield",
+            Action::Fire => "Fire",
+        }
+    }
+}
+
+/// Players in a match.
+pub const NUM_PLAYERS: usize = 2;
+
+/// Health and ammo of a player, carried from round to round.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct PlayerStats {
+    pub health: i32,
+    pub ammo: i32,
+}
+
+/// Resolves a round where player `i` plays `actions[i]`, returning everyone's new stats.
+/// Reloading players gain one ammo but are the only ones who can be hit. Firing spends one
+/// ammo and hits the next player, firing without ammo does nothing.
+pub fn resolve_round(
+    players: [PlayerStats; NUM_PLAYERS],
+    actions: [Action; NUM_PLAYERS],
+) -> [PlayerStats; NUM_PLAYERS] {
+    let mut next = players;
+    let mut hit = [false; NUM_PLAYERS];
+    for (i, action) in actions.iter().enumerate() {
+        match action {
+            Action::Reload => next[i].ammo += 1,
+            Action::Shield => {}
+            Action::Fire => {
+                if next[i].ammo <= 0 {
+                    continue;
+                }
+                next[i].ammo -= 1;
+                let target = (i + 1) % NUM_PLAYERS;
+                if actions[target] == Action::Reload {
+                    hit[target] = true;
+                }
+            }
+        }
+    }
+    for (player, hit) in next.iter_mut().zip(hit) {
+        if hit {
+            player.health -= 1;
+        }
+    }
+    next
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use proptest::prelude::*;
+
+    fn action() -> impl Strategy<Value = Action> {
+        prop::sample::select(Action::ALL.to_vec())
+    }
+
+    fn actions() -> impl Strategy<Value = [Action; NUM_PLAYERS]> {
+        [action(), action()]
+    }
+
+    fn stats() -> impl Strategy<Value = PlayerStats> {
+        (1..=5, 0..=5).prop_map(|(health, ammo)| PlayerStats { health, ammo })
+    }
+
+    /// Checks what must hold for any round, whatever happened before.
+    fn check_round(
+        before: [PlayerStats; NUM_PLAYERS],
+        actions: [Action; NUM_PLAYERS],
+    ) -> Result<[PlayerStats; NUM_PLAYERS], TestCaseError> {
+        let after = resolve_round(before, actions);
+        let mut hp_lost = 0;
+        for i in 0..NUM_PLAYERS {
+            prop_assert!(after[i].ammo >= 0, "negative ammo for {}", i);
+            let lost = before[i].health - after[i].health;
+            prop_assert!((0..=1).contains(&lost), "{} lost {} HP", i, lost);
+            hp_lost += lost;
+            match actions[i] {
+                Action::Reload => prop_assert_eq!(after[i].ammo, before[i].ammo + 1),
+                Action::Shield => prop_assert_eq!(after[i].health, before[i].health),
+                Action::Fire => {}
+            }
+        }
+        let firing = actions.iter().filter(|a| **a == Action::Fire).count() as i32;
+        prop_assert!(hp_lost <= firing, "{} HP lost to {} shots", hp_lost, firing);
+        if actions.iter().all(|a| *a == Action::Fire) {
+            prop_assert_eq!(hp_lost, 0);
+        }
+        Ok(after)
+    }
+
+    proptest! {
+        #[test]
+        fn any_round_keeps_the_invariants(
+            before in [stats(), stats()],
+            actions in actions(),
+        ) {
+            check_round(before, actions)?;
+        }
+
+        #[test]
+        fn any_match_keeps_the_invariants(rounds in prop::collection::vec(actions(), 0..50)) {
+            let mut players = [PlayerStats { health: 3, ammo: 0 }; NUM_PLAYERS];
+            for actions in rounds {
+                if players.iter().any(|p| p.health <= 0) {
+                    break;
+                }
+                players = check_round(players, actions)?;
+            }
+        }
+    }
+}
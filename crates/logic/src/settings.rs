@@ -0,0 +1,140 @@
+use bevy::prelude::*;
+use bevy_egui::{egui, EguiContext};
+use serde::{Deserialize, Serialize};
+
+use crate::rules::Action;
+use crate::storage;
+
+const KEY_BINDINGS_KEY: &str = "key_bindings.json";
+
+pub struct SettingsPlugin;
+
+impl Plugin for SettingsPlugin {
+    fn build(&self, app: &mut App) {
+        app.insert_resource(KeyBindings::load());
+        app.insert_resource(SettingsUi::default());
+        app.add_system(settings_ui);
+    }
+}
+
+/// Keyboard key picking each action, persisted across restarts.
+#[derive(Clone, PartialEq, Serialize, Deserialize)]
+pub struct KeyBindings {
+    pub reload: KeyCode,
+    pub shield: KeyCode,
+    pub fire: KeyCode,
+}
+
+impl Default for KeyBindings {
+    fn default() -> Self {
+        Self {
+            reload: KeyCode::R,
+            shield: KeyCode::S,
+            fire: KeyCode::F,
+        }
+    }
+}
+
+impl KeyBindings {
+    /// Saved bindings, or the defaults if there are none or they can't be read.
+    fn load() -> Self {
+        storage::load(KEY_BINDINGS_KEY)
+            .and_then(|json| match serde_json::from_str(&json) {
+                Ok(bindings) => Some(bindings),
+                Err(e) => {
+                    warn!("ignoring saved key bindings: {}", e);
+                    None
+                }
+            })
+            .unwrap_or_default()
+    }
+
+    fn save(&self) {
+        let json = serde_json::to_string(self).unwrap();
+        if let Err(e) = storage::save(KEY_BINDINGS_KEY, &json) {
+            warn!("could not save key bindings: {}", e);
+        }
+    }
+
+    pub fn key(&self, action: Action) -> KeyCode {
+        match action {
+            Action::Reload => self.reload,
+            Action::Shield => self.shield,
+            Action::Fire => self.fire,
+        }
+    }
+
+    fn key_mut(&mut self, action: Action) -> &mut KeyCode {
+        match action {
+            Action::Reload => &mut self.reload,
+            Action::Shield => &mut self.shield,
+            Action::Fire => &mut self.fire,
+        }
+    }
+
+    /// Binds `key` to `action`, the action previously using `key` gets the old key of `action`.
+    pub fn rebind(&mut self, action: Action, key: KeyCode) {
+        let previous = self.key(action);
+        if let Some(other) = Action::ALL.into_iter().find(|a| self.key(*a) == key) {
+            *self.key_mut(other) = previous;
+        }
+        *self.key_mut(action) = key;
+    }
+}
+
+#[derive(Default)]
+pub(crate) struct SettingsUi {
+    pub(crate) open: bool,
+    /// Action waiting for a key press to be rebound.
+    rebinding: Option<Action>,
+}
+
+fn settings_ui(
+    egui_context: Res<EguiContext>,
+    keys: Res<Input<KeyCode>>,
+    mut settings_ui: ResMut<SettingsUi>,
+    mut bindings: ResMut<KeyBindings>,
+) {
+    if !settings_ui.open {
+        return;
+    }
+    if let Some(action) = settings_ui.rebinding {
+        if let Some(key) = keys.get_just_pressed().next() {
+            if *key != KeyCode::Escape {
+                bindings.rebind(action, *key);
+                bindings.save();
+            }
+            settings_ui.rebinding = None;
+        }
+    }
+
+    let mut open = true;
+    egui::Window::new("Settings")
+        .anchor(egui::Align2::CENTER_CENTER, [0.0, 0.0])
+        .collapsible(false)
+        .resizable(false)
+        .open(&mut open)
+        .show(egui_context.ctx(), |ui| {
+            ui.heading("Controls");
+            egui::Grid::new("key_bindings").show(ui, |ui| {
+                for action in Action::ALL {
+                    ui.label(action.name());
+                    if settings_ui.rebinding == Some(action) {
+                        ui.label("Press a key (Esc to cancel)");
+                    } else if ui.button(format!("{:?}", bindings.key(action))).clicked() {
+                        settings_ui.rebinding = Some(action);
+                    }
+                    ui.end_row();
+                }
+            });
+            if ui.button("Reset to defaults").clicked() {
+                *bindings = KeyBindings::default();
+                bindings.save();
+                settings_ui.rebinding = None;
+            }
+        });
+    if !open {
+        settings_ui.open = false;
+        settings_ui.rebinding = None;
+    }
+}
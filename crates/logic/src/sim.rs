@@ -0,0 +1,174 @@
+//! The match as the rollback schedule simulates it, frame by frame, without Bevy. Clients
+//! that don't run the Bevy app step it with the same inputs and reach the same states.
+
+use ggrs::Frame;
+
+use crate::desync::{FrameState, PlayerState};
+use crate::logic::{next_round_state, MatchRules, MatchStart, RoundState, RoundWait};
+use crate::protocol::PlayerInput;
+use crate::rules::{resolve_round, Action, PlayerStats, NUM_PLAYERS};
+
+/// Where the current round is.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Phase {
+    /// No frame was simulated yet.
+    Starting,
+    /// Players pick their action from frame `from` until `until`.
+    Deciding {
+        from: Frame,
+        until: Frame,
+    },
+    /// The actions are shown until `until`, then resolved.
+    Showing {
+        from: Frame,
+        until: Frame,
+    },
+    Over,
+}
+
+impl Phase {
+    pub(crate) fn of(round_state: RoundState) -> Self {
+        match round_state {
+            RoundState::WaitUntil(RoundWait { from, until }) => Phase::Deciding { from, until },
+            RoundState::DisplayUntil(RoundWait { from, until }) => Phase::Showing { from, until },
+            RoundState::GameOver => Phase::Over,
+            // `Compute` and `NextRound` never last past a frame
+            _ => Phase::Starting,
+        }
+    }
+}
+
+/// Everything the rollback schedule changes, so also what GGRS saves and loads.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct MatchSim {
+    /// Frames simulated so far.
+    frame: Frame,
+    round_state: RoundState,
+    players: [PlayerStats; NUM_PLAYERS],
+    /// Action of each player this round, players spawn reloading.
+    actions: [Action; NUM_PLAYERS],
+}
+
+impl MatchSim {
+    pub fn new(start: [PlayerStats; NUM_PLAYERS]) -> Self {
+        Self {
+            frame: 0,
+            round_state: RoundState::NotReady,
+            players: start,
+            actions: [Action::Reload; NUM_PLAYERS],
+        }
+    }
+
+    /// A match starting with the stats of `rules`.
+    pub fn from_rules(rules: &MatchRules) -> Self {
+        Self::new(MatchStart::from_rules(rules).0)
+    }
+
+    /// Frame simulated by the next `advance`.
+    pub fn frame(&self) -> Frame {
+        self.frame
+    }
+
+    pub fn players(&self) -> [PlayerStats; NUM_PLAYERS] {
+        self.players
+    }
+
+    /// Actions picked so far this round, or the ones of the round shown or just resolved.
+    pub fn actions(&self) -> [Action; NUM_PLAYERS] {
+        self.actions
+    }
+
+    pub fn phase(&self) -> Phase {
+        Phase::of(self.round_state)
+    }
+
+    /// Simulates a frame with the encoded input of each player, like the systems of the
+    /// rollback schedule, in their order. Returns the actions of the round resolved on
+    /// this frame, if any.
+    pub fn advance(
+        &mut self,
+        rules: &MatchRules,
+        inputs: &[Vec<u8>],
+    ) -> Option<[Action; NUM_PLAYERS]> {
+        // `update_round` then `handle_inputs`, which skips inputs that do not decode
+        self.round_state = next_round_state(self.round_state, self.frame, rules);
+        if matches!(self.round_state, RoundState::WaitUntil(_)) {
+            for (action, input) in self.actions.iter_mut().zip(inputs) {
+                if let Ok(PlayerInput {
+                    action: Some(picked),
+                    ..
+                }) = PlayerInput::decode(input)
+                {
+                    *action = picked;
+                }
+            }
+        }
+
+        // `compute_end_round` then `react_end_round`
+        let mut resolved = None;
+        if self.round_state == RoundState::Compute {
+            self.players = resolve_round(self.players, self.actions);
+            resolved = Some(self.actions);
+            self.round_state = if self.players.iter().any(|stats| stats.health <= 0) {
+                RoundState::GameOver
+            } else {
+                RoundState::NextRound
+            };
+        }
+        if self.round_state == RoundState::NextRound {
+            self.round_state = RoundState::WaitUntil(RoundWait {
+                from: self.frame,
+                until: self.frame + rules.decision_frames,
+            });
+        }
+        self.frame += 1;
+        resolved
+    }
+
+    /// Checksum of the state, the one `desync::record_state` computes for the frame last
+    /// simulated.
+    pub fn checksum(&self) -> u64 {
+        let players = (0..NUM_PLAYERS)
+            .map(|handle| PlayerState::new(handle, self.players[handle], self.actions[handle]))
+            .collect();
+        FrameState::new(&self.round_state, players).checksum
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn rounds_run_on_the_timers() {
+        let rules = MatchRules {
+            starting_health: 1,
+            decision_frames: 2,
+            display_frames: 1,
+            ..Default::default()
+        };
+        let mut sim = MatchSim::new([PlayerStats { health: 1, ammo: 1 }; NUM_PLAYERS]);
+        let idle = vec![PlayerInput::default().encode().to_vec(); NUM_PLAYERS];
+        let mut fire = idle.clone();
+        fire[0] = PlayerInput {
+            action: Some(Action::Fire),
+            ..Default::default()
+        }
+        .encode()
+        .to_vec();
+
+        assert_eq!(sim.advance(&rules, &fire), None);
+        assert_eq!(sim.phase(), Phase::Deciding { from: 0, until: 2 });
+        assert_eq!(sim.actions(), [Action::Fire, Action::Reload]);
+        sim.advance(&rules, &idle);
+        sim.advance(&rules, &idle);
+        assert_eq!(sim.phase(), Phase::Showing { from: 2, until: 3 });
+        assert_eq!(
+            sim.advance(&rules, &idle),
+            Some([Action::Fire, Action::Reload])
+        );
+        assert_eq!(sim.phase(), Phase::Over);
+        assert_eq!(sim.players()[1].health, 0);
+        assert_eq!(sim.frame(), 4);
+    }
+}
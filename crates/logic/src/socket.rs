@@ -0,0 +1,659 @@
+use ggrs::{Frame, PlayerType, UdpMessage};
+use instant::{Duration, Instant};
+#[cfg(feature = "app")]
+use matchbox_socket::WebRtcSocket;
+#[cfg(feature = "app")]
+use std::{
+    collections::hash_map::DefaultHasher,
+    future::Future,
+    hash::{Hash, Hasher},
+    pin::Pin,
+};
+use std::{
+    collections::HashMap,
+    net::{Ipv4Addr, Ipv6Addr, SocketAddr},
+    sync::{Arc, Mutex},
+};
+use tracing::warn;
+
+#[cfg(not(target_arch = "wasm32"))]
+use std::net::UdpSocket;
+
+#[cfg(feature = "app")]
+use crate::netsim::{NetClock, NetworkConditions, SimulatedTransport};
+use crate::protocol::PROTOCOL_VERSION;
+#[cfg(test)]
+use crate::rules::NUM_PLAYERS;
+
+/// First byte of every packet, telling what follows.
+const PACKET_HELLO: u8 = 0;
+const PACKET_GGRS: u8 = 1;
+const PACKET_CHECKSUM: u8 = 2;
+const PACKET_PING: u8 = 3;
+const PACKET_PONG: u8 = 4;
+const PACKET_STATE: u8 = 5;
+
+/// Tells our hellos apart from whatever an unrelated client could send.
+const HELLO_MAGIC: &[u8; 4] = b"CBOY";
+
+/// Result of the protocol version handshake with the connected peers.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum Handshake {
+    /// Some peers did not tell their version yet.
+    Pending,
+    /// Every peer plays our version.
+    Done,
+    /// A peer plays another version, `None` if it does not speak the handshake at all.
+    Mismatch(Option<u8>),
+}
+
+/// Game state checksums sent beside the GGRS traffic, and the states themselves once the
+/// checksums disagree. GGRS owns the socket once the session starts, so the game and the
+/// socket share the queues.
+#[derive(Clone, Default)]
+pub(crate) struct ChecksumChannel(Arc<Mutex<ChecksumQueues>>);
+
+#[derive(Default)]
+struct ChecksumQueues {
+    outgoing: Vec<(Frame, u64)>,
+    incoming: Vec<(Frame, u64)>,
+    /// Serialized `FrameState`s, only sent for desync reports.
+    outgoing_states: Vec<(Frame, Vec<u8>)>,
+    incoming_states: Vec<(Frame, Vec<u8>)>,
+}
+
+impl ChecksumChannel {
+    pub(crate) fn send(&self, frame: Frame, checksum: u64) {
+        self.0.lock().unwrap().outgoing.push((frame, checksum));
+    }
+
+    pub(crate) fn receive(&self) -> Vec<(Frame, u64)> {
+        std::mem::take(&mut self.0.lock().unwrap().incoming)
+    }
+
+    #[cfg(feature = "app")]
+    pub(crate) fn send_state(&self, frame: Frame, state: Vec<u8>) {
+        self.0.lock().unwrap().outgoing_states.push((frame, state));
+    }
+
+    #[cfg(feature = "app")]
+    pub(crate) fn receive_states(&self) -> Vec<(Frame, Vec<u8>)> {
+        std::mem::take(&mut self.0.lock().unwrap().incoming_states)
+    }
+}
+
+/// When each peer was last heard from, shared like `ChecksumChannel`.
+#[derive(Clone, Default)]
+pub(crate) struct PeerActivity(Arc<Mutex<HashMap<SocketAddr, Instant>>>);
+
+impl PeerActivity {
+    fn heard_from(&self, addr: SocketAddr) {
+        self.0.lock().unwrap().insert(addr, Instant::now());
+    }
+
+    /// Time since `addr` last sent anything, zero if it never did.
+    pub(crate) fn silent_for(&self, addr: &SocketAddr) -> Duration {
+        self.0
+            .lock()
+            .unwrap()
+            .get(addr)
+            .map_or(Duration::ZERO, |last| last.elapsed())
+    }
+}
+
+/// Moves raw packets between us and the other peers, whatever the network is.
+pub(crate) trait Transport: Send + Sync {
+    /// Takes in the peers who connected since the last call.
+    fn accept_new_connections(&mut self) {}
+    fn connected_peers(&self) -> Vec<SocketAddr>;
+    /// Local and remote players, in the same order on every peer.
+    fn players(&self) -> Vec<PlayerType>;
+    fn send(&mut self, packet: Box<[u8]>, addr: SocketAddr);
+    fn receive(&mut self) -> Vec<(SocketAddr, Box<[u8]>)>;
+}
+
+/// Peers connected through matchbox, like `matchbox_socket::WebRtcNonBlockingSocket`.
+#[cfg(feature = "app")]
+pub(crate) struct MatchboxTransport {
+    socket: WebRtcSocket,
+    fake_socket_addrs: HashMap<String, SocketAddr>,
+    fake_socket_addrs_reverse: HashMap<SocketAddr, String>,
+}
+
+#[cfg(feature = "app")]
+impl MatchboxTransport {
+    #[must_use]
+    pub(crate) fn new<T: Into<String>>(room_url: T) -> (Self, Pin<Box<dyn Future<Output = ()>>>) {
+        let (socket, message_loop) = WebRtcSocket::new(room_url);
+        (
+            Self {
+                socket,
+                fake_socket_addrs: Default::default(),
+                fake_socket_addrs_reverse: Default::default(),
+            },
+            message_loop,
+        )
+    }
+
+    fn get_or_create_fake_addr(&mut self, id: &str) -> SocketAddr {
+        match self.fake_socket_addrs.get(id) {
+            Some(fake_addr) => *fake_addr,
+            None => self.handle_new_peer_id(id.to_string()),
+        }
+    }
+
+    fn handle_new_peer_id(&mut self, id: String) -> SocketAddr {
+        let fake_addr = make_fake_socket_addr(&id);
+        self.fake_socket_addrs.insert(id.clone(), fake_addr);
+        self.fake_socket_addrs_reverse.insert(fake_addr, id);
+        fake_addr
+    }
+}
+
+#[cfg(feature = "app")]
+impl Transport for MatchboxTransport {
+    fn accept_new_connections(&mut self) {
+        let new_peers = self.socket.accept_new_connections();
+        for peer in new_peers {
+            self.handle_new_peer_id(peer);
+        }
+    }
+
+    fn connected_peers(&self) -> Vec<SocketAddr> {
+        self.socket
+            .connected_peers()
+            .iter()
+            .map(|id| *self.fake_socket_addrs.get(id).unwrap())
+            .collect()
+    }
+
+    fn players(&self) -> Vec<PlayerType> {
+        // needs to be consistent order across all peers
+        let mut ids = self.socket.connected_peers();
+        ids.push(self.socket.id().to_owned());
+        ids.sort();
+        ids.iter()
+            .map(|id| {
+                if id == self.socket.id() {
+                    PlayerType::Local
+                } else {
+                    let addr = *self.fake_socket_addrs.get(id).unwrap();
+                    PlayerType::Remote(addr)
+                }
+            })
+            .collect()
+    }
+
+    fn send(&mut self, packet: Box<[u8]>, addr: SocketAddr) {
+        let id = self.fake_socket_addrs_reverse[&addr].clone();
+        self.socket.send(packet, id);
+    }
+
+    fn receive(&mut self) -> Vec<(SocketAddr, Box<[u8]>)> {
+        self.socket
+            .receive()
+            .into_iter()
+            .map(|(id, packet)| (self.get_or_create_fake_addr(&id), packet))
+            .collect()
+    }
+}
+
+/// Largest packet we expect over UDP, GGRS messages are far smaller.
+#[cfg(not(target_arch = "wasm32"))]
+const UDP_BUFFER_SIZE: usize = 4096;
+
+/// Direct UDP connection between two native clients, the host being player 0.
+///
+/// `ggrs::UdpNonBlockingSocket` would do for GGRS alone, but it reads every datagram as a
+/// GGRS message. `GameSocket` multiplexes its hellos, pings and checksums on the same
+/// port, so it needs the raw packets a `Transport` gives.
+#[cfg(not(target_arch = "wasm32"))]
+pub(crate) struct UdpTransport {
+    socket: UdpSocket,
+    /// Whoever connects first for the host, the host for the other client.
+    peer: Option<SocketAddr>,
+    is_host: bool,
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+impl UdpTransport {
+    /// Waits for a client on `port`, over IPv4.
+    pub(crate) fn host(port: u16) -> std::io::Result<Self> {
+        let socket = UdpSocket::bind((Ipv4Addr::UNSPECIFIED, port))?;
+        socket.set_nonblocking(true)?;
+        Ok(Self {
+            socket,
+            peer: None,
+            is_host: true,
+        })
+    }
+
+    /// Connects to the host listening at `addr`, from a socket of the same address family.
+    pub(crate) fn connect(addr: SocketAddr) -> std::io::Result<Self> {
+        let any: std::net::IpAddr = match addr {
+            SocketAddr::V4(_) => Ipv4Addr::UNSPECIFIED.into(),
+            SocketAddr::V6(_) => Ipv6Addr::UNSPECIFIED.into(),
+        };
+        let socket = UdpSocket::bind((any, 0))?;
+        socket.set_nonblocking(true)?;
+        Ok(Self {
+            socket,
+            peer: Some(addr),
+            is_host: false,
+        })
+    }
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+impl Transport for UdpTransport {
+    /// The host takes whoever sends it a packet first, leaving the packet to `receive`.
+    fn accept_new_connections(&mut self) {
+        if self.peer.is_some() {
+            return;
+        }
+        let mut buffer = [0u8; UDP_BUFFER_SIZE];
+        if let Ok((_, addr)) = self.socket.peek_from(&mut buffer) {
+            self.peer = Some(addr);
+        }
+    }
+
+    fn connected_peers(&self) -> Vec<SocketAddr> {
+        self.peer.into_iter().collect()
+    }
+
+    fn players(&self) -> Vec<PlayerType> {
+        let remote = self.peer.map(PlayerType::Remote);
+        let local = Some(PlayerType::Local);
+        let players = if self.is_host {
+            [local, remote]
+        } else {
+            [remote, local]
+        };
+        players.into_iter().flatten().collect()
+    }
+
+    fn send(&mut self, packet: Box<[u8]>, addr: SocketAddr) {
+        if let Err(e) = self.socket.send_to(&packet, addr) {
+            warn!("could not send to {}: {}", addr, e);
+        }
+    }
+
+    fn receive(&mut self) -> Vec<(SocketAddr, Box<[u8]>)> {
+        let mut packets = vec![];
+        let mut buffer = [0u8; UDP_BUFFER_SIZE];
+        loop {
+            match self.socket.recv_from(&mut buffer) {
+                Ok((len, addr)) => {
+                    // ignore strangers, the game is between two peers
+                    if self.peer == Some(addr) {
+                        packets.push((addr, buffer[..len].into()));
+                    }
+                }
+                Err(e) if e.kind() == std::io::ErrorKind::WouldBlock => break,
+                // on some platforms, an unreachable peer shows up as an error here
+                Err(_) => break,
+            }
+        }
+        packets
+    }
+}
+
+#[cfg(test)]
+type Mailbox = Arc<Mutex<Vec<(SocketAddr, Box<[u8]>)>>>;
+
+/// One end of an in-memory connection, packets arrive as soon as they are sent. A clone
+/// sends as the same end.
+#[cfg(test)]
+#[derive(Clone)]
+pub(crate) struct MemoryTransport {
+    /// Address of each player, ours at `handle`.
+    addrs: [SocketAddr; NUM_PLAYERS],
+    handle: usize,
+    inbox: Mailbox,
+    peer_inbox: Mailbox,
+}
+
+#[cfg(test)]
+impl MemoryTransport {
+    pub(crate) fn pair(addrs: [SocketAddr; NUM_PLAYERS]) -> [Self; NUM_PLAYERS] {
+        let inboxes = [Mailbox::default(), Mailbox::default()];
+        [0, 1].map(|handle| Self {
+            addrs,
+            handle,
+            inbox: inboxes[handle].clone(),
+            peer_inbox: inboxes[1 - handle].clone(),
+        })
+    }
+}
+
+#[cfg(test)]
+impl Transport for MemoryTransport {
+    fn connected_peers(&self) -> Vec<SocketAddr> {
+        vec![self.addrs[1 - self.handle]]
+    }
+
+    fn players(&self) -> Vec<PlayerType> {
+        (0..NUM_PLAYERS)
+            .map(|handle| {
+                if handle == self.handle {
+                    PlayerType::Local
+                } else {
+                    PlayerType::Remote(self.addrs[handle])
+                }
+            })
+            .collect()
+    }
+
+    fn send(&mut self, packet: Box<[u8]>, _addr: SocketAddr) {
+        self.peer_inbox
+            .lock()
+            .unwrap()
+            .push((self.addrs[self.handle], packet));
+    }
+
+    fn receive(&mut self) -> Vec<(SocketAddr, Box<[u8]>)> {
+        std::mem::take(&mut self.inbox.lock().unwrap())
+    }
+}
+
+/// GGRS socket over any `Transport`, which also exchanges `PROTOCOL_VERSION` with peers
+/// before the session starts and carries our own packets beside the GGRS ones.
+pub(crate) struct GameSocket {
+    transport: Box<dyn Transport>,
+    /// Version each peer announced, `None` for peers sending packets we don't understand.
+    peer_versions: HashMap<SocketAddr, Option<u8>>,
+    checksums: ChecksumChannel,
+    activity: PeerActivity,
+    /// When each ping still waiting for its pong was sent.
+    pings: HashMap<u32, Instant>,
+    next_ping: u32,
+    round_trips: HashMap<SocketAddr, Vec<Duration>>,
+}
+
+impl GameSocket {
+    pub(crate) fn new(transport: impl Transport + 'static) -> Self {
+        Self {
+            transport: Box::new(transport),
+            peer_versions: Default::default(),
+            checksums: Default::default(),
+            activity: Default::default(),
+            pings: Default::default(),
+            next_ping: 0,
+            round_trips: Default::default(),
+        }
+    }
+
+    /// Sends every packet through the simulated network `conditions`, the handshake's
+    /// included, so call it before the handshake starts.
+    #[cfg(feature = "app")]
+    pub(crate) fn simulate(self, conditions: NetworkConditions, clock: NetClock) -> Self {
+        warn!("simulating network conditions: {:?}", conditions);
+        Self {
+            transport: Box::new(SimulatedTransport::new(self.transport, conditions, clock)),
+            ..self
+        }
+    }
+
+    pub(crate) fn accept_new_connections(&mut self) {
+        self.transport.accept_new_connections();
+    }
+
+    pub(crate) fn players(&self) -> Vec<PlayerType> {
+        self.transport.players()
+    }
+
+    /// Announces our version to every connected peer. Packets may be lost, so this is
+    /// repeated until `handshake` is done.
+    pub(crate) fn say_hello(&mut self) {
+        for addr in self.transport.connected_peers() {
+            self.send_hello(addr, false);
+        }
+    }
+
+    /// Reads the hellos received so far. GGRS packets arriving before the session starts
+    /// are dropped, GGRS sends them again.
+    pub(crate) fn receive_hellos(&mut self) {
+        for (addr, packet) in self.transport.receive() {
+            self.handle_packet(addr, &packet);
+        }
+    }
+
+    /// Sends a ping to every connected peer, their pongs are read by `receive_hellos`.
+    pub(crate) fn ping(&mut self) {
+        let id = self.next_ping;
+        self.next_ping += 1;
+        self.pings.insert(id, Instant::now());
+        for addr in self.transport.connected_peers() {
+            let mut packet = vec![PACKET_PING];
+            packet.extend_from_slice(&id.to_le_bytes());
+            self.transport.send(packet.into_boxed_slice(), addr);
+        }
+    }
+
+    /// Fewest round trips measured with a single peer.
+    pub(crate) fn round_trip_samples(&self) -> usize {
+        self.transport
+            .connected_peers()
+            .iter()
+            .map(|addr| self.round_trips.get(addr).map_or(0, Vec::len))
+            .min()
+            .unwrap_or(0)
+    }
+
+    /// Median round-trip time to the slowest peer, `None` before any pong came back.
+    pub(crate) fn round_trip_time(&self) -> Option<Duration> {
+        self.round_trips
+            .values()
+            .filter_map(|samples| {
+                let mut samples = samples.clone();
+                samples.sort();
+                samples.get(samples.len() / 2).copied()
+            })
+            .max()
+    }
+
+    pub(crate) fn checksums(&self) -> ChecksumChannel {
+        self.checksums.clone()
+    }
+
+    pub(crate) fn activity(&self) -> PeerActivity {
+        self.activity.clone()
+    }
+
+    fn send_checksums(&mut self) {
+        let (outgoing, outgoing_states) = {
+            let mut queues = self.checksums.0.lock().unwrap();
+            (
+                std::mem::take(&mut queues.outgoing),
+                std::mem::take(&mut queues.outgoing_states),
+            )
+        };
+        let mut packets = vec![];
+        for (frame, checksum) in outgoing {
+            let mut packet = vec![PACKET_CHECKSUM];
+            packet.extend_from_slice(&frame.to_le_bytes());
+            packet.extend_from_slice(&checksum.to_le_bytes());
+            packets.push(packet);
+        }
+        for (frame, state) in outgoing_states {
+            let mut packet = vec![PACKET_STATE];
+            packet.extend_from_slice(&frame.to_le_bytes());
+            packet.extend(state);
+            packets.push(packet);
+        }
+        for packet in packets {
+            for addr in self.transport.connected_peers() {
+                self.transport.send(packet.clone().into_boxed_slice(), addr);
+            }
+        }
+    }
+
+    pub(crate) fn handshake(&self) -> Handshake {
+        let mut handshake = Handshake::Done;
+        for addr in self.transport.connected_peers() {
+            match self.peer_versions.get(&addr) {
+                None => handshake = Handshake::Pending,
+                Some(Some(PROTOCOL_VERSION)) => {}
+                Some(version) => return Handshake::Mismatch(*version),
+            }
+        }
+        handshake
+    }
+
+    fn send_hello(&mut self, addr: SocketAddr, reply: bool) {
+        let mut packet = vec![PACKET_HELLO];
+        packet.extend_from_slice(HELLO_MAGIC);
+        packet.push(PROTOCOL_VERSION);
+        packet.push(reply as u8);
+        self.transport.send(packet.into_boxed_slice(), addr);
+    }
+
+    /// Returns the GGRS message in `packet`, if any and if the peer plays our version.
+    fn handle_packet(&mut self, addr: SocketAddr, packet: &[u8]) -> Option<UdpMessage> {
+        self.activity.heard_from(addr);
+        match packet.split_first() {
+            Some((&PACKET_HELLO, [magic @ .., version, reply])) if magic == HELLO_MAGIC => {
+                self.peer_versions.insert(addr, Some(*version));
+                // the peer may have missed our hello if we already started the game
+                if *reply == 0 {
+                    self.send_hello(addr, true);
+                }
+                None
+            }
+            Some((&PACKET_CHECKSUM, message)) if message.len() == 12 => {
+                let frame = Frame::from_le_bytes(message[..4].try_into().unwrap());
+                let checksum = u64::from_le_bytes(message[4..].try_into().unwrap());
+                self.checksums
+                    .0
+                    .lock()
+                    .unwrap()
+                    .incoming
+                    .push((frame, checksum));
+                None
+            }
+            Some((&PACKET_STATE, message)) if message.len() >= 4 => {
+                let frame = Frame::from_le_bytes(message[..4].try_into().unwrap());
+                self.checksums
+                    .0
+                    .lock()
+                    .unwrap()
+                    .incoming_states
+                    .push((frame, message[4..].to_vec()));
+                None
+            }
+            Some((&PACKET_PING, id)) if id.len() == 4 => {
+                let mut packet = vec![PACKET_PONG];
+                packet.extend_from_slice(id);
+                self.transport.send(packet.into_boxed_slice(), addr);
+                None
+            }
+            Some((&PACKET_PONG, id)) if id.len() == 4 => {
+                let id = u32::from_le_bytes(id.try_into().unwrap());
+                if let Some(sent) = self.pings.get(&id) {
+                    self.round_trips
+                        .entry(addr)
+                        .or_default()
+                        .push(sent.elapsed());
+                }
+                None
+            }
+            Some((&PACKET_GGRS, message)) => {
+                if self.peer_versions.get(&addr) != Some(&Some(PROTOCOL_VERSION)) {
+                    return None;
+                }
+                bincode::deserialize(message).ok()
+            }
+            // a peer speaking another protocol is told apart by its first packets, once it
+            // said hello a bad packet is only noise, which must not cut the match off
+            _ if self.peer_versions.contains_key(&addr) => {
+                warn!("dropping an unknown packet from {}", addr);
+                None
+            }
+            _ => {
+                warn!("unknown packet from {}, protocol mismatch", addr);
+                self.peer_versions.insert(addr, None);
+                None
+            }
+        }
+    }
+}
+
+impl ggrs::NonBlockingSocket<SocketAddr> for GameSocket {
+    fn send_to(&mut self, msg: &UdpMessage, addr: &SocketAddr) {
+        let mut packet = vec![PACKET_GGRS];
+        packet.extend(bincode::serialize(&msg).unwrap());
+        self.transport.send(packet.into_boxed_slice(), *addr);
+    }
+
+    fn receive_all_messages(&mut self) -> Vec<(SocketAddr, UdpMessage)> {
+        // called by GGRS on every poll, a good time to flush our own packets too
+        self.send_checksums();
+        let mut messages = vec![];
+        for (addr, packet) in self.transport.receive() {
+            if let Some(msg) = self.handle_packet(addr, &packet) {
+                messages.push((addr, msg));
+            }
+        }
+        messages
+    }
+}
+
+#[cfg(feature = "app")]
+fn make_fake_socket_addr(id: &str) -> SocketAddr {
+    // same mapping as matchbox, GGRS only needs the address to be unique per peer
+    let mut hasher = DefaultHasher::new();
+    id.hash(&mut hasher);
+    let hash = hasher.finish();
+    let a: u16 = hash as u16;
+    SocketAddr::new(Ipv6Addr::new(a, a, a, a, a, a, a, a).into(), 1111)
+}
+
+#[cfg(test)]
+mod tests {
+    use ggrs::NonBlockingSocket;
+
+    use super::*;
+
+    fn addrs() -> [SocketAddr; 2] {
+        [
+            "127.0.0.1:7000".parse().unwrap(),
+            "127.0.0.1:7001".parse().unwrap(),
+        ]
+    }
+
+    /// A GGRS keep-alive, the message GGRS sends when it has nothing else to say.
+    fn keep_alive() -> UdpMessage {
+        bincode::deserialize(&[7, 0, 6, 0, 0, 0]).unwrap()
+    }
+
+    #[test]
+    fn junk_before_hello_is_a_protocol_mismatch() {
+        let [first, mut second] = MemoryTransport::pair(addrs());
+        let mut socket = GameSocket::new(first);
+        second.send(vec![0xff, 1, 2].into_boxed_slice(), addrs()[0]);
+        socket.receive_hellos();
+        assert_eq!(socket.handshake(), Handshake::Mismatch(None));
+    }
+
+    #[test]
+    fn junk_after_hello_leaves_ggrs_traffic_through() {
+        let [first, second] = MemoryTransport::pair(addrs());
+        let mut intruder = second.clone();
+        let mut sockets = [GameSocket::new(first), GameSocket::new(second)];
+        for socket in &mut sockets {
+            socket.say_hello();
+        }
+        for socket in &mut sockets {
+            socket.receive_hellos();
+        }
+        assert_eq!(sockets[0].handshake(), Handshake::Done);
+
+        intruder.send(vec![0xff, 1, 2].into_boxed_slice(), addrs()[0]);
+        sockets[1].send_to(&keep_alive(), &addrs()[0]);
+        assert_eq!(
+            sockets[0].receive_all_messages(),
+            vec![(addrs()[1], keep_alive())]
+        );
+        assert_eq!(sockets[0].handshake(), Handshake::Done);
+    }
+}
@@ -0,0 +1,492 @@
+//! Equilibrium strategies of the duel.
+//!
+//! Each round is a simultaneous-move matrix game whose payoffs are the values of the states
+//! the actions lead to. Value iteration over every (HP, ammo) state converges to the value
+//! of the whole stochastic game, a win being worth 1 and a loss -1 to the first player.
+//!
+//! Shielding is never punished, so either player can hold a draw forever and every state
+//! of the exact game is worth 0. The solver lets actions tremble instead, each one being
+//! swapped for a random legal action once in a while, which singles out the equilibrium
+//! punishing mistakes (Selten's perfect equilibrium, as the tremble goes to 0).
+
+use std::collections::HashMap;
+
+use crate::logic::MatchRules;
+use crate::rules::{resolve_round, Action, PlayerStats, NUM_PLAYERS};
+
+/// Below this, probabilities and payoff differences are rounding errors.
+const EPSILON: f64 = 1e-9;
+
+pub struct SolverConfig {
+    /// Ammo is counted up to this, reloading beyond it changes nothing.
+    pub max_ammo: i32,
+    /// Weight of the next round, below 1 so winning sooner is worth more and value
+    /// iteration converges even though players can shield forever.
+    pub discount: f64,
+    /// Iteration stops once no state value moves by more than this.
+    pub tolerance: f64,
+    pub max_iterations: u32,
+    /// Chance of playing a random legal action instead of the chosen one, 0 for the exact
+    /// game.
+    pub tremble: f64,
+}
+
+impl Default for SolverConfig {
+    fn default() -> Self {
+        Self {
+            max_ammo: 8,
+            discount: 0.95,
+            tolerance: 1e-6,
+            max_iterations: 10_000,
+            tremble: 0.05,
+        }
+    }
+}
+
+/// Equilibrium of one state, for the first player.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct StateSolution {
+    /// Between -1 (lost) and 1 (won).
+    pub value: f64,
+    /// Probability of each of `Action::ALL`, for each player.
+    pub strategies: [[f64; 3]; NUM_PLAYERS],
+}
+
+impl StateSolution {
+    /// Samples an action of `player` from `roll`, uniform in [0, 1).
+    pub fn pick(&self, player: usize, roll: f64) -> Action {
+        let mut total = 0.0;
+        for (action, probability) in Action::ALL.into_iter().zip(self.strategies[player]) {
+            total += probability;
+            if roll < total {
+                return action;
+            }
+        }
+        // the probabilities may sum to slightly less than 1
+        Action::ALL
+            .into_iter()
+            .zip(self.strategies[player])
+            .rev()
+            .find(|(_, probability)| *probability > 0.0)
+            .map_or(Action::Shield, |(action, _)| action)
+    }
+}
+
+pub struct Solution {
+    pub max_health: i32,
+    pub max_ammo: i32,
+    pub iterations: u32,
+    states: HashMap<[PlayerStats; NUM_PLAYERS], StateSolution>,
+}
+
+impl Solution {
+    /// Equilibrium of a state where both players are alive, ammo above the cap counting
+    /// as the cap.
+    pub fn get(&self, players: [PlayerStats; NUM_PLAYERS]) -> Option<&StateSolution> {
+        self.states.get(&self.clamp(players))
+    }
+
+    /// Every state, sorted by HP then ammo.
+    pub fn states(&self) -> Vec<([PlayerStats; NUM_PLAYERS], &StateSolution)> {
+        let mut states: Vec<_> = self.states.iter().map(|(s, v)| (*s, v)).collect();
+        states.sort_by_key(|(s, _)| (s[0].health, s[1].health, s[0].ammo, s[1].ammo));
+        states
+    }
+
+    fn clamp(&self, mut players: [PlayerStats; NUM_PLAYERS]) -> [PlayerStats; NUM_PLAYERS] {
+        for player in players.iter_mut() {
+            player.ammo = player.ammo.min(self.max_ammo);
+        }
+        players
+    }
+}
+
+/// Indices in `Action::ALL` worth considering, firing without ammo being a worse shield.
+fn legal_actions(player: PlayerStats) -> Vec<usize> {
+    (0..Action::ALL.len())
+        .filter(|i| Action::ALL[*i] != Action::Fire || player.ammo > 0)
+        .collect()
+}
+
+/// Solves every state of a duel played with `rules`.
+pub fn solve(rules: &MatchRules, config: &SolverConfig) -> Solution {
+    let max_health = rules.starting_health;
+    let max_ammo = config.max_ammo.max(rules.starting_ammo);
+    let mut solution = Solution {
+        max_health,
+        max_ammo,
+        iterations: 0,
+        states: HashMap::new(),
+    };
+    let mut all = vec![];
+    for health0 in 1..=max_health {
+        for health1 in 1..=max_health {
+            for ammo0 in 0..=max_ammo {
+                for ammo1 in 0..=max_ammo {
+                    all.push([
+                        PlayerStats {
+                            health: health0,
+                            ammo: ammo0,
+                        },
+                        PlayerStats {
+                            health: health1,
+                            ammo: ammo1,
+                        },
+                    ]);
+                }
+            }
+        }
+    }
+    let mut values: HashMap<[PlayerStats; NUM_PLAYERS], f64> =
+        all.iter().map(|s| (*s, 0.0)).collect();
+
+    while solution.iterations < config.max_iterations {
+        solution.iterations += 1;
+        let mut change: f64 = 0.0;
+        let mut next_values = HashMap::with_capacity(values.len());
+        for state in &all {
+            let rows = legal_actions(state[0]);
+            let columns = legal_actions(state[1]);
+            let exact: Vec<Vec<f64>> = rows
+                .iter()
+                .map(|row| {
+                    columns
+                        .iter()
+                        .map(|column| {
+                            let next =
+                                resolve_round(*state, [Action::ALL[*row], Action::ALL[*column]]);
+                            match (next[0].health > 0, next[1].health > 0) {
+                                (true, false) => 1.0,
+                                (false, true) => -1.0,
+                                (false, false) => 0.0,
+                                (true, true) => config.discount * values[&solution.clamp(next)],
+                            }
+                        })
+                        .collect()
+                })
+                .collect();
+            let matrix = trembling(&exact, config.tremble);
+            let (value, row_strategy, column_strategy) = solve_matrix_game(&matrix);
+            let mut strategies = [[0.0; 3]; NUM_PLAYERS];
+            for (row, p) in rows.iter().zip(row_strategy) {
+                strategies[0][*row] = p;
+            }
+            for (column, p) in columns.iter().zip(column_strategy) {
+                strategies[1][*column] = p;
+            }
+            change = change.max((value - values[state]).abs());
+            next_values.insert(*state, value);
+            solution
+                .states
+                .insert(*state, StateSolution { value, strategies });
+        }
+        values = next_values;
+        if change < config.tolerance {
+            break;
+        }
+    }
+    solution
+}
+
+/// Payoffs when each player plays the chosen action with probability `1 - tremble`, and
+/// any action uniformly at random otherwise.
+fn trembling(matrix: &[Vec<f64>], tremble: f64) -> Vec<Vec<f64>> {
+    let played = |chosen: usize, count: usize| {
+        (0..count)
+            .map(move |i| {
+                let p = tremble / count as f64;
+                (i, if i == chosen { p + 1.0 - tremble } else { p })
+            })
+            .collect::<Vec<_>>()
+    };
+    let (rows, columns) = (matrix.len(), matrix[0].len());
+    (0..rows)
+        .map(|row| {
+            (0..columns)
+                .map(|column| {
+                    let mut payoff = 0.0;
+                    for (r, p) in played(row, rows) {
+                        for (c, q) in played(column, columns) {
+                            payoff += p * q * matrix[r][c];
+                        }
+                    }
+                    payoff
+                })
+                .collect()
+        })
+        .collect()
+}
+
+/// Value and optimal mixed strategies of the zero-sum game where the row player gets
+/// `matrix[row][column]`.
+///
+/// Some optimal strategies equalize the payoffs over a square submatrix (Shapley-Snow), so
+/// trying every square submatrix finds one. Meant for the few actions of a round. Should
+/// rounding errors reject every submatrix, the strategies are approximated by fictitious play.
+pub fn solve_matrix_game(matrix: &[Vec<f64>]) -> (f64, Vec<f64>, Vec<f64>) {
+    let rows = matrix.len();
+    let columns = matrix[0].len();
+    for size in 1..=rows.min(columns) {
+        for row_support in subsets(rows, size) {
+            for column_support in subsets(columns, size) {
+                if let Some(solution) = equalizing(matrix, &row_support, &column_support) {
+                    return solution;
+                }
+            }
+        }
+    }
+    fictitious_play(matrix, FICTITIOUS_PLAY_ROUNDS)
+}
+
+/// Rounds of fictitious play, enough to get within a percent of the value with a few actions.
+const FICTITIOUS_PLAY_ROUNDS: usize = 100_000;
+
+/// Each player plays the best response to how often the other played each action so far,
+/// the frequencies converge to optimal strategies (Robinson). The value is halfway between
+/// what each frequency guarantees.
+fn fictitious_play(matrix: &[Vec<f64>], rounds: usize) -> (f64, Vec<f64>, Vec<f64>) {
+    let (rows, columns) = (matrix.len(), matrix[0].len());
+    let mut row_counts = vec![0usize; rows];
+    let mut column_counts = vec![0usize; columns];
+    // total payoff of each action against everything the other player played
+    let mut row_payoffs = vec![0.0; rows];
+    let mut column_payoffs = vec![0.0; columns];
+    let mut row = 0;
+    for _ in 0..rounds {
+        row_counts[row] += 1;
+        for (payoff, m) in column_payoffs.iter_mut().zip(&matrix[row]) {
+            *payoff += m;
+        }
+        let column = (0..columns)
+            .min_by(|a, b| column_payoffs[*a].total_cmp(&column_payoffs[*b]))
+            .unwrap();
+        column_counts[column] += 1;
+        for (payoff, m) in row_payoffs.iter_mut().zip(matrix) {
+            *payoff += m[column];
+        }
+        row = (0..rows)
+            .max_by(|a, b| row_payoffs[*a].total_cmp(&row_payoffs[*b]))
+            .unwrap();
+    }
+    let rounds = rounds as f64;
+    let lower = column_payoffs.iter().copied().fold(f64::INFINITY, f64::min) / rounds;
+    let upper = row_payoffs
+        .iter()
+        .copied()
+        .fold(f64::NEG_INFINITY, f64::max)
+        / rounds;
+    let frequencies = |counts: Vec<usize>| counts.into_iter().map(|n| n as f64 / rounds).collect();
+    (
+        (lower + upper) / 2.0,
+        frequencies(row_counts),
+        frequencies(column_counts),
+    )
+}
+
+/// Weights over `support` making the payoff the same against every `against` index, and
+/// that payoff. `payoff(mine, theirs)` is what the weighted player gets.
+fn equalize(
+    support: &[usize],
+    against: &[usize],
+    payoff: impl Fn(usize, usize) -> f64,
+) -> Option<(Vec<f64>, f64)> {
+    // weights w and value v: sum(w) = 1, then w . payoff(., a) - v = 0 for every a
+    let mut system = vec![];
+    let mut ones = vec![1.0; support.len()];
+    ones.extend([0.0, 1.0]);
+    system.push(ones);
+    for a in against {
+        let mut equation: Vec<f64> = support.iter().map(|s| payoff(*s, *a)).collect();
+        equation.extend([-1.0, 0.0]);
+        system.push(equation);
+    }
+    let mut weights = solve_linear(system)?;
+    let value = weights.pop()?;
+    Some((weights, value))
+}
+
+/// Strategies mixing over the given supports so the opponent is indifferent within them,
+/// if they are an equilibrium.
+fn equalizing(
+    matrix: &[Vec<f64>],
+    row_support: &[usize],
+    column_support: &[usize],
+) -> Option<(f64, Vec<f64>, Vec<f64>)> {
+    let (x, value) = equalize(row_support, column_support, |r, c| matrix[r][c])?;
+    let (y, _) = equalize(column_support, row_support, |c, r| matrix[r][c])?;
+    if x.iter().chain(&y).any(|p| *p < -EPSILON) {
+        return None;
+    }
+
+    let mut row_strategy = vec![0.0; matrix.len()];
+    for (r, p) in row_support.iter().zip(&x) {
+        row_strategy[*r] = p.max(0.0);
+    }
+    let mut column_strategy = vec![0.0; matrix[0].len()];
+    for (c, p) in column_support.iter().zip(&y) {
+        column_strategy[*c] = p.max(0.0);
+    }
+    // neither player gains by leaving the support
+    for c in 0..column_strategy.len() {
+        let payoff: f64 = row_strategy
+            .iter()
+            .zip(matrix)
+            .map(|(p, row)| p * row[c])
+            .sum();
+        if payoff < value - EPSILON {
+            return None;
+        }
+    }
+    for row in matrix {
+        let payoff: f64 = column_strategy.iter().zip(row).map(|(q, m)| q * m).sum();
+        if payoff > value + EPSILON {
+            return None;
+        }
+    }
+    Some((value, row_strategy, column_strategy))
+}
+
+/// Solves the square system whose rows are coefficients followed by the constant, by
+/// Gaussian elimination. `None` if it is singular.
+fn solve_linear(mut system: Vec<Vec<f64>>) -> Option<Vec<f64>> {
+    let n = system.len();
+    for column in 0..n {
+        let pivot = (column..n).max_by(|a, b| {
+            system[*a][column]
+                .abs()
+                .total_cmp(&system[*b][column].abs())
+        })?;
+        if system[pivot][column].abs() < EPSILON {
+            return None;
+        }
+        system.swap(column, pivot);
+        for row in 0..n {
+            if row == column {
+                continue;
+            }
+            let factor = system[row][column] / system[column][column];
+            let pivot_row = system[column].clone();
+            for (value, pivot_value) in system[row].iter_mut().zip(pivot_row).skip(column) {
+                *value -= factor * pivot_value;
+            }
+        }
+    }
+    Some((0..n).map(|i| system[i][n] / system[i][i]).collect())
+}
+
+/// Every increasing sequence of `size` indices below `n`.
+fn subsets(n: usize, size: usize) -> Vec<Vec<usize>> {
+    if size == 0 {
+        return vec![vec![]];
+    }
+    (size - 1..n)
+        .flat_map(|last| {
+            subsets(last, size - 1).into_iter().map(move |mut s| {
+                s.push(last);
+                s
+            })
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn rock_paper_scissors_is_uniform() {
+        let matrix = vec![
+            vec![0.0, -1.0, 1.0],
+            vec![1.0, 0.0, -1.0],
+            vec![-1.0, 1.0, 0.0],
+        ];
+        let (value, rows, columns) = solve_matrix_game(&matrix);
+        assert!(value.abs() < 1e-9);
+        for p in rows.iter().chain(&columns) {
+            assert!((p - 1.0 / 3.0).abs() < 1e-9);
+        }
+    }
+
+    #[test]
+    fn saddle_point_is_pure() {
+        let matrix = vec![vec![3.0, 1.0], vec![2.0, 0.0]];
+        assert_eq!(
+            solve_matrix_game(&matrix),
+            (1.0, vec![1.0, 0.0], vec![0.0, 1.0])
+        );
+    }
+
+    /// Asserts neither strategy can be exploited by more than `tolerance` against `value`.
+    fn assert_optimal(matrix: &[Vec<f64>], solution: &(f64, Vec<f64>, Vec<f64>), tolerance: f64) {
+        let (value, rows, columns) = solution;
+        for strategy in [rows, columns] {
+            assert!((strategy.iter().sum::<f64>() - 1.0).abs() < 1e-9);
+            assert!(strategy.iter().all(|p| *p >= 0.0));
+        }
+        for c in 0..columns.len() {
+            let payoff: f64 = rows.iter().zip(matrix).map(|(p, row)| p * row[c]).sum();
+            assert!(payoff > value - tolerance);
+        }
+        for row in matrix {
+            let payoff: f64 = columns.iter().zip(row).map(|(q, m)| q * m).sum();
+            assert!(payoff < value + tolerance);
+        }
+    }
+
+    #[test]
+    fn duplicate_actions_are_solved() {
+        // the first two rows are the same action, every support holding both is singular
+        let matrix = vec![vec![1.0, -1.0], vec![1.0, -1.0], vec![-1.0, 1.0]];
+        let solution = solve_matrix_game(&matrix);
+        assert!(solution.0.abs() < 1e-9);
+        assert_optimal(&matrix, &solution, 1e-9);
+
+        let constant = vec![vec![0.5; 3]; 3];
+        let solution = solve_matrix_game(&constant);
+        assert_eq!(solution.0, 0.5);
+        assert_optimal(&constant, &solution, 1e-9);
+    }
+
+    #[test]
+    fn fictitious_play_approaches_the_equilibrium() {
+        let matrix = vec![
+            vec![0.0, -1.0, 1.0],
+            vec![1.0, 0.0, -1.0],
+            vec![-1.0, 1.0, 0.0],
+            vec![0.0, -1.0, 1.0],
+        ];
+        let solution = fictitious_play(&matrix, FICTITIOUS_PLAY_ROUNDS);
+        assert!(solution.0.abs() < 1e-2);
+        assert_optimal(&matrix, &solution, 1e-2);
+    }
+
+    #[test]
+    fn exact_duel_is_a_draw() {
+        let config = SolverConfig {
+            tremble: 0.0,
+            ..Default::default()
+        };
+        let solution = solve(&MatchRules::default(), &config);
+        for (_, solved) in solution.states() {
+            assert!(solved.value.abs() < 1e-9);
+        }
+    }
+
+    #[test]
+    fn duel_is_fair_and_ammo_helps() {
+        let solution = solve(&MatchRules::default(), &SolverConfig::default());
+        let stats = |health, ammo| PlayerStats { health, ammo };
+        for (state, solved) in solution.states() {
+            let mirrored = solution.get([state[1], state[0]]).unwrap();
+            assert!((solved.value + mirrored.value).abs() < 1e-4);
+            for strategy in solved.strategies {
+                assert!((strategy.iter().sum::<f64>() - 1.0).abs() < 1e-6);
+            }
+        }
+        let start = solution.get([stats(3, 0), stats(3, 0)]).unwrap();
+        assert!(start.value.abs() < 1e-4);
+        // nobody can be hurt, reloading is the only sensible move
+        assert_eq!(start.strategies, [[1.0, 0.0, 0.0]; NUM_PLAYERS]);
+        assert!(solution.get([stats(3, 1), stats(3, 0)]).unwrap().value > 0.0);
+    }
+}
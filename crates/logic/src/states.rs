@@ -1,6 +1,7 @@
 #[derive(Clone, Eq, PartialEq, Debug, Hash)]
 pub enum GameState {
     AssetLoading,
+    Lobby,
     Matchmaking,
     InGame,
 }
@@ -0,0 +1,32 @@
+/// Persisted data lives in files under this directory on native, and in `localStorage` on web.
+#[cfg(not(target_arch = "wasm32"))]
+const SAVE_DIR: &str = "saves";
+
+#[cfg(not(target_arch = "wasm32"))]
+pub(crate) fn save(key: &str, contents: &str) -> Result<(), String> {
+    std::fs::create_dir_all(SAVE_DIR).map_err(|e| e.to_string())?;
+    std::fs::write(std::path::Path::new(SAVE_DIR).join(key), contents).map_err(|e| e.to_string())
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+pub(crate) fn load(key: &str) -> Option<String> {
+    std::fs::read_to_string(std::path::Path::new(SAVE_DIR).join(key)).ok()
+}
+
+#[cfg(target_arch = "wasm32")]
+fn local_storage() -> Option<web_sys::Storage> {
+    web_sys::window()?.local_storage().ok().flatten()
+}
+
+#[cfg(target_arch = "wasm32")]
+pub(crate) fn save(key: &str, contents: &str) -> Result<(), String> {
+    local_storage()
+        .ok_or("localStorage unavailable")?
+        .set_item(&format!("cowboys.{key}"), contents)
+        .map_err(|e| format!("{e:?}"))
+}
+
+#[cfg(target_arch = "wasm32")]
+pub(crate) fn load(key: &str) -> Option<String> {
+    local_storage()?.get_item(&format!("cowboys.{key}")).ok()?
+}
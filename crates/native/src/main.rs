@@ -1,3 +1,42 @@
+use logic::{DirectConnect, LaunchOptions};
+
+const USAGE: &str = "usage: native [--host PORT | --connect IP:PORT] \
+                     [--netsim latency=MS,jitter=MS,loss=CHANCE,reorder=CHANCE,seed=N]";
+
 fn main() {
-    logic::run();
+    let options = match parse_args(std::env::args().skip(1)) {
+        Ok(options) => options,
+        Err(e) => {
+            eprintln!("{e}\n{USAGE}");
+            std::process::exit(2);
+        }
+    };
+    logic::run_with(options);
+}
+
+fn parse_args(mut args: impl Iterator<Item = String>) -> Result<LaunchOptions, String> {
+    let mut options = LaunchOptions::default();
+    while let Some(arg) = args.next() {
+        let value = match arg.as_str() {
+            "--host" | "--connect" | "--netsim" => {
+                args.next().ok_or(format!("missing value for {arg}"))?
+            }
+            _ => return Err(format!("unknown argument {arg}")),
+        };
+        if arg == "--netsim" {
+            options.network_conditions = Some(value.parse()?);
+            continue;
+        }
+        options.direct = Some(match arg.as_str() {
+            "--host" => {
+                DirectConnect::Host(value.parse().map_err(|_| format!("invalid port {value}"))?)
+            }
+            _ => DirectConnect::Connect(
+                value
+                    .parse()
+                    .map_err(|_| format!("invalid address {value}"))?,
+            ),
+        });
+    }
+    Ok(options)
 }
@@ -0,0 +1,331 @@
+//! Python bindings of the duel: round resolution, the reinforcement learning environment
+//! and replays, running the same Rust code as the game.
+//!
+//! Build and install in the current virtualenv with `maturin develop --release` from this
+//! directory, or build a wheel with `maturin build --release`.
+//!
+//! Actions are given as their index in `ACTIONS` or their name, and returned as names.
+
+use logic::bot::{action_word, parse_action, DEFAULT_MAX_ROUNDS};
+use logic::env::{BatchEnv as RustBatchEnv, Env as RustEnv, EnvConfig, OBSERVATION_SIZE};
+use logic::{
+    resolve_round as rust_resolve_round, Action, MatchRules, PlayerStats, Replay as RustReplay,
+    NUM_PLAYERS,
+};
+use pyo3::exceptions::PyValueError;
+use pyo3::prelude::*;
+use pyo3::types::PyDict;
+
+fn value_error(e: impl ToString) -> PyErr {
+    PyValueError::new_err(e.to_string())
+}
+
+fn action(value: &Bound<'_, PyAny>) -> PyResult<Action> {
+    if let Ok(index) = value.extract::<usize>() {
+        return Action::ALL
+            .get(index)
+            .copied()
+            .ok_or_else(|| value_error(format!("no action {index}")));
+    }
+    parse_action(&value.extract::<String>()?).map_err(value_error)
+}
+
+fn actions(values: &[Bound<'_, PyAny>]) -> PyResult<[Action; NUM_PLAYERS]> {
+    let [first, second] = values else {
+        return Err(value_error(format!("expected {NUM_PLAYERS} actions")));
+    };
+    Ok([action(first)?, action(second)?])
+}
+
+fn player_tuples(players: [PlayerStats; NUM_PLAYERS]) -> Vec<(i32, i32)> {
+    players.iter().map(|p| (p.health, p.ammo)).collect()
+}
+
+fn check_player(player: usize) -> PyResult<()> {
+    if player < NUM_PLAYERS {
+        Ok(())
+    } else {
+        Err(value_error(format!("no player {player}")))
+    }
+}
+
+/// Match rules, the fields left out keeping their default.
+#[pyclass]
+#[derive(Clone)]
+struct Rules {
+    rules: MatchRules,
+}
+
+#[pymethods]
+impl Rules {
+    #[new]
+    #[pyo3(signature = (starting_health=None, starting_ammo=None))]
+    fn new(starting_health: Option<i32>, starting_ammo: Option<i32>) -> Self {
+        let mut rules = MatchRules::default();
+        rules.starting_health = starting_health.unwrap_or(rules.starting_health);
+        rules.starting_ammo = starting_ammo.unwrap_or(rules.starting_ammo);
+        Self { rules }
+    }
+
+    #[getter]
+    fn starting_health(&self) -> i32 {
+        self.rules.starting_health
+    }
+
+    #[setter]
+    fn set_starting_health(&mut self, value: i32) {
+        self.rules.starting_health = value;
+    }
+
+    #[getter]
+    fn starting_ammo(&self) -> i32 {
+        self.rules.starting_ammo
+    }
+
+    #[setter]
+    fn set_starting_ammo(&mut self, value: i32) {
+        self.rules.starting_ammo = value;
+    }
+
+    #[getter]
+    fn decision_frames(&self) -> i32 {
+        self.rules.decision_frames
+    }
+
+    #[getter]
+    fn display_frames(&self) -> i32 {
+        self.rules.display_frames
+    }
+
+    fn __repr__(&self) -> String {
+        format!(
+            "Rules(starting_health={}, starting_ammo={})",
+            self.rules.starting_health, self.rules.starting_ammo
+        )
+    }
+}
+
+fn env_config(rules: Option<Rules>, max_rounds: u32, random_start: bool) -> EnvConfig {
+    EnvConfig {
+        rules: rules.map(|r| r.rules).unwrap_or_default(),
+        max_rounds,
+        random_start,
+    }
+}
+
+/// Resolves a round: `players` are (health, ammo) pairs, the result is the stats after it.
+#[pyfunction]
+fn resolve_round(
+    players: Vec<(i32, i32)>,
+    actions: Vec<Bound<'_, PyAny>>,
+) -> PyResult<Vec<(i32, i32)>> {
+    let [first, second] = players[..] else {
+        return Err(value_error(format!("expected {NUM_PLAYERS} players")));
+    };
+    let players = [first, second].map(|(health, ammo)| PlayerStats { health, ammo });
+    Ok(player_tuples(rust_resolve_round(
+        players,
+        self::actions(&actions)?,
+    )))
+}
+
+/// A duel where both players act every step, see `logic::env`.
+#[pyclass]
+struct Env {
+    env: RustEnv,
+}
+
+#[pymethods]
+impl Env {
+    #[new]
+    #[pyo3(signature = (rules=None, max_rounds=DEFAULT_MAX_ROUNDS, random_start=false))]
+    fn new(rules: Option<Rules>, max_rounds: u32, random_start: bool) -> Self {
+        Self {
+            env: RustEnv::new(env_config(rules, max_rounds, random_start)),
+        }
+    }
+
+    /// Starts an episode, returning the observation of each player.
+    #[pyo3(signature = (seed=0))]
+    fn reset(&mut self, seed: u64) -> Vec<Vec<f32>> {
+        self.env.reset(seed).iter().map(|o| o.to_vec()).collect()
+    }
+
+    /// Plays a round, returning (observations, rewards, done).
+    fn step(
+        &mut self,
+        actions: Vec<Bound<'_, PyAny>>,
+    ) -> PyResult<(Vec<Vec<f32>>, Vec<f32>, bool)> {
+        let step = self
+            .env
+            .step(self::actions(&actions)?)
+            .map_err(value_error)?;
+        Ok((
+            step.observations.iter().map(|o| o.to_vec()).collect(),
+            step.rewards.to_vec(),
+            step.done,
+        ))
+    }
+
+    fn legal_actions(&self, player: usize) -> PyResult<Vec<&'static str>> {
+        check_player(player)?;
+        Ok(self
+            .env
+            .legal_actions(player)
+            .into_iter()
+            .map(action_word)
+            .collect())
+    }
+
+    /// Whether each of `ACTIONS` is legal.
+    fn legal_mask(&self, player: usize) -> PyResult<Vec<bool>> {
+        check_player(player)?;
+        Ok(self.env.legal_mask(player).to_vec())
+    }
+
+    /// (health, ammo) of each player.
+    #[getter]
+    fn players(&self) -> Vec<(i32, i32)> {
+        player_tuples(self.env.stats())
+    }
+
+    #[getter]
+    fn done(&self) -> bool {
+        self.env.is_done()
+    }
+}
+
+/// Environments stepped together with flat observations, rewards and masks, starting a new
+/// episode as soon as one ends.
+#[pyclass]
+struct BatchEnv {
+    batch: RustBatchEnv,
+}
+
+#[pymethods]
+impl BatchEnv {
+    #[new]
+    #[pyo3(signature = (count, rules=None, max_rounds=DEFAULT_MAX_ROUNDS, random_start=false))]
+    fn new(count: usize, rules: Option<Rules>, max_rounds: u32, random_start: bool) -> Self {
+        Self {
+            batch: RustBatchEnv::new(count, env_config(rules, max_rounds, random_start)),
+        }
+    }
+
+    fn __len__(&self) -> usize {
+        self.batch.len()
+    }
+
+    /// Returns `len * 2 * OBSERVATION_SIZE` values.
+    #[pyo3(signature = (seed=0))]
+    fn reset(&mut self, seed: u64) -> Vec<f32> {
+        self.batch.reset(seed)
+    }
+
+    /// Takes the pair of actions of every environment, returns (observations, rewards,
+    /// dones, final_observations). Where an episode ended, observations are those of the
+    /// next one and final_observations holds the last ones of the episode, elsewhere None.
+    #[allow(clippy::type_complexity)]
+    fn step(
+        &mut self,
+        actions: Vec<Vec<Bound<'_, PyAny>>>,
+    ) -> PyResult<(Vec<f32>, Vec<f32>, Vec<bool>, Vec<Option<Vec<f32>>>)> {
+        let actions = actions
+            .iter()
+            .map(|pair| self::actions(pair))
+            .collect::<PyResult<Vec<_>>>()?;
+        let step = self.batch.step(&actions).map_err(value_error)?;
+        Ok((
+            step.observations,
+            step.rewards,
+            step.dones,
+            step.final_observations,
+        ))
+    }
+
+    /// `len * 2 * len(ACTIONS)` values.
+    fn legal_masks(&self) -> Vec<bool> {
+        self.batch.legal_masks()
+    }
+}
+
+/// A recorded match.
+#[pyclass]
+struct Replay {
+    replay: RustReplay,
+}
+
+#[pymethods]
+impl Replay {
+    #[getter]
+    fn rules(&self) -> Rules {
+        Rules {
+            rules: self.replay.rules.clone(),
+        }
+    }
+
+    #[getter]
+    fn seed(&self) -> u64 {
+        self.replay.seed
+    }
+
+    #[getter]
+    fn frames(&self) -> usize {
+        self.replay.inputs.len()
+    }
+
+    /// Handle of the player who saved the replay, if known.
+    #[getter]
+    fn local_player(&self) -> Option<usize> {
+        self.replay
+            .players
+            .iter()
+            .find(|player| player.local)
+            .map(|player| player.handle)
+    }
+
+    /// One dict per round: the frame it was resolved on, the (health, ammo) of the players
+    /// before it and their actions.
+    fn rounds<'py>(&self, py: Python<'py>) -> PyResult<Vec<Bound<'py, PyDict>>> {
+        let rounds = self.replay.rounds().map_err(value_error)?;
+        rounds
+            .iter()
+            .map(|round| {
+                let dict = PyDict::new(py);
+                dict.set_item("frame", round.frame)?;
+                dict.set_item("players", player_tuples(round.players))?;
+                dict.set_item("actions", round.actions.map(action_word).to_vec())?;
+                Ok(dict)
+            })
+            .collect()
+    }
+}
+
+/// Reads a replay saved by the game.
+#[pyfunction]
+fn parse_replay(json: &str) -> PyResult<Replay> {
+    Ok(Replay {
+        replay: RustReplay::from_json(json).map_err(value_error)?,
+    })
+}
+
+#[pyfunction]
+fn load_replay(path: &str) -> PyResult<Replay> {
+    let json = std::fs::read_to_string(path).map_err(value_error)?;
+    parse_replay(&json)
+}
+
+#[pymodule]
+fn cowboys(m: &Bound<'_, PyModule>) -> PyResult<()> {
+    m.add("ACTIONS", Action::ALL.map(action_word).to_vec())?;
+    m.add("OBSERVATION_SIZE", OBSERVATION_SIZE)?;
+    m.add("NUM_PLAYERS", NUM_PLAYERS)?;
+    m.add_class::<Rules>()?;
+    m.add_class::<Env>()?;
+    m.add_class::<BatchEnv>()?;
+    m.add_class::<Replay>()?;
+    m.add_function(wrap_pyfunction!(resolve_round, m)?)?;
+    m.add_function(wrap_pyfunction!(parse_replay, m)?)?;
+    m.add_function(wrap_pyfunction!(load_replay, m)?)?;
+    Ok(())
+}
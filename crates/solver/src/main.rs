@@ -0,0 +1,99 @@
+//! Prints the equilibrium strategy of every state of the duel, see `logic::solver`.
+
+use logic::solver::{solve, SolverConfig};
+use logic::MatchRules;
+
+const USAGE: &str = "usage: solver [--health N] [--starting-ammo N] [--max-ammo N] [--discount X]
+              [--tremble X] [--csv]";
+
+struct Options {
+    rules: MatchRules,
+    config: SolverConfig,
+    csv: bool,
+}
+
+fn main() {
+    let options = match parse_args(std::env::args().skip(1)) {
+        Ok(options) => options,
+        Err(e) => {
+            eprintln!("{e}\n{USAGE}");
+            std::process::exit(2);
+        }
+    };
+
+    let solution = solve(&options.rules, &options.config);
+    if options.csv {
+        println!("hp,opponent_hp,ammo,opponent_ammo,value,reload,shield,fire");
+    } else {
+        eprintln!(
+            "solved {} HP, up to {} ammo, in {} iterations",
+            solution.max_health, solution.max_ammo, solution.iterations
+        );
+    }
+    let mut health = None;
+    for (state, solved) in solution.states() {
+        let [me, opponent] = state;
+        let [reload, shield, fire] = solved.strategies[0];
+        if options.csv {
+            println!(
+                "{},{},{},{},{:.6},{:.6},{:.6},{:.6}",
+                me.health,
+                opponent.health,
+                me.ammo,
+                opponent.ammo,
+                solved.value,
+                reload,
+                shield,
+                fire
+            );
+            continue;
+        }
+        if health != Some((me.health, opponent.health)) {
+            health = Some((me.health, opponent.health));
+            println!("\n{} HP against {} HP", me.health, opponent.health);
+            println!(
+                "{:>5} {:>14} {:>7} {:>7} {:>7} {:>7}",
+                "ammo", "opponent ammo", "value", "reload", "shield", "fire"
+            );
+        }
+        println!(
+            "{:>5} {:>14} {:>+7.3} {:>7.3} {:>7.3} {:>7.3}",
+            me.ammo, opponent.ammo, solved.value, reload, shield, fire
+        );
+    }
+}
+
+fn parse_args(mut args: impl Iterator<Item = String>) -> Result<Options, String> {
+    let mut options = Options {
+        rules: MatchRules::default(),
+        config: SolverConfig::default(),
+        csv: false,
+    };
+    while let Some(arg) = args.next() {
+        if arg == "--csv" {
+            options.csv = true;
+            continue;
+        }
+        let value = args.next().ok_or(format!("missing value for {arg}"))?;
+        let integer = || value.parse().map_err(|_| format!("invalid number {value}"));
+        let real = || value.parse().map_err(|_| format!("invalid number {value}"));
+        match arg.as_str() {
+            "--health" => options.rules.starting_health = integer()?,
+            "--starting-ammo" => options.rules.starting_ammo = integer()?,
+            "--max-ammo" => options.config.max_ammo = integer()?,
+            "--discount" => options.config.discount = real()?,
+            "--tremble" => options.config.tremble = real()?,
+            _ => return Err(format!("unknown argument {arg}")),
+        }
+    }
+    if options.rules.starting_health < 1 {
+        return Err("health must be at least 1".to_string());
+    }
+    if !(0.0..1.0).contains(&options.config.discount) {
+        return Err("discount must be in [0, 1)".to_string());
+    }
+    if !(0.0..=1.0).contains(&options.config.tremble) {
+        return Err("tremble must be in [0, 1]".to_string());
+    }
+    Ok(options)
+}
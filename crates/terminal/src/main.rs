@@ -0,0 +1,436 @@
+//! Plays the duel in a terminal, against a bot or over a direct UDP connection with a
+//! player running the game or this client, for headless servers and SSH sessions.
+
+mod ui;
+
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use logic::ai::{builtin, BUILTIN_BOTS};
+use logic::bot::{split_command, Bot, BotTurns, ProcessBot, SeatedBot};
+use logic::netplay::{DirectConnection, NetMatch};
+use logic::sim::{MatchSim, Phase};
+use logic::{
+    Action, DirectConnect, MatchRules, PlayerInput, Replay, ReplayPlayer, ReplayRound, NUM_PLAYERS,
+    REPLAY_VERSION,
+};
+use ratatui::crossterm::event::{self, Event, KeyCode, KeyEventKind};
+use ratatui::DefaultTerminal;
+
+const USAGE: &str = "usage: terminal [--bot BOT | --host PORT | --connect IP:PORT]
+BOT is a built-in bot or the command running a bot, e.g. \"python3 bot.py\"";
+
+/// Bot played when neither a bot nor a connection is given.
+const DEFAULT_BOT: &str = "cautious";
+
+/// Duration of a frame, the game runs at 60 frames per second.
+const FRAME_DURATION: Duration = Duration::from_micros(16_667);
+
+enum Options {
+    Bot(String),
+    Direct(DirectConnect),
+}
+
+pub(crate) enum Opponent {
+    Bot {
+        bot: SeatedBot,
+        name: String,
+        seed: u64,
+        turns: BotTurns,
+        rounds: Vec<ReplayRound>,
+        inputs: Vec<Vec<Vec<u8>>>,
+    },
+    Remote(Box<NetMatch>),
+}
+
+/// Why the match stopped before its end.
+pub(crate) enum Stopped {
+    BotError(String),
+    Network(String),
+    Desync(i32),
+    Forfeit,
+}
+
+pub(crate) struct Game {
+    pub(crate) sim: MatchSim,
+    pub(crate) rules: MatchRules,
+    /// Handle of the player at the keyboard.
+    pub(crate) me: usize,
+    /// Action picked with the keyboard, sent once the round takes actions.
+    pub(crate) picked: Option<Action>,
+    pub(crate) opponent: Opponent,
+    pub(crate) stopped: Option<Stopped>,
+}
+
+impl Game {
+    fn against_bot(command: &str) -> Result<Self, String> {
+        let seed = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |time| time.as_nanos() as u64);
+        let (bot, name) = match builtin(command, seed) {
+            Some(mut bot) => {
+                bot.new_game()?;
+                let name = bot.name();
+                (SeatedBot::Builtin(bot), name)
+            }
+            None => {
+                let (program, args) = split_command(command)?;
+                let mut bot = ProcessBot::spawn(&program, &args)?;
+                bot.new_game()?;
+                let name = bot.name();
+                (SeatedBot::Process(bot), name)
+            }
+        };
+        let rules = MatchRules::default();
+        Ok(Self {
+            sim: MatchSim::from_rules(&rules),
+            rules,
+            me: 0,
+            picked: None,
+            opponent: Opponent::Bot {
+                bot,
+                name,
+                seed,
+                turns: BotTurns::default(),
+                rounds: vec![],
+                inputs: vec![],
+            },
+            stopped: None,
+        })
+    }
+
+    fn online(net: NetMatch) -> Self {
+        let rules = net.rules().clone();
+        Self {
+            sim: MatchSim::from_rules(&rules),
+            rules,
+            me: net.local_handle(),
+            picked: None,
+            opponent: Opponent::Remote(Box::new(net)),
+            stopped: None,
+        }
+    }
+
+    pub(crate) fn over(&self) -> bool {
+        self.stopped.is_some() || self.sim.phase() == Phase::Over
+    }
+
+    /// The match so far, online the inputs of the latest frames may still be predictions.
+    pub(crate) fn replay(&self) -> Replay {
+        let (inputs, seed) = match &self.opponent {
+            Opponent::Bot { inputs, seed, .. } => (inputs.clone(), *seed),
+            Opponent::Remote(net) => (net.inputs().to_vec(), net.seed()),
+        };
+        Replay {
+            version: REPLAY_VERSION,
+            rules: self.rules.clone(),
+            seed,
+            start: None,
+            players: (0..NUM_PLAYERS)
+                .map(|handle| ReplayPlayer {
+                    handle,
+                    local: handle == self.me,
+                })
+                .collect(),
+            inputs,
+            forfeit: matches!(self.stopped, Some(Stopped::Forfeit))
+                .then(|| (self.me + 1) % NUM_PLAYERS),
+        }
+    }
+
+    /// Rounds played so far, online the latest ones may still be predictions.
+    pub(crate) fn rounds(&self) -> &[ReplayRound] {
+        match &self.opponent {
+            Opponent::Bot { rounds, .. } => rounds,
+            Opponent::Remote(net) => net.rounds(),
+        }
+    }
+
+    /// Runs a frame, returns whether the next one should come a bit later.
+    fn step(&mut self) -> bool {
+        if self.over() {
+            return false;
+        }
+        // an action picked while the last round is shown is kept for the next one
+        let showing = matches!(self.sim.phase(), Phase::Showing { .. });
+        let mine = PlayerInput {
+            action: if showing { None } else { self.picked.take() },
+            target: ((self.me + 1) % NUM_PLAYERS) as u8,
+            ..Default::default()
+        };
+        match &mut self.opponent {
+            Opponent::Bot {
+                bot,
+                turns,
+                rounds,
+                inputs,
+                ..
+            } => {
+                let seat = (self.me + 1) % NUM_PLAYERS;
+                let (phase, players, actions) =
+                    (self.sim.phase(), self.sim.players(), self.sim.actions());
+                // sent every frame once picked, like the game's bot seat does
+                let action = match turns.update(bot, seat, phase, players, actions) {
+                    Ok(action) => action,
+                    Err(e) => {
+                        self.stopped = Some(Stopped::BotError(e));
+                        return false;
+                    }
+                };
+                let theirs = PlayerInput {
+                    action,
+                    target: self.me as u8,
+                    ..Default::default()
+                };
+                let mut frame = vec![vec![]; NUM_PLAYERS];
+                frame[self.me] = mine.encode().to_vec();
+                frame[seat] = theirs.encode().to_vec();
+                let (now, players) = (self.sim.frame(), self.sim.players());
+                if let Some(actions) = self.sim.advance(&self.rules, &frame) {
+                    rounds.push(ReplayRound {
+                        frame: now,
+                        players,
+                        actions,
+                    });
+                }
+                inputs.push(frame);
+                false
+            }
+            Opponent::Remote(net) => {
+                if let Err(e) = net.advance(&mut self.sim, &mine) {
+                    self.stopped = Some(Stopped::Network(e));
+                } else if let Some(frame) = net.check_desync() {
+                    self.stopped = Some(Stopped::Desync(frame));
+                } else if net.opponent_gone() {
+                    self.stopped = Some(Stopped::Forfeit);
+                }
+                net.run_slow()
+            }
+        }
+    }
+}
+
+fn main() {
+    let options = match parse_args(std::env::args().skip(1)) {
+        Ok(options) => options,
+        Err(e) => {
+            eprintln!("{e}\n{USAGE}\nbuilt-in bots: {}", BUILTIN_BOTS.join(", "));
+            std::process::exit(2);
+        }
+    };
+    // fail before taking over the terminal when the bot or the socket can't start
+    let start = match options {
+        Options::Bot(command) => Game::against_bot(&command).map(|game| (Some(game), None)),
+        Options::Direct(direct) => {
+            DirectConnection::open(direct).map(|connection| (None, Some((direct, connection))))
+        }
+    };
+    let (game, connection) = match start {
+        Ok(start) => start,
+        Err(e) => {
+            eprintln!("{e}");
+            std::process::exit(1);
+        }
+    };
+
+    let mut terminal = ratatui::init();
+    let game = match connection {
+        Some((direct, connection)) => {
+            connect(&mut terminal, direct, connection).map(|net| net.map(Game::online))
+        }
+        None => Ok(game),
+    };
+    let result = game.and_then(|game| match game {
+        Some(mut game) => play(&mut terminal, &mut game).map(|_| Some(game)),
+        None => Ok(None),
+    });
+    ratatui::restore();
+
+    match result {
+        Ok(Some(game)) => print_match(&game),
+        Ok(None) => {}
+        Err(e) => {
+            eprintln!("{e}");
+            std::process::exit(1);
+        }
+    }
+}
+
+/// Waits for the peer, returns `None` if the player gave up.
+fn connect(
+    terminal: &mut DefaultTerminal,
+    direct: DirectConnect,
+    mut connection: DirectConnection,
+) -> Result<Option<NetMatch>, String> {
+    loop {
+        if let Some(net) = connection.update()? {
+            return Ok(Some(net));
+        }
+        terminal
+            .draw(|frame| ui::draw_connecting(frame, direct))
+            .map_err(|e| e.to_string())?;
+        if let Some(KeyCode::Char('q') | KeyCode::Esc) = next_key(FRAME_DURATION)? {
+            return Ok(None);
+        }
+    }
+}
+
+/// Runs the match at 60 frames per second until the player quits.
+fn play(terminal: &mut DefaultTerminal, game: &mut Game) -> Result<(), String> {
+    let mut next_frame = Instant::now();
+    loop {
+        terminal
+            .draw(|frame| ui::draw_game(frame, game))
+            .map_err(|e| e.to_string())?;
+        let wait = next_frame.saturating_duration_since(Instant::now());
+        match next_key(wait)? {
+            Some(KeyCode::Char('q') | KeyCode::Esc) => return Ok(()),
+            Some(KeyCode::Char(key)) if !game.over() => {
+                if let Some(action) = key_action(key) {
+                    game.picked = Some(action);
+                }
+            }
+            _ => {}
+        }
+        let now = Instant::now();
+        if now < next_frame {
+            continue;
+        }
+        // like bevy_ggrs, the peer ahead runs slower until the other catches up
+        let run_slow = game.step();
+        next_frame += if run_slow {
+            FRAME_DURATION.mul_f32(1.1)
+        } else {
+            FRAME_DURATION
+        };
+        // after a stall, go on from now rather than rushing the frames missed
+        if next_frame < now {
+            next_frame = now;
+        }
+    }
+}
+
+/// The key pressed within `timeout`, if any.
+fn next_key(timeout: Duration) -> Result<Option<KeyCode>, String> {
+    if !event::poll(timeout).map_err(|e| e.to_string())? {
+        return Ok(None);
+    }
+    match event::read().map_err(|e| e.to_string())? {
+        Event::Key(key) if key.kind == KeyEventKind::Press => Ok(Some(key.code)),
+        _ => Ok(None),
+    }
+}
+
+fn key_action(key: char) -> Option<Action> {
+    match key.to_ascii_lowercase() {
+        'r' | '1' => Some(Action::Reload),
+        's' | '2' => Some(Action::Shield),
+        'f' | '3' => Some(Action::Fire),
+        _ => None,
+    }
+}
+
+/// Prints the match in notation once the terminal is restored, to keep or share it.
+fn print_match(game: &Game) {
+    let mut record = match game.replay().to_record() {
+        Ok(record) => record,
+        Err(e) => {
+            eprintln!("could not write the match: {e}");
+            return;
+        }
+    };
+    if record.rounds.is_empty() {
+        return;
+    }
+    if let Opponent::Bot { name, .. } = &game.opponent {
+        record.players[(game.me + 1) % NUM_PLAYERS] = name.clone();
+    }
+    println!("{record}");
+}
+
+fn parse_args(mut args: impl Iterator<Item = String>) -> Result<Options, String> {
+    let mut options = None;
+    while let Some(arg) = args.next() {
+        let value = match arg.as_str() {
+            "--bot" | "--host" | "--connect" => {
+                args.next().ok_or(format!("missing value for {arg}"))?
+            }
+            _ => return Err(format!("unknown argument {arg}")),
+        };
+        if options.is_some() {
+            return Err("expected a single opponent".to_string());
+        }
+        options = Some(match arg.as_str() {
+            "--bot" => Options::Bot(value),
+            "--host" => Options::Direct(DirectConnect::Host(
+                value.parse().map_err(|_| format!("invalid port {value}"))?,
+            )),
+            _ => Options::Direct(DirectConnect::Connect(
+                value
+                    .parse()
+                    .map_err(|_| format!("invalid address {value}"))?,
+            )),
+        });
+    }
+    Ok(options.unwrap_or_else(|| Options::Bot(DEFAULT_BOT.to_string())))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn args(args: &[&str]) -> Result<Options, String> {
+        parse_args(args.iter().map(|arg| arg.to_string()))
+    }
+
+    #[test]
+    fn parses_the_opponent() {
+        assert!(matches!(args(&[]), Ok(Options::Bot(bot)) if bot == DEFAULT_BOT));
+        assert!(matches!(
+            args(&["--bot", "python3 bot.py"]),
+            Ok(Options::Bot(bot)) if bot == "python3 bot.py"
+        ));
+        assert!(matches!(
+            args(&["--host", "7000"]),
+            Ok(Options::Direct(DirectConnect::Host(7000)))
+        ));
+        assert!(matches!(
+            args(&["--connect", "10.0.0.2:7000"]),
+            Ok(Options::Direct(DirectConnect::Connect(addr))) if addr.to_string() == "10.0.0.2:7000"
+        ));
+    }
+
+    #[test]
+    fn rejects_bad_arguments() {
+        assert!(args(&["--bot"]).is_err());
+        assert!(args(&["--host", "70000"]).is_err());
+        assert!(args(&["--connect", "10.0.0.2"]).is_err());
+        assert!(args(&["--bot", "random", "--host", "7000"]).is_err());
+        assert!(args(&["--seat", "1"]).is_err());
+    }
+
+    #[test]
+    fn keys_pick_actions() {
+        assert_eq!(key_action('r'), Some(Action::Reload));
+        assert_eq!(key_action('S'), Some(Action::Shield));
+        assert_eq!(key_action('3'), Some(Action::Fire));
+        assert_eq!(key_action('q'), None);
+    }
+
+    #[test]
+    fn bots_play_the_action_they_send() {
+        let mut game = Game::against_bot("random").unwrap();
+        while game.rounds().len() < 3 && !game.over() {
+            game.picked = Some(Action::Shield);
+            game.step();
+        }
+        assert!(game.stopped.is_none());
+        let inputs = game.replay().inputs;
+        let sent = |frame: usize| PlayerInput::decode(&inputs[frame][1]).unwrap().action;
+        // built-in bots answer on the first frame of a round
+        assert!(sent(0).is_some());
+        for round in game.rounds() {
+            let last_sent = (0..round.frame as usize).rev().find_map(sent);
+            assert_eq!(last_sent, Some(round.actions[1]));
+        }
+    }
+}
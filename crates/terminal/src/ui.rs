@@ -0,0 +1,185 @@
+use logic::bot::action_word;
+use logic::sim::Phase;
+use logic::{resolve_round, DirectConnect, PlayerStats, NUM_PLAYERS};
+use ratatui::layout::{Constraint, Layout, Rect};
+use ratatui::style::{Color, Style, Stylize};
+use ratatui::text::{Line, Span};
+use ratatui::widgets::{Block, Gauge, List, ListItem, Paragraph};
+use ratatui::Frame;
+
+use crate::{Game, Opponent, Stopped};
+
+pub(crate) fn draw_connecting(frame: &mut Frame, direct: DirectConnect) {
+    let waiting = match direct {
+        DirectConnect::Host(port) => format!("Waiting for a player on port {port}..."),
+        DirectConnect::Connect(addr) => format!("Connecting to {addr}..."),
+    };
+    let text = vec![Line::from(waiting), Line::from("q to give up".dark_gray())];
+    frame.render_widget(
+        Paragraph::new(text).block(Block::bordered().title(" Cowboys ")),
+        frame.area(),
+    );
+}
+
+pub(crate) fn draw_game(frame: &mut Frame, game: &Game) {
+    let [players, phase, log, status] = Layout::vertical([
+        Constraint::Length(4),
+        Constraint::Length(3),
+        Constraint::Min(3),
+        Constraint::Length(3),
+    ])
+    .areas(frame.area());
+
+    let them = (game.me + 1) % NUM_PLAYERS;
+    let names = player_names(game);
+    let stats = game.sim.players();
+    let [mine, theirs] = Layout::horizontal([Constraint::Fill(1); 2]).areas(players);
+    draw_player(frame, mine, &names[game.me], stats[game.me], Color::Green);
+    draw_player(frame, theirs, &names[them], stats[them], Color::Red);
+
+    draw_phase(frame, phase, game);
+    draw_log(frame, log, game, &names);
+    frame.render_widget(
+        Paragraph::new(status_line(game)).block(Block::bordered()),
+        status,
+    );
+}
+
+fn player_names(game: &Game) -> Vec<String> {
+    let them = match &game.opponent {
+        Opponent::Bot { name, .. } => name.clone(),
+        Opponent::Remote(_) => "Opponent".to_string(),
+    };
+    (0..NUM_PLAYERS)
+        .map(|handle| {
+            if handle == game.me {
+                "You".to_string()
+            } else {
+                them.clone()
+            }
+        })
+        .collect()
+}
+
+fn draw_player(frame: &mut Frame, area: Rect, name: &str, stats: PlayerStats, color: Color) {
+    let text = vec![
+        Line::from(vec![
+            "HP   ".into(),
+            Span::styled("♥ ".repeat(stats.health.max(0) as usize), color),
+            stats.health.to_string().dark_gray(),
+        ]),
+        Line::from(vec![
+            "Ammo ".into(),
+            "• ".repeat(stats.ammo.max(0) as usize).yellow(),
+            stats.ammo.to_string().dark_gray(),
+        ]),
+    ];
+    let block = Block::bordered().title(Span::styled(format!(" {name} "), color).bold());
+    frame.render_widget(Paragraph::new(text).block(block), area);
+}
+
+/// The current round, with a bar of the time left. The opponent's pick stays hidden
+/// until the actions are shown.
+fn draw_phase(frame: &mut Frame, area: Rect, game: &Game) {
+    let them = (game.me + 1) % NUM_PLAYERS;
+    let actions = game.sim.actions();
+    let now = game.sim.frame();
+    let (title, ratio, color) = match game.sim.phase() {
+        Phase::Starting => (" Get ready ".to_string(), 0.0, Color::Gray),
+        Phase::Deciding { from, until } => {
+            let pick = game.picked.unwrap_or(actions[game.me]);
+            let title = format!(
+                " Pick: r reload, s shield, f fire - you play {} ",
+                action_word(pick)
+            );
+            (title, left(now, from, until), Color::Cyan)
+        }
+        Phase::Showing { from, until } => {
+            let title = format!(
+                " You {}, they {} ",
+                action_word(actions[game.me]),
+                action_word(actions[them])
+            );
+            (title, left(now, from, until), Color::Magenta)
+        }
+        Phase::Over => (" Match over ".to_string(), 0.0, Color::Gray),
+    };
+    let gauge = Gauge::default()
+        .block(Block::bordered().title(title))
+        .gauge_style(Style::new().fg(color))
+        .ratio(ratio)
+        .label("");
+    frame.render_widget(gauge, area);
+}
+
+/// Share of the phase from `from` to `until` left at frame `now`.
+fn left(now: i32, from: i32, until: i32) -> f64 {
+    let length = (until - from).max(1) as f64;
+    ((until - now) as f64 / length).clamp(0.0, 1.0)
+}
+
+fn draw_log(frame: &mut Frame, area: Rect, game: &Game, names: &[String]) {
+    let them = (game.me + 1) % NUM_PLAYERS;
+    let rounds = game.rounds();
+    let shown = area.height.saturating_sub(2) as usize;
+    let items: Vec<ListItem> = rounds
+        .iter()
+        .enumerate()
+        .skip(rounds.len().saturating_sub(shown))
+        .map(|(i, round)| {
+            let after = resolve_round(round.players, round.actions);
+            ListItem::new(format!(
+                "{:>3}. {} {:<7} {} {:<7} -> {} {}/{}, {} {}/{}",
+                i + 1,
+                names[game.me],
+                action_word(round.actions[game.me]),
+                names[them],
+                action_word(round.actions[them]),
+                names[game.me],
+                after[game.me].health,
+                after[game.me].ammo,
+                names[them],
+                after[them].health,
+                after[them].ammo
+            ))
+        })
+        .collect();
+    frame.render_widget(
+        List::new(items).block(Block::bordered().title(" Rounds ")),
+        area,
+    );
+}
+
+fn status_line(game: &Game) -> Line<'static> {
+    let them = (game.me + 1) % NUM_PLAYERS;
+    let stats = game.sim.players();
+    if let Some(stopped) = &game.stopped {
+        let text = match stopped {
+            Stopped::BotError(e) => format!("The bot stopped playing: {e}"),
+            Stopped::Network(e) => format!("Connection error: {e}"),
+            Stopped::Desync(frame) => {
+                format!("The games went out of sync at frame {frame}, the match is void")
+            }
+            Stopped::Forfeit => "Your opponent did not come back, you win by forfeit!".into(),
+        };
+        return Line::from(vec![text.bold(), " - q to quit".dark_gray()]);
+    }
+    if game.sim.phase() == Phase::Over {
+        let result = match (stats[game.me].health > 0, stats[them].health > 0) {
+            (true, false) => "You win!".green(),
+            (false, true) => "You lose".red(),
+            _ => "Draw".yellow(),
+        };
+        return Line::from(vec![result.bold(), " - q to quit".dark_gray()]);
+    }
+    if let Opponent::Remote(net) = &game.opponent {
+        if let Some(timeout) = net.opponent_timeout() {
+            let remaining = timeout.as_secs() + 1;
+            return Line::from(format!("Waiting for opponent ({remaining} s)").yellow());
+        }
+        return Line::from(
+            format!("Online, input delay {} frames - q to quit", net.delay()).dark_gray(),
+        );
+    }
+    Line::from("q to quit".dark_gray())
+}
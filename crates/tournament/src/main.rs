@@ -0,0 +1,511 @@
+//! Plays tournaments between bots, built-in or speaking the line protocol of `logic::bot`.
+
+use std::collections::{HashMap, HashSet};
+use std::str::FromStr;
+
+use logic::ai::{builtin, BUILTIN_BOTS};
+use logic::bot::{play_duel, split_command, Bot, ProcessBot, DEFAULT_MAX_ROUNDS};
+use logic::MatchRules;
+use serde::Serialize;
+
+const USAGE: &str = "usage: tournament [--format round-robin|swiss] [--games N] [--swiss-rounds N]
+                  [--max-rounds N] [--seed N] [--output text|csv|json] BOT BOT...
+BOT is a built-in bot or the command running a protocol bot, e.g. \"python3 bot.py\"";
+
+const INITIAL_ELO: f64 = 1500.0;
+const ELO_K: f64 = 16.0;
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Format {
+    RoundRobin,
+    Swiss,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Output {
+    Text,
+    Csv,
+    Json,
+}
+
+struct Options {
+    bots: Vec<String>,
+    format: Format,
+    /// Duels per pairing, players switching seats every duel.
+    games: u32,
+    swiss_rounds: u32,
+    max_rounds: u32,
+    seed: u64,
+    output: Output,
+}
+
+struct Entrant {
+    name: String,
+    bot: Box<dyn Bot>,
+    elo: f64,
+    /// Points from byes, in Swiss tournaments with an odd number of bots.
+    bye_points: f64,
+}
+
+/// Duels between two entrants, from the point of view of the first one.
+#[derive(Clone, Copy, Default)]
+struct Record {
+    wins: u32,
+    draws: u32,
+    losses: u32,
+}
+
+impl Record {
+    fn score(&self) -> f64 {
+        self.wins as f64 + self.draws as f64 / 2.0
+    }
+
+    fn games(&self) -> u32 {
+        self.wins + self.draws + self.losses
+    }
+}
+
+#[derive(Serialize)]
+struct Standing {
+    rank: usize,
+    name: String,
+    games: u32,
+    wins: u32,
+    draws: u32,
+    losses: u32,
+    score: f64,
+    elo: f64,
+}
+
+#[derive(Serialize)]
+struct PairResult {
+    first: String,
+    second: String,
+    first_wins: u32,
+    draws: u32,
+    second_wins: u32,
+}
+
+#[derive(Serialize)]
+struct Report {
+    standings: Vec<Standing>,
+    pairs: Vec<PairResult>,
+}
+
+struct Tournament {
+    rules: MatchRules,
+    max_rounds: u32,
+    entrants: Vec<Entrant>,
+    /// Keyed by entrant indices, the lower one first.
+    records: HashMap<(usize, usize), Record>,
+}
+
+impl Tournament {
+    /// Plays `games` duels between entrants `a` and `b`, switching seats every duel.
+    fn play(&mut self, a: usize, b: usize, games: u32) {
+        let (a, b) = (a.min(b), a.max(b));
+        for game in 0..games {
+            let (low, high) = self.entrants.split_at_mut(b);
+            let (first, second) = (&mut low[a], &mut high[0]);
+            let swapped = game % 2 == 1;
+            let seats: [&mut dyn Bot; 2] = if swapped {
+                [second.bot.as_mut(), first.bot.as_mut()]
+            } else {
+                [first.bot.as_mut(), second.bot.as_mut()]
+            };
+            let duel = play_duel(&self.rules, seats, self.max_rounds);
+            if let Some((loser, reason)) = &duel.forfeit {
+                let loser = if (*loser == 0) != swapped { a } else { b };
+                eprintln!("{} forfeits: {reason}", self.entrants[loser].name);
+            }
+            let record = self.records.entry((a, b)).or_default();
+            // score of `a`
+            let score = match duel.winner {
+                None => {
+                    record.draws += 1;
+                    0.5
+                }
+                Some(winner) if (winner == 0) != swapped => {
+                    record.wins += 1;
+                    1.0
+                }
+                Some(_) => {
+                    record.losses += 1;
+                    0.0
+                }
+            };
+            let change = elo_change(self.entrants[a].elo, self.entrants[b].elo, score);
+            self.entrants[a].elo += change;
+            self.entrants[b].elo -= change;
+        }
+    }
+
+    /// Record of entrant `i` against everyone.
+    fn total(&self, i: usize) -> Record {
+        let mut total = Record::default();
+        for (&(a, b), record) in &self.records {
+            if a == i {
+                total.wins += record.wins;
+                total.draws += record.draws;
+                total.losses += record.losses;
+            } else if b == i {
+                total.wins += record.losses;
+                total.draws += record.draws;
+                total.losses += record.wins;
+            }
+        }
+        total
+    }
+
+    fn score(&self, i: usize) -> f64 {
+        self.total(i).score() + self.entrants[i].bye_points
+    }
+
+    fn round_robin(&mut self, games: u32) {
+        for a in 0..self.entrants.len() {
+            for b in a + 1..self.entrants.len() {
+                self.play(a, b, games);
+            }
+        }
+    }
+
+    /// Pairs entrants with close scores each round, avoiding rematches when possible.
+    fn swiss(&mut self, rounds: u32, games: u32) {
+        let mut met: HashSet<(usize, usize)> = HashSet::new();
+        let mut byes: HashSet<usize> = HashSet::new();
+        for _ in 0..rounds {
+            let mut order: Vec<usize> = (0..self.entrants.len()).collect();
+            order.sort_by(|a, b| self.score(*b).total_cmp(&self.score(*a)));
+            let (pairings, bye) = swiss_pairings(order, &met, &byes);
+            if let Some(bye) = bye {
+                self.entrants[bye].bye_points += games as f64;
+                byes.insert(bye);
+            }
+            for (a, b) in pairings {
+                if !met.insert((a.min(b), a.max(b))) {
+                    eprintln!(
+                        "{} and {} meet again, no other opponent was left",
+                        self.entrants[a].name, self.entrants[b].name
+                    );
+                }
+                self.play(a, b, games);
+            }
+        }
+    }
+
+    fn report(&self) -> Report {
+        let mut order: Vec<usize> = (0..self.entrants.len()).collect();
+        order.sort_by(|a, b| {
+            self.score(*b)
+                .total_cmp(&self.score(*a))
+                .then(self.entrants[*b].elo.total_cmp(&self.entrants[*a].elo))
+        });
+        let standings = order
+            .iter()
+            .enumerate()
+            .map(|(rank, i)| {
+                let total = self.total(*i);
+                Standing {
+                    rank: rank + 1,
+                    name: self.entrants[*i].name.clone(),
+                    games: total.games(),
+                    wins: total.wins,
+                    draws: total.draws,
+                    losses: total.losses,
+                    score: self.score(*i),
+                    elo: self.entrants[*i].elo.round(),
+                }
+            })
+            .collect();
+        let mut pairs: Vec<_> = self.records.iter().collect();
+        pairs.sort_by_key(|(key, _)| **key);
+        let pairs = pairs
+            .into_iter()
+            .map(|(&(a, b), record)| PairResult {
+                first: self.entrants[a].name.clone(),
+                second: self.entrants[b].name.clone(),
+                first_wins: record.wins,
+                draws: record.draws,
+                second_wins: record.losses,
+            })
+            .collect();
+        Report { standings, pairs }
+    }
+}
+
+/// Pairings of a Swiss round, `order` ranking the entrants from the best score. With an odd
+/// number of entrants, the lowest ranked one who had no bye yet sits the round out, it is
+/// returned second. Pairs who `met` already only play again when nobody else is left.
+fn swiss_pairings(
+    mut order: Vec<usize>,
+    met: &HashSet<(usize, usize)>,
+    byes: &HashSet<usize>,
+) -> (Vec<(usize, usize)>, Option<usize>) {
+    let bye = if order.len() % 2 == 1 {
+        // `parse_args` allows no more rounds than entrants, there is always one left
+        let last = order
+            .iter()
+            .rposition(|i| !byes.contains(i))
+            .expect("every entrant had a bye");
+        Some(order.remove(last))
+    } else {
+        None
+    };
+    let mut pairings = vec![];
+    while !order.is_empty() {
+        let a = order.remove(0);
+        let opponent = order
+            .iter()
+            .position(|b| !met.contains(&(a.min(*b), a.max(*b))))
+            .unwrap_or(0);
+        pairings.push((a, order.remove(opponent)));
+    }
+    (pairings, bye)
+}
+
+/// Elo points the first player wins, or loses when negative, for a `score` of 1 for a
+/// win, 0.5 for a draw and 0 for a loss. The second player loses as many.
+fn elo_change(elo: f64, opponent_elo: f64, score: f64) -> f64 {
+    let expected = 1.0 / (1.0 + 10f64.powf((opponent_elo - elo) / 400.0));
+    ELO_K * (score - expected)
+}
+
+fn main() {
+    let options = match parse_args(std::env::args().skip(1)) {
+        Ok(options) => options,
+        Err(e) => {
+            eprintln!("{e}\n{USAGE}");
+            std::process::exit(2);
+        }
+    };
+
+    let mut entrants = vec![];
+    for (i, spec) in options.bots.iter().enumerate() {
+        let bot = match builtin(spec, options.seed.wrapping_add(i as u64)) {
+            Some(bot) => bot,
+            None => match split_command(spec)
+                .and_then(|(program, args)| ProcessBot::spawn(&program, &args))
+            {
+                Ok(bot) => Box::new(bot),
+                Err(e) => {
+                    eprintln!("{e}\nbuilt-in bots: {}", BUILTIN_BOTS.join(", "));
+                    std::process::exit(1);
+                }
+            },
+        };
+        // the same bot may enter more than once
+        let mut name = bot.name();
+        if options.bots[..i].contains(spec) {
+            name = format!("{name} #{}", i + 1);
+        }
+        entrants.push(Entrant {
+            name,
+            bot,
+            elo: INITIAL_ELO,
+            bye_points: 0.0,
+        });
+    }
+
+    let mut tournament = Tournament {
+        rules: MatchRules::default(),
+        max_rounds: options.max_rounds,
+        entrants,
+        records: HashMap::new(),
+    };
+    match options.format {
+        Format::RoundRobin => tournament.round_robin(options.games),
+        Format::Swiss => tournament.swiss(options.swiss_rounds, options.games),
+    }
+    let report = tournament.report();
+    match options.output {
+        Output::Text => print_text(&report),
+        Output::Csv => print_csv(&report),
+        Output::Json => println!("{}", serde_json::to_string_pretty(&report).unwrap()),
+    }
+}
+
+fn print_text(report: &Report) {
+    println!(
+        "{:>4}  {:<24} {:>6} {:>6} {:>6} {:>6} {:>8} {:>6}",
+        "rank", "bot", "games", "wins", "draws", "losses", "score", "elo"
+    );
+    for s in &report.standings {
+        println!(
+            "{:>4}  {:<24} {:>6} {:>6} {:>6} {:>6} {:>8} {:>6}",
+            s.rank, s.name, s.games, s.wins, s.draws, s.losses, s.score, s.elo
+        );
+    }
+    println!();
+    for p in &report.pairs {
+        println!(
+            "{} vs {}: {} wins, {} draws, {} losses",
+            p.first, p.second, p.first_wins, p.draws, p.second_wins
+        );
+    }
+}
+
+/// Quotes `field` if it could be mistaken for several fields.
+fn csv_field(field: &str) -> String {
+    if field.contains([',', '"', '\n']) {
+        format!("\"{}\"", field.replace('"', "\"\""))
+    } else {
+        field.to_string()
+    }
+}
+
+/// Standings, a blank line, then the result of every pairing.
+fn print_csv(report: &Report) {
+    println!("rank,bot,games,wins,draws,losses,score,elo");
+    for s in &report.standings {
+        println!(
+            "{},{},{},{},{},{},{},{}",
+            s.rank,
+            csv_field(&s.name),
+            s.games,
+            s.wins,
+            s.draws,
+            s.losses,
+            s.score,
+            s.elo
+        );
+    }
+    println!();
+    println!("first,second,first_wins,draws,second_wins");
+    for p in &report.pairs {
+        println!(
+            "{},{},{},{},{}",
+            csv_field(&p.first),
+            csv_field(&p.second),
+            p.first_wins,
+            p.draws,
+            p.second_wins
+        );
+    }
+}
+
+fn parse_args(mut args: impl Iterator<Item = String>) -> Result<Options, String> {
+    let mut options = Options {
+        bots: vec![],
+        format: Format::RoundRobin,
+        games: 100,
+        swiss_rounds: 5,
+        max_rounds: DEFAULT_MAX_ROUNDS,
+        seed: 0,
+        output: Output::Text,
+    };
+    while let Some(arg) = args.next() {
+        if !arg.starts_with("--") {
+            options.bots.push(arg);
+            continue;
+        }
+        let value = args.next().ok_or(format!("missing value for {arg}"))?;
+        match arg.as_str() {
+            "--format" => {
+                options.format = match value.as_str() {
+                    "round-robin" => Format::RoundRobin,
+                    "swiss" => Format::Swiss,
+                    _ => return Err(format!("unknown format {value}")),
+                }
+            }
+            "--output" => {
+                options.output = match value.as_str() {
+                    "text" => Output::Text,
+                    "csv" => Output::Csv,
+                    "json" => Output::Json,
+                    _ => return Err(format!("unknown output {value}")),
+                }
+            }
+            "--games" => options.games = parse_number(&value)?,
+            "--swiss-rounds" => options.swiss_rounds = parse_number(&value)?,
+            "--max-rounds" => options.max_rounds = parse_number(&value)?,
+            "--seed" => options.seed = parse_number(&value)?,
+            _ => return Err(format!("unknown argument {arg}")),
+        }
+    }
+    if options.bots.len() < 2 {
+        return Err("expected at least two bots".to_string());
+    }
+    // every bot sits out a round at most once
+    if options.format == Format::Swiss
+        && options.bots.len() % 2 == 1
+        && options.swiss_rounds as usize > options.bots.len()
+    {
+        return Err(format!(
+            "with {} bots, a Swiss tournament has at most {} rounds",
+            options.bots.len(),
+            options.bots.len()
+        ));
+    }
+    Ok(options)
+}
+
+fn parse_number<T: FromStr>(value: &str) -> Result<T, String> {
+    value.parse().map_err(|_| format!("invalid number {value}"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn swiss_pairs_neighbours_and_avoids_rematches() {
+        let mut met = HashSet::new();
+        let (pairings, bye) = swiss_pairings(vec![0, 1, 2, 3], &met, &HashSet::new());
+        assert_eq!(pairings, [(0, 1), (2, 3)]);
+        assert_eq!(bye, None);
+
+        met.extend([(0, 1), (2, 3)]);
+        let (pairings, _) = swiss_pairings(vec![0, 1, 2, 3], &met, &HashSet::new());
+        assert_eq!(pairings, [(0, 2), (1, 3)]);
+
+        // 0 met everyone, it has to play someone again
+        met.extend([(0, 2), (0, 3)]);
+        let (pairings, _) = swiss_pairings(vec![0, 1, 2, 3], &met, &HashSet::new());
+        assert_eq!(pairings, [(0, 1), (2, 3)]);
+    }
+
+    #[test]
+    fn swiss_gives_each_entrant_one_bye() {
+        let mut byes = HashSet::new();
+        for _ in 0..5 {
+            let (pairings, bye) = swiss_pairings(vec![0, 1, 2, 3, 4], &HashSet::new(), &byes);
+            let bye = bye.unwrap();
+            assert!(byes.insert(bye));
+            assert!(pairings.iter().all(|(a, b)| *a != bye && *b != bye));
+            assert_eq!(pairings.len(), 2);
+        }
+        assert_eq!(byes.len(), 5);
+    }
+
+    #[test]
+    fn swiss_rounds_are_capped_by_the_byes() {
+        let args = |rounds: &str| {
+            ["--format", "swiss", "--swiss-rounds", rounds, "a", "b", "c"]
+                .map(String::from)
+                .to_vec()
+        };
+        assert!(parse_args(args("3").into_iter()).is_ok());
+        assert!(parse_args(args("4").into_iter()).is_err());
+    }
+
+    #[test]
+    fn elo_moves_by_the_surprise() {
+        assert_eq!(elo_change(1500.0, 1500.0, 1.0), ELO_K / 2.0);
+        assert_eq!(elo_change(1500.0, 1500.0, 0.5), 0.0);
+        // 400 points ahead, a win was expected 10 times out of 11
+        assert!((elo_change(1900.0, 1500.0, 1.0) - ELO_K / 11.0).abs() < 1e-9);
+        assert!((elo_change(1500.0, 1900.0, 1.0) - ELO_K * 10.0 / 11.0).abs() < 1e-9);
+        assert_eq!(
+            elo_change(1600.0, 1500.0, 0.0),
+            -elo_change(1500.0, 1600.0, 1.0)
+        );
+    }
+
+    #[test]
+    fn counts_must_fit_their_type() {
+        let args = |games: &str| ["--games", games, "a", "b"].map(String::from).to_vec();
+        assert_eq!(parse_args(args("4").into_iter()).unwrap().games, 4);
+        assert!(parse_args(args("4294967296").into_iter()).is_err());
+        assert!(parse_args(args("-1").into_iter()).is_err());
+    }
+}
@@ -0,0 +1,649 @@
+//! Correspondence matches: players seal their action for the round whenever they come by,
+//! and the server resolves the round once both did.
+//!
+//! `POST /matches` creates a match from a JSON object of `MatchRules` fields, `{}` for the
+//! default rules, and seats its creator. `POST /matches/:id/join` seats the opponent. Both
+//! answer with the player's `seat` and the `token` they act with, never shown again.
+//!
+//! `POST /matches/:id/actions` seals `{"token", "round", "action"}` for the current round,
+//! `action` being `reload`, `shield` or `fire`. `GET /matches/:id?token=...` shows the
+//! rounds resolved so far and who sealed an action for the current one, the token only
+//! revealing the player's own sealed action. `GET /matches/:id/notation` gives the match in
+//! notation.
+//!
+//! Every match is saved as JSON in `MATCHES_DIR` on each change and loaded back on start.
+//! Matches nobody joined within `OPEN_MATCH_EXPIRY` are deleted by a sweep running every
+//! `EXPIRY_SWEEP_INTERVAL`, and no more than `MAX_OPEN_MATCHES` wait for an opponent at once.
+
+use std::{
+    collections::HashMap,
+    fs, io,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use logic::bot::{action_word, parse_action, DEFAULT_MAX_ROUNDS};
+use logic::notation::{MatchRecord, MatchResult};
+use logic::{Action, MatchRules, PlayerStats, NUM_PLAYERS};
+use rand::{distributions::Alphanumeric, Rng};
+use serde::{Deserialize, Serialize};
+use warp::{http::StatusCode, reply::Response, Filter, Rejection, Reply};
+
+/// Directory the matches are saved in, relative to where the server runs.
+pub const MATCHES_DIR: &str = "matches";
+
+const ID_LENGTH: usize = 8;
+const TOKEN_LENGTH: usize = 24;
+/// Time an open match waits for an opponent before it is deleted.
+const OPEN_MATCH_EXPIRY: Duration = Duration::from_secs(7 * 24 * 60 * 60);
+/// Open matches beyond which new ones are refused until some are joined or expire.
+const MAX_OPEN_MATCHES: usize = 1000;
+/// Time between two deletions of the expired matches.
+const EXPIRY_SWEEP_INTERVAL: Duration = Duration::from_secs(60 * 60);
+
+/// A match played one sealed action at a time.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Match {
+    pub id: String,
+    pub rules: MatchRules,
+    /// Token of each seat, `None` until someone takes it.
+    tokens: [Option<String>; NUM_PLAYERS],
+    pub rounds: Vec<[Action; NUM_PLAYERS]>,
+    /// Actions sealed for the current round.
+    sealed: [Option<Action>; NUM_PLAYERS],
+    /// Seconds since the Unix epoch. Matches saved without it count from when they were
+    /// loaded.
+    #[serde(default = "now")]
+    created: u64,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum MatchStatus {
+    /// Waiting for an opponent to join.
+    Open,
+    Playing,
+    Won {
+        winner: usize,
+    },
+    Draw,
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum MatchError {
+    Full,
+    BadToken,
+    /// The action is for another round than the current one.
+    WrongRound(u32),
+    AlreadySealed,
+    Over,
+    NotStarted,
+}
+
+impl MatchError {
+    fn status(&self) -> StatusCode {
+        match self {
+            MatchError::BadToken => StatusCode::FORBIDDEN,
+            _ => StatusCode::CONFLICT,
+        }
+    }
+
+    fn message(&self) -> String {
+        match self {
+            MatchError::Full => "the match already has two players".to_string(),
+            MatchError::BadToken => "this token does not play this match".to_string(),
+            MatchError::WrongRound(round) => format!("the current round is {round}"),
+            MatchError::AlreadySealed => "an action was already sealed this round".to_string(),
+            MatchError::Over => "the match is over".to_string(),
+            MatchError::NotStarted => "the opponent has not joined yet".to_string(),
+        }
+    }
+}
+
+/// What a player gets back when taking a seat.
+#[derive(Serialize)]
+pub struct Seat {
+    pub id: String,
+    pub seat: usize,
+    pub token: String,
+}
+
+#[derive(Serialize)]
+pub struct RoundView {
+    pub actions: [&'static str; NUM_PLAYERS],
+    /// Stats after the round.
+    pub players: [PlayerStats; NUM_PLAYERS],
+}
+
+/// A match as one of its players, or anyone else, may see it.
+#[derive(Serialize)]
+pub struct MatchView {
+    pub id: String,
+    pub rules: MatchRules,
+    pub status: MatchStatus,
+    /// Round to seal an action for, starting at 1.
+    pub round: u32,
+    pub players: [PlayerStats; NUM_PLAYERS],
+    pub joined: [bool; NUM_PLAYERS],
+    pub sealed: [bool; NUM_PLAYERS],
+    pub rounds: Vec<RoundView>,
+    /// Seat of the token given, and the action it sealed this round.
+    pub seat: Option<usize>,
+    pub your_action: Option<&'static str>,
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |time| time.as_secs())
+}
+
+fn random_string(length: usize) -> String {
+    rand::thread_rng()
+        .sample_iter(&Alphanumeric)
+        .take(length)
+        .map(char::from)
+        .collect()
+}
+
+impl Match {
+    /// A new match, its creator taking the first seat. Its id is not `taken`.
+    pub fn create(rules: MatchRules, taken: impl Fn(&str) -> bool) -> (Self, Seat) {
+        let id = loop {
+            let id = random_string(ID_LENGTH);
+            if !taken(&id) {
+                break id;
+            }
+        };
+        let mut new_match = Self {
+            id,
+            rules,
+            tokens: Default::default(),
+            rounds: vec![],
+            sealed: [None; NUM_PLAYERS],
+            created: now(),
+        };
+        let seat = new_match.join().expect("a new match has free seats");
+        (new_match, seat)
+    }
+
+    pub fn join(&mut self) -> Result<Seat, MatchError> {
+        let seat = self
+            .tokens
+            .iter()
+            .position(Option::is_none)
+            .ok_or(MatchError::Full)?;
+        let token = random_string(TOKEN_LENGTH);
+        self.tokens[seat] = Some(token.clone());
+        Ok(Seat {
+            id: self.id.clone(),
+            seat,
+            token,
+        })
+    }
+
+    fn seat_of(&self, token: &str) -> Option<usize> {
+        self.tokens
+            .iter()
+            .position(|seat| seat.as_deref() == Some(token))
+    }
+
+    fn record(&self) -> MatchRecord {
+        let mut record = MatchRecord::new(self.rules.clone());
+        record.rounds = self.rounds.clone();
+        record.result = match record.outcome() {
+            Some(result) => result,
+            None if self.rounds.len() >= DEFAULT_MAX_ROUNDS as usize => MatchResult::Draw,
+            None => MatchResult::Unfinished,
+        };
+        record
+    }
+
+    pub fn players(&self) -> [PlayerStats; NUM_PLAYERS] {
+        *self.record().positions().last().unwrap()
+    }
+
+    /// Like duels between bots, a match lasting `DEFAULT_MAX_ROUNDS` rounds is a draw.
+    pub fn status(&self) -> MatchStatus {
+        if self.tokens.iter().any(Option::is_none) {
+            return MatchStatus::Open;
+        }
+        let players = self.players();
+        let alive: Vec<usize> = (0..NUM_PLAYERS)
+            .filter(|i| players[*i].health > 0)
+            .collect();
+        match alive[..] {
+            [winner] => MatchStatus::Won { winner },
+            [] => MatchStatus::Draw,
+            _ if self.rounds.len() >= DEFAULT_MAX_ROUNDS as usize => MatchStatus::Draw,
+            _ => MatchStatus::Playing,
+        }
+    }
+
+    /// Whether the match waited for an opponent for too long at `now`.
+    fn expired(&self, now: u64) -> bool {
+        self.status() == MatchStatus::Open
+            && now.saturating_sub(self.created) >= OPEN_MATCH_EXPIRY.as_secs()
+    }
+
+    pub fn current_round(&self) -> u32 {
+        self.rounds.len() as u32 + 1
+    }
+
+    /// Seals the action of the player with `token` for `round`, resolving the round once
+    /// both players sealed theirs.
+    pub fn submit(&mut self, token: &str, round: u32, action: Action) -> Result<(), MatchError> {
+        let seat = self.seat_of(token).ok_or(MatchError::BadToken)?;
+        match self.status() {
+            MatchStatus::Open => return Err(MatchError::NotStarted),
+            MatchStatus::Playing => {}
+            _ => return Err(MatchError::Over),
+        }
+        if round != self.current_round() {
+            return Err(MatchError::WrongRound(self.current_round()));
+        }
+        if self.sealed[seat].is_some() {
+            return Err(MatchError::AlreadySealed);
+        }
+        self.sealed[seat] = Some(action);
+        if let [Some(first), Some(second)] = self.sealed {
+            self.rounds.push([first, second]);
+            self.sealed = [None; NUM_PLAYERS];
+        }
+        Ok(())
+    }
+
+    /// The match as the player with `token` sees it, sealed actions staying hidden from
+    /// everyone else.
+    pub fn view(&self, token: Option<&str>) -> MatchView {
+        let seat = token.and_then(|token| self.seat_of(token));
+        let positions = self.record().positions();
+        MatchView {
+            id: self.id.clone(),
+            rules: self.rules.clone(),
+            status: self.status(),
+            round: self.current_round(),
+            players: *positions.last().unwrap(),
+            joined: self.tokens.clone().map(|token| token.is_some()),
+            sealed: self.sealed.map(|action| action.is_some()),
+            rounds: self
+                .rounds
+                .iter()
+                .zip(&positions[1..])
+                .map(|(actions, players)| RoundView {
+                    actions: actions.map(action_word),
+                    players: *players,
+                })
+                .collect(),
+            seat,
+            your_action: seat.and_then(|seat| self.sealed[seat]).map(action_word),
+        }
+    }
+
+    pub fn notation(&self) -> String {
+        self.record().to_string()
+    }
+}
+
+/// A match locked on its own, held while it is saved so saves keep the order of changes.
+type MatchCell = Arc<tokio::sync::Mutex<Match>>;
+
+/// Matches in memory, each one also saved in `dir`. The map is only locked to find or add
+/// a match, never while one is saved.
+pub struct Correspondence {
+    matches: Mutex<HashMap<String, MatchCell>>,
+    dir: PathBuf,
+}
+
+pub type Matches = Arc<Correspondence>;
+
+impl Correspondence {
+    /// Loads the matches saved in `dir`, creating it if needed.
+    pub fn load(dir: impl AsRef<Path>) -> io::Result<Matches> {
+        let dir = dir.as_ref().to_path_buf();
+        fs::create_dir_all(&dir)?;
+        let mut matches = HashMap::new();
+        for entry in fs::read_dir(&dir)? {
+            let path = entry?.path();
+            if path.extension().and_then(|ext| ext.to_str()) != Some("json") {
+                continue;
+            }
+            match serde_json::from_str::<Match>(&fs::read_to_string(&path)?) {
+                Ok(saved) => {
+                    matches.insert(saved.id.clone(), Arc::new(saved.into()));
+                }
+                Err(e) => eprintln!("skipping {}: {e}", path.display()),
+            }
+        }
+        Ok(Arc::new(Self {
+            matches: Mutex::new(matches),
+            dir,
+        }))
+    }
+
+    fn get(&self, id: &str) -> Option<MatchCell> {
+        self.matches.lock().unwrap().get(id).cloned()
+    }
+
+    fn path(&self, id: &str) -> PathBuf {
+        self.dir.join(format!("{id}.json"))
+    }
+
+    /// Writes the match to a temporary file first, so a crash never leaves half of it.
+    async fn save(&self, saved: &Match) -> io::Result<()> {
+        let path = self.path(&saved.id);
+        let temporary = path.with_extension("json.tmp");
+        tokio::fs::write(&temporary, serde_json::to_string(saved)?).await?;
+        tokio::fs::rename(temporary, path).await
+    }
+
+    /// Deletes the open matches that waited too long. Those being changed are left for the
+    /// next sweep.
+    async fn remove_expired(&self) {
+        let now = now();
+        let expired: Vec<String> = {
+            let mut all = self.matches.lock().unwrap();
+            let expired: Vec<String> = all
+                .iter()
+                .filter(|(_, cell)| cell.try_lock().is_ok_and(|open| open.expired(now)))
+                .map(|(id, _)| id.clone())
+                .collect();
+            for id in &expired {
+                all.remove(id);
+            }
+            expired
+        };
+        for id in expired {
+            if let Err(e) = tokio::fs::remove_file(self.path(&id)).await {
+                eprintln!("could not delete expired match {id}: {e}");
+            }
+        }
+    }
+}
+
+/// Deletes the expired matches every `EXPIRY_SWEEP_INTERVAL`, for as long as the server runs.
+pub async fn sweep_expired(matches: Matches) {
+    let mut interval = tokio::time::interval(EXPIRY_SWEEP_INTERVAL);
+    loop {
+        interval.tick().await;
+        matches.remove_expired().await;
+    }
+}
+
+/// Matches waiting for an opponent at `now`. Those being changed count as open, the limit
+/// needs not be exact.
+fn open_matches(all: &HashMap<String, MatchCell>, now: u64) -> usize {
+    all.values()
+        .filter(|cell| match cell.try_lock() {
+            Ok(open) => open.status() == MatchStatus::Open && !open.expired(now),
+            Err(_) => true,
+        })
+        .count()
+}
+
+#[derive(Deserialize)]
+pub struct Submission {
+    pub token: String,
+    pub round: u32,
+    pub action: String,
+}
+
+#[derive(Default, Deserialize)]
+pub struct TokenQuery {
+    pub token: Option<String>,
+}
+
+pub fn routes(matches: Matches) -> impl Filter<Extract = impl Reply, Error = Rejection> + Clone {
+    let with_matches = warp::any().map(move || matches.clone());
+    let token = warp::query::<TokenQuery>()
+        .or(warp::any().map(TokenQuery::default))
+        .unify();
+
+    let create = warp::path!("matches")
+        .and(warp::post())
+        .and(warp::body::content_length_limit(1024))
+        .and(warp::body::json())
+        .and(with_matches.clone())
+        .and_then(create_match);
+    let join = warp::path!("matches" / String / "join")
+        .and(warp::post())
+        .and(with_matches.clone())
+        .and_then(join_match);
+    let submit = warp::path!("matches" / String / "actions")
+        .and(warp::post())
+        .and(warp::body::content_length_limit(1024))
+        .and(warp::body::json())
+        .and(with_matches.clone())
+        .and_then(submit_action);
+    let show = warp::path!("matches" / String)
+        .and(warp::get())
+        .and(token)
+        .and(with_matches.clone())
+        .and_then(show_match);
+    let notation = warp::path!("matches" / String / "notation")
+        .and(warp::get())
+        .and(with_matches)
+        .and_then(match_notation);
+
+    create.or(join).or(submit).or(show).or(notation)
+}
+
+fn error(status: StatusCode, message: impl Into<String>) -> Response {
+    warp::reply::with_status(message.into(), status).into_response()
+}
+
+fn not_found() -> Response {
+    error(StatusCode::NOT_FOUND, "no such match")
+}
+
+fn save_error(e: io::Error) -> Response {
+    eprintln!("could not save a match: {e}");
+    error(
+        StatusCode::INTERNAL_SERVER_ERROR,
+        "could not save the match",
+    )
+}
+
+async fn create_match(rules: MatchRules, matches: Matches) -> Result<Response, Rejection> {
+    if rules.starting_health < 1 || rules.starting_ammo < 0 {
+        return Ok(error(StatusCode::BAD_REQUEST, "invalid starting stats"));
+    }
+    // added locked, so its id stays taken while it is saved but nobody sees it before
+    let (new_match, seat) = {
+        let mut all = matches.matches.lock().unwrap();
+        if open_matches(&all, now()) >= MAX_OPEN_MATCHES {
+            return Ok(error(
+                StatusCode::SERVICE_UNAVAILABLE,
+                "too many matches are waiting for an opponent, join one instead",
+            ));
+        }
+        let (new_match, seat) = Match::create(rules, |id| all.contains_key(id));
+        let cell: MatchCell = Arc::new(new_match.into());
+        let new_match = cell.clone().try_lock_owned().unwrap();
+        all.insert(seat.id.clone(), cell);
+        (new_match, seat)
+    };
+    if let Err(e) = matches.save(&new_match).await {
+        matches.matches.lock().unwrap().remove(&seat.id);
+        return Ok(save_error(e));
+    }
+    Ok(warp::reply::json(&seat).into_response())
+}
+
+async fn join_match(id: String, matches: Matches) -> Result<Response, Rejection> {
+    let Some(cell) = matches.get(&id) else {
+        return Ok(not_found());
+    };
+    let mut joined = cell.lock().await;
+    // the sweep may not have deleted it yet
+    if joined.expired(now()) {
+        return Ok(not_found());
+    }
+    let mut updated = joined.clone();
+    let seat = match updated.join() {
+        Ok(seat) => seat,
+        Err(e) => return Ok(error(e.status(), e.message())),
+    };
+    if let Err(e) = matches.save(&updated).await {
+        return Ok(save_error(e));
+    }
+    *joined = updated;
+    Ok(warp::reply::json(&seat).into_response())
+}
+
+async fn submit_action(
+    id: String,
+    submission: Submission,
+    matches: Matches,
+) -> Result<Response, Rejection> {
+    let action = match parse_action(&submission.action) {
+        Ok(action) => action,
+        Err(e) => return Ok(error(StatusCode::BAD_REQUEST, e)),
+    };
+    let Some(cell) = matches.get(&id) else {
+        return Ok(not_found());
+    };
+    let mut played = cell.lock().await;
+    // only keep the action once it is saved
+    let mut updated = played.clone();
+    if let Err(e) = updated.submit(&submission.token, submission.round, action) {
+        return Ok(error(e.status(), e.message()));
+    }
+    if let Err(e) = matches.save(&updated).await {
+        return Ok(save_error(e));
+    }
+    *played = updated;
+    Ok(warp::reply::json(&played.view(Some(&submission.token))).into_response())
+}
+
+async fn show_match(
+    id: String,
+    query: TokenQuery,
+    matches: Matches,
+) -> Result<Response, Rejection> {
+    match matches.get(&id) {
+        Some(cell) => {
+            let shown = cell.lock().await;
+            Ok(warp::reply::json(&shown.view(query.token.as_deref())).into_response())
+        }
+        None => Ok(not_found()),
+    }
+}
+
+async fn match_notation(id: String, matches: Matches) -> Result<Response, Rejection> {
+    match matches.get(&id) {
+        Some(cell) => Ok(cell.lock().await.notation().into_response()),
+        None => Ok(not_found()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn rounds_resolve_once_both_actions_are_sealed() {
+        let rules = MatchRules {
+            starting_health: 1,
+            ..Default::default()
+        };
+        let (mut played, first) = Match::create(rules, |_| false);
+        assert_eq!(played.status(), MatchStatus::Open);
+        assert_eq!(
+            played.submit(&first.token, 1, Action::Reload),
+            Err(MatchError::NotStarted)
+        );
+        let second = played.join().unwrap();
+        assert!(played.join().is_err());
+
+        played.submit(&first.token, 1, Action::Reload).unwrap();
+        assert_eq!(
+            played.submit(&first.token, 1, Action::Shield),
+            Err(MatchError::AlreadySealed)
+        );
+        // the opponent only sees that an action was sealed
+        let view = played.view(Some(&second.token));
+        assert_eq!(view.sealed, [true, false]);
+        assert_eq!(view.your_action, None);
+        assert_eq!(played.view(Some(&first.token)).your_action, Some("reload"));
+        assert_eq!(
+            played.submit("nobody", 1, Action::Fire),
+            Err(MatchError::BadToken)
+        );
+
+        played.submit(&second.token, 1, Action::Reload).unwrap();
+        assert_eq!(played.rounds, vec![[Action::Reload; NUM_PLAYERS]]);
+        assert_eq!(
+            played.submit(&first.token, 1, Action::Fire),
+            Err(MatchError::WrongRound(2))
+        );
+        played.submit(&first.token, 2, Action::Fire).unwrap();
+        played.submit(&second.token, 2, Action::Reload).unwrap();
+        assert_eq!(played.status(), MatchStatus::Won { winner: 0 });
+        assert_eq!(
+            played.submit(&first.token, 3, Action::Fire),
+            Err(MatchError::Over)
+        );
+        assert!(played.notation().contains("1-0"));
+    }
+
+    #[test]
+    fn only_open_matches_expire() {
+        let (mut open, _) = Match::create(MatchRules::default(), |_| false);
+        let expiry = OPEN_MATCH_EXPIRY.as_secs();
+        assert!(!open.expired(open.created + expiry - 1));
+        assert!(open.expired(open.created + expiry));
+        open.join().unwrap();
+        assert!(!open.expired(open.created + expiry));
+    }
+
+    #[tokio::test]
+    async fn routes_play_a_round() {
+        let dir = std::env::temp_dir().join(format!("correspondence-{}", std::process::id()));
+        let routes = routes(Correspondence::load(&dir).unwrap());
+        let request = |method: &str, path: String, body: &str| {
+            warp::test::request()
+                .method(method)
+                .path(&path)
+                .body(body)
+                .reply(&routes)
+        };
+        let json = |response: warp::http::Response<warp::hyper::body::Bytes>| {
+            assert_eq!(response.status(), StatusCode::OK);
+            serde_json::from_slice::<serde_json::Value>(response.body()).unwrap()
+        };
+
+        let first = json(request("POST", "/matches".to_string(), "{}").await);
+        let id = first["id"].as_str().unwrap();
+        let second = json(request("POST", format!("/matches/{id}/join"), "").await);
+        assert_eq!(second["seat"], 1);
+        let submission = serde_json::json!({
+            "token": first["token"],
+            "round": 1,
+            "action": "fire",
+        });
+        let submitted = request(
+            "POST",
+            format!("/matches/{id}/actions"),
+            &submission.to_string(),
+        );
+        assert_eq!(json(submitted.await)["your_action"], "fire");
+
+        let token = first["token"].as_str().unwrap();
+        let mine = json(request("GET", format!("/matches/{id}?token={token}"), "").await);
+        assert_eq!(mine["seat"], 0);
+        assert_eq!(mine["your_action"], "fire");
+        let anyone = json(request("GET", format!("/matches/{id}"), "").await);
+        assert_eq!(anyone["sealed"], serde_json::json!([true, false]));
+        assert_eq!(anyone["seat"], serde_json::Value::Null);
+        assert_eq!(anyone["your_action"], serde_json::Value::Null);
+        assert_eq!(
+            request("GET", "/matches/nothere".to_string(), "")
+                .await
+                .status(),
+            StatusCode::NOT_FOUND
+        );
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -1,6 +1,19 @@
+mod correspondence;
+mod rooms;
+
+use warp::Filter;
+
 #[tokio::main]
 async fn main() {
-    warp::serve(warp::fs::dir("public"))
-        .run(([0, 0, 0, 0], 8000))
-        .await;
+    let rooms = rooms::Rooms::default();
+    let matches = correspondence::Correspondence::load(correspondence::MATCHES_DIR)
+        .expect("could not load the saved matches");
+    tokio::spawn(correspondence::sweep_expired(matches.clone()));
+    warp::serve(
+        rooms::routes(rooms)
+            .or(correspondence::routes(matches))
+            .or(warp::fs::dir("public")),
+    )
+    .run(([0, 0, 0, 0], 8000))
+    .await;
 }
@@ -0,0 +1,66 @@
+use std::{
+    collections::HashMap,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use serde::{Deserialize, Serialize};
+use tokio::sync::Mutex;
+use warp::{http::StatusCode, Filter, Rejection, Reply};
+
+/// Rooms not refreshed by their host for this long are dropped from the list.
+const ROOM_EXPIRY: Duration = Duration::from_secs(30);
+
+#[derive(Clone, Serialize, Deserialize)]
+pub struct RoomInfo {
+    pub code: String,
+}
+
+pub type Rooms = Arc<Mutex<HashMap<String, Instant>>>;
+
+/// `GET /rooms` lists open rooms, `POST /rooms` publishes (or refreshes) one,
+/// `DELETE /rooms/:code` removes it once the host found an opponent.
+pub fn routes(rooms: Rooms) -> impl Filter<Extract = impl Reply, Error = Rejection> + Clone {
+    let with_rooms = warp::any().map(move || rooms.clone());
+
+    let list = warp::path!("rooms")
+        .and(warp::get())
+        .and(with_rooms.clone())
+        .and_then(list_rooms);
+    let publish = warp::path!("rooms")
+        .and(warp::post())
+        .and(warp::body::content_length_limit(1024))
+        .and(warp::body::json())
+        .and(with_rooms.clone())
+        .and_then(publish_room);
+    let remove = warp::path!("rooms" / String)
+        .and(warp::delete())
+        .and(with_rooms)
+        .and_then(remove_room);
+
+    list.or(publish).or(remove)
+}
+
+async fn list_rooms(rooms: Rooms) -> Result<impl Reply, Rejection> {
+    let mut rooms = rooms.lock().await;
+    rooms.retain(|_, refreshed| refreshed.elapsed() < ROOM_EXPIRY);
+    let mut list: Vec<RoomInfo> = rooms
+        .keys()
+        .map(|code| RoomInfo { code: code.clone() })
+        .collect();
+    list.sort_by(|a, b| a.code.cmp(&b.code));
+    Ok(warp::reply::json(&list))
+}
+
+async fn publish_room(room: RoomInfo, rooms: Rooms) -> Result<impl Reply, Rejection> {
+    if room.code.is_empty() || !room.code.chars().all(|c| c.is_ascii_alphanumeric()) {
+        return Ok(StatusCode::BAD_REQUEST);
+    }
+    rooms.lock().await.insert(room.code, Instant::now());
+    Ok(StatusCode::OK)
+}
+
+async fn remove_room(code: String, rooms: Rooms) -> Result<impl Reply, Rejection> {
+    rooms.lock().await.remove(&code);
+    Ok(StatusCode::OK)
+}